@@ -0,0 +1,134 @@
+//How fast the VM runs, and what the per instruction costs it avoids are
+//worth. Run with `cargo bench`. There is no harness: every case runs a
+//few times and the best time counts, which is what is least disturbed
+//by whatever else the machine is doing.
+//
+//The cases run the same loop of ALU, load and store instructions:
+//
+//  plain           nothing but the program, the way most programs run
+//  timer armed     the timer counts down, so the devices are ticked and
+//                  interrupt requests are polled after every instruction
+//  recording       the input is recorded, so the console is told the
+//                  instruction count before every instruction
+//
+//and the decode case compares decoding an instruction to looking up the
+//decoded one in a cache, which is what fetching an instruction costs
+//with and without the VM's decode cache.
+
+use std::io;
+use std::time::{Duration, Instant};
+
+use rust_vm::{Vm, StopReason};
+use rust_vm::asm::assemble;
+use rust_vm::console::BufferConsole;
+use rust_vm::decode::{decode, Instruction};
+use rust_vm::replay::RecordingConsole;
+
+
+const RUNS: u32 = 5;
+
+//R2 x R3 iterations of the 8 instructions from L2 to BRp L2.
+const LOOP: &str = "
+        .ORIG x3000
+        LD R6, DATA
+        LDI R0, TIMER
+        STI R0, TIR
+        LD R2, OUTER
+L1      LD R3, INNER
+L2      ADD R1, R1, #1
+        AND R4, R1, #7
+        LDR R5, R6, #0
+        STR R1, R6, #1
+        NOT R5, R5
+        ADD R5, R5, R4
+        ADD R3, R3, #-1
+        BRp L2
+        ADD R2, R2, #-1
+        BRp L1
+        HALT
+DATA    .FILL x4000
+TIR     .FILL xFE0A
+OUTER   .FILL #500
+INNER   .FILL #2000
+TIMER   .FILL x5000
+        .END
+";
+
+//The word at TIMER is the timer interval the program sets, 0 leaves the
+//timer off. Without an interrupt enable or a handler the timer counts
+//down and expires, but nothing is taken.
+fn run(interval: u16, record: bool) -> (Duration, u64) {
+    let program = assemble(LOOP).expect("the benchmark assembles");
+    let console = Box::new(BufferConsole::new(b""));
+    let mut vm = if record {
+        Vm::with_console(Box::new(RecordingConsole::new(console, io::sink()).unwrap()))
+    }
+    else {
+        Vm::with_console(console)
+    };
+
+    let words = vm.memory_mut();
+    words[0x3000..0x3000 + program.words.len()].copy_from_slice(&program.words);
+    words[0x5000] = interval;
+
+    let start = Instant::now();
+    assert_eq!(vm.run().unwrap(), StopReason::Halted);
+    (start.elapsed(), vm.instruction_count())
+}
+
+fn best<F: FnMut() -> (Duration, u64)>(mut f: F) -> (Duration, u64) {
+    (0..RUNS).map(|_| f()).min().unwrap()
+}
+
+fn report(name: &str, (time, count): (Duration, u64), base: Option<Duration>) {
+    let mips = count as f64 / time.as_secs_f64() / 1e6;
+    match base {
+        Some(base) => println!(
+            "{:<14} {:>8.1} ms  {:>6.1} M instructions/s  {:+.0}%",
+            name, time.as_secs_f64() * 1e3, mips, 100.0 * (time.as_secs_f64() / base.as_secs_f64() - 1.0)
+        ),
+        None => println!("{:<14} {:>8.1} ms  {:>6.1} M instructions/s", name, time.as_secs_f64() * 1e3, mips),
+    }
+}
+
+//Decodes `words` over and over, or looks them up decoded, `rounds` times.
+fn fetch(words: &[u16], rounds: u32, cached: bool) -> (Duration, u64) {
+    let decoded: Vec<Option<Instruction>> = words.iter().map(|&word| Some(decode(word))).collect();
+    let mut seen = 0u64;
+
+    let start = Instant::now();
+    for _ in 0..rounds {
+        for (i, &word) in words.iter().enumerate() {
+            let instruction = if cached {
+                decoded[i].unwrap()
+            }
+            else {
+                decode(std::hint::black_box(word))
+            };
+            //Keeps the compiler from throwing the work away.
+            if let Instruction::Add { dr, .. } = std::hint::black_box(instruction) {
+                seen += dr as u64;
+            }
+        }
+    }
+    let time = start.elapsed();
+    std::hint::black_box(seen);
+    (time, rounds as u64 * words.len() as u64)
+}
+
+fn main() {
+    let plain = best(|| run(0, false));
+    report("plain", plain, None);
+    report("timer armed", best(|| run(50, false)), Some(plain.0));
+    report("recording", best(|| run(0, true)), Some(plain.0));
+
+    let words = assemble(LOOP).unwrap().words;
+    let decoding = best(|| fetch(&words, 1_000_000, false));
+    let cached = best(|| fetch(&words, 1_000_000, true));
+    println!();
+    println!(
+        "fetch          {:>6.2} ns decoding, {:.2} ns cached",
+        decoding.0.as_secs_f64() * 1e9 / decoding.1 as f64,
+        cached.0.as_secs_f64() * 1e9 / cached.1 as f64,
+    );
+}
@@ -0,0 +1,834 @@
+//LC3 assembler. Turns assembly source into the same big-endian .obj
+//format read_image() loads: the origin word followed by the program.
+//
+//It works in the usual two passes. The first one splits every line into
+//label, opcode and operands and works out the address of every label.
+//The second one encodes the instructions, now that every label a
+//PC-relative operand may refer to has an address.
+//
+//A mistake on one line does not stop the assembler, it notes the error
+//and carries on with the next line so that all of them get reported at
+//once. Nothing is produced unless there were none.
+
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+
+use crate::TrapCode;
+use crate::symbols::SymbolTable;
+use crate::opcodes::OpCodes;
+
+
+//An assembled program, ready to be loaded at `origin`.
+pub struct Program {
+    pub origin: u16,
+    pub words: Vec<u16>,
+    pub symbols: SymbolTable,
+    //The source line of every statement that produced words, in address
+    //order.
+    pub lines: Vec<SourceLine>,
+}
+
+//Where the words of one line of source went: `len` words from `addr`.
+//`code` is set for instructions, as opposed to .FILL, .BLKW and .STRINGZ.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SourceLine {
+    pub line: usize,
+    pub addr: u16,
+    pub len: u16,
+    pub code: bool,
+}
+
+impl Program {
+    //The program in .obj format: the origin, then every word, big-endian.
+    pub fn to_obj_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(2 * (self.words.len() + 1));
+        bytes.extend_from_slice(&self.origin.to_be_bytes());
+        for word in &self.words {
+            bytes.extend_from_slice(&word.to_be_bytes());
+        }
+        bytes
+    }
+}
+
+
+//A problem in the source. `line` and `column` are 1-based, and `width` is
+//the number of characters the problem spans, at least 1.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AsmError {
+    pub line: usize,
+    pub column: usize,
+    pub width: usize,
+    pub message: String,
+}
+
+impl AsmError {
+    //The error the way compilers print them, with the offending line and
+    //a row of carets under the culprit:
+    //
+    //  error: undefined label LOPO
+    //   --> count.asm:7:14
+    //    |
+    //  7 |         BRp LOPO
+    //    |             ^^^^
+    pub fn render(&self, file: &str, source: &str) -> String {
+        let text = source.lines().nth(self.line.wrapping_sub(1)).unwrap_or("");
+        let number = self.line.to_string();
+        let gutter = " ".repeat(number.len());
+
+        //Keep tabs in the padding so the carets line up with the text.
+        let padding: String = text.chars()
+            .take(self.column.saturating_sub(1))
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+
+        format!(
+            "error: {}\n{}--> {}:{}:{}\n{} |\n{} | {}\n{} | {}{}\n",
+            self.message,
+            gutter, file, self.line, self.column,
+            gutter,
+            number, text,
+            gutter, padding, "^".repeat(self.width.max(1)),
+        )
+    }
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.line, self.column, self.message)
+    }
+}
+
+impl Error for AsmError {}
+
+type AsmResult<T> = Result<T, AsmError>;
+
+
+#[derive(Clone, Copy)]
+struct Token<'a> {
+    //Set for "..." tokens, `text` then is what is between the quotes.
+    text: &'a str,
+    quoted: bool,
+    line: usize,
+    //1-based column of the token's first character, and its length in
+    //characters (quotes included).
+    column: usize,
+    width: usize,
+}
+
+impl<'a> Token<'a> {
+    fn error(&self, message: String) -> AsmError {
+        AsmError { line: self.line, column: self.column, width: self.width, message }
+    }
+}
+
+//One line of source, split up. Blank and comment-only lines are dropped.
+struct Statement<'a> {
+    label: Option<Token<'a>>,
+    op: Option<Token<'a>>,
+    operands: Vec<Token<'a>>,
+    //Address of the statement's first word.
+    addr: u16,
+}
+
+impl<'a> Statement<'a> {
+    fn op_name(&self) -> String {
+        self.op.map(|op| op.text.to_ascii_uppercase()).unwrap_or_default()
+    }
+
+    fn line(&self) -> usize {
+        self.label.or(self.op).map_or(0, |token| token.line)
+    }
+}
+
+//Opcodes (including the RET and trap aliases) and directives. Both are
+//case insensitive, labels are not.
+const OPCODES: [&str; 35] = [
+    "ADD", "AND", "NOT", "BR", "BRN", "BRZ", "BRP", "BRNZ", "BRNP", "BRZP", "BRNZP",
+    "JMP", "RET", "JSR", "JSRR", "LD", "LDI", "LDR", "LEA", "ST", "STI", "STR",
+    "TRAP", "RTI", "GETC", "OUT", "PUTS", "IN", "PUTSP", "HALT",
+    ".ORIG", ".FILL", ".BLKW", ".STRINGZ", ".END",
+];
+
+fn is_opcode(word: &str) -> bool {
+    OPCODES.contains(&word.to_ascii_uppercase().as_str())
+}
+
+
+pub fn assemble(source: &str) -> Result<Program, Vec<AsmError>> {
+    let mut errors = Vec::new();
+
+    //Pass one: parse every line and give every label an address.
+    let mut statements = Vec::new();
+    let mut symbols = SymbolTable::new();
+    let mut defined_at: BTreeMap<&str, usize> = BTreeMap::new();
+    let mut origin: Option<u16> = None;
+    let mut pc: u32 = 0;
+
+    for (index, text) in source.lines().enumerate() {
+        let line = index + 1;
+        let mut statement = match parse_line(text, line, &mut errors) {
+            Some(statement) => statement,
+            None => continue,
+        };
+        let op = statement.op_name();
+
+        if origin.is_none() {
+            let first = statement.label.or(statement.op).unwrap();
+            if op != ".ORIG" || statement.label.is_some() {
+                errors.push(first.error("expected .ORIG before the first instruction".to_string()));
+                //Carry on as if the program started at x3000, so that the
+                //rest of the file still gets checked.
+                origin = Some(0x3000);
+                pc = 0x3000;
+            }
+            else {
+                let addr = expect_operands(&statement, 1)
+                    .and_then(|_| number(statement.operands[0]))
+                    .and_then(|addr| fits_unsigned(addr, 16, statement.operands[0]));
+                let addr = addr.unwrap_or_else(|err| {
+                    errors.push(err);
+                    0x3000
+                });
+                origin = Some(addr);
+                pc = addr as u32;
+                continue;
+            }
+        }
+
+        //A program filling memory to the end may still be followed by .END.
+        if pc > 0xFFFF && op != ".END" {
+            let first = statement.label.or(statement.op).unwrap();
+            errors.push(first.error("program runs past the end of memory".to_string()));
+            break;
+        }
+        statement.addr = pc as u16;
+
+        if let Some(label) = statement.label {
+            match defined_at.get(label.text) {
+                Some(first) => {
+                    errors.push(label.error(format!(
+                        "label {} is defined twice, first on line {}", label.text, first
+                    )));
+                },
+                None => {
+                    defined_at.insert(label.text, line);
+                    symbols.insert(label.text, pc as u16);
+                },
+            }
+        }
+
+        match op.as_str() {
+            ".END" => break,
+            ".ORIG" => {
+                let token = statement.op.unwrap();
+                errors.push(token.error("only one .ORIG block is supported per file".to_string()));
+                continue;
+            },
+            "" => {},
+            _ => match size(&statement) {
+                Ok(words) => pc += words as u32,
+                Err(err) => {
+                    errors.push(err);
+                    continue;
+                },
+            },
+        }
+
+        //The statement itself may not fit, e.g. a .BLKW at the very end.
+        if pc > 0x10000 {
+            let first = statement.label.or(statement.op).unwrap();
+            errors.push(first.error("program runs past the end of memory".to_string()));
+            break;
+        }
+
+        statements.push(statement);
+    }
+
+    let origin = match origin {
+        Some(origin) => origin,
+        None => {
+            errors.push(AsmError { line: 1, column: 1, width: 1, message: "no .ORIG found".to_string() });
+            return Err(errors);
+        },
+    };
+
+    //Pass two: encode.
+    let mut words = Vec::new();
+    let mut lines = Vec::new();
+    for statement in &statements {
+        let start = words.len();
+        if let Err(err) = encode(statement, &symbols, &mut words, &mut errors) {
+            errors.push(err);
+        }
+        if words.len() > start {
+            lines.push(SourceLine {
+                line: statement.line(),
+                addr: statement.addr,
+                len: (words.len() - start) as u16,
+                code: !statement.op_name().starts_with('.'),
+            });
+        }
+    }
+
+    if !errors.is_empty() {
+        errors.sort_by_key(|err| (err.line, err.column));
+        return Err(errors);
+    }
+
+    Ok(Program { origin, words, symbols, lines })
+}
+
+
+//Splits a line into tokens: words, and quoted strings with their escape
+//sequences still in them. Commas separate operands like whitespace does,
+//and everything after a ';' outside of a string is a comment. A string
+//missing its closing quote is reported and runs to the end of the line.
+fn tokenize<'a>(text: &'a str, line: usize, errors: &mut Vec<AsmError>) -> Vec<Token<'a>> {
+    let mut tokens = Vec::new();
+    let bytes = text.as_bytes();
+    let mut i = 0;
+
+    let column = |offset: usize| text[..offset].chars().count() + 1;
+    let width = |start: usize, end: usize| text[start..end].chars().count();
+
+    while i < bytes.len() {
+        let c = bytes[i];
+        if c == b';' {
+            break;
+        }
+        if c.is_ascii_whitespace() || c == b',' {
+            i += 1;
+            continue;
+        }
+
+        if c == b'"' {
+            let start = i + 1;
+            let mut end = start;
+            let mut closed = false;
+            while end < bytes.len() {
+                match bytes[end] {
+                    b'\\' => end += 2,
+                    b'"' => {
+                        closed = true;
+                        break;
+                    },
+                    _ => end += 1,
+                }
+            }
+            let end = end.min(bytes.len());
+
+            let token = Token {
+                text: &text[start..end],
+                quoted: true,
+                line,
+                column: column(i),
+                width: width(i, (end + 1).min(bytes.len())),
+            };
+            if !closed {
+                errors.push(token.error("unterminated string".to_string()));
+            }
+            tokens.push(token);
+            i = end + 1;
+            continue;
+        }
+
+        let start = i;
+        while i < bytes.len() && !bytes[i].is_ascii_whitespace() && bytes[i] != b',' && bytes[i] != b';' {
+            i += 1;
+        }
+        tokens.push(Token {
+            text: &text[start..i],
+            quoted: false,
+            line,
+            column: column(start),
+            width: width(start, i),
+        });
+    }
+
+    tokens
+}
+
+fn parse_line<'a>(text: &'a str, line: usize, errors: &mut Vec<AsmError>) -> Option<Statement<'a>> {
+    let mut tokens = tokenize(text, line, errors).into_iter();
+    let first = tokens.next()?;
+
+    let (label, op) = if !first.quoted && is_opcode(first.text) {
+        (None, Some(first))
+    }
+    else {
+        let mut label = first;
+        label.text = label.text.trim_end_matches(':');
+        if first.quoted || !is_label(label.text) {
+            let message = if !first.quoted && parse_number(label.text).is_some() {
+                format!("{} is a number, it cannot be a label", first.text)
+            }
+            else {
+                format!("{} is not a valid label or opcode", first.text)
+            };
+            errors.push(first.error(message));
+            return None;
+        }
+        (Some(label), tokens.next())
+    };
+
+    let mut label = label;
+    let mut op = op;
+    if let Some(token) = op {
+        if token.quoted || !is_opcode(token.text) {
+            //In `FOO R1` it is FOO that is the misspelled opcode, not R1.
+            //Otherwise the label still gets defined, so that later
+            //references to it do not produce errors of their own.
+            let misspelled = if token.quoted || register(token.text).is_some() || parse_number(token.text).is_some() {
+                label = None;
+                first
+            }
+            else {
+                token
+            };
+            errors.push(misspelled.error(format!("unknown opcode {}", misspelled.text)));
+            op = None;
+        }
+    }
+
+    if label.is_none() && op.is_none() {
+        return None;
+    }
+    Some(Statement {
+        label,
+        op,
+        operands: if op.is_some() { tokens.collect() } else { Vec::new() },
+        addr: 0,
+    })
+}
+
+//Names that read as a register or a number (x10, b1) are not labels,
+//`BR x10` would be ambiguous otherwise.
+fn is_label(text: &str) -> bool {
+    let mut chars = text.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => {},
+        _ => return false,
+    }
+    chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+        && register(text).is_none()
+        && parse_number(text).is_none()
+}
+
+fn expect_operands(statement: &Statement, count: usize) -> AsmResult<()> {
+    let found = statement.operands.len();
+    if found == count {
+        return Ok(());
+    }
+
+    let message = format!(
+        "{} takes {} operand{}, found {}",
+        statement.op_name(), count, if count == 1 { "" } else { "s" }, found
+    );
+    //Point at the first operand too many, or at the opcode if some are
+    //missing.
+    let token = match statement.operands.get(count) {
+        Some(extra) => extra,
+        None => statement.op.as_ref().unwrap(),
+    };
+    Err(token.error(message))
+}
+
+//Number of words a statement takes up in memory.
+fn size(statement: &Statement) -> AsmResult<u16> {
+    match statement.op_name().as_str() {
+        ".BLKW" => {
+            if statement.operands.is_empty() || statement.operands.len() > 2 {
+                let token = statement.operands.get(2).or(statement.op.as_ref()).unwrap();
+                return Err(token.error(".BLKW takes a count and an optional fill value".to_string()));
+            }
+            let count = statement.operands[0];
+            fits_unsigned(number(count)?, 16, count)
+        },
+        ".STRINGZ" => {
+            expect_operands(statement, 1)?;
+            Ok(string(statement.operands[0])?.len() as u16 + 1)
+        },
+        _ => Ok(1),
+    }
+}
+
+
+//Parses #decimal, xHEX, bBINARY (and 0x/0b, or plain decimal) literals.
+pub fn parse_number(text: &str) -> Option<i32> {
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, text),
+    };
+
+    let (radix, digits) = if let Some(rest) = digits.strip_prefix('#') {
+        (10, rest)
+    }
+    else if let Some(rest) = digits.strip_prefix("0x").or_else(|| digits.strip_prefix("0X")) {
+        (16, rest)
+    }
+    else if let Some(rest) = digits.strip_prefix("0b").or_else(|| digits.strip_prefix("0B")) {
+        (2, rest)
+    }
+    else if let Some(rest) = digits.strip_prefix('x').or_else(|| digits.strip_prefix('X')) {
+        (16, rest)
+    }
+    else if let Some(rest) = digits.strip_prefix('b').or_else(|| digits.strip_prefix('B')) {
+        (2, rest)
+    }
+    else {
+        (10, digits)
+    };
+
+    //`#-5` is allowed as well as `-#5`.
+    let (negative, digits) = match digits.strip_prefix('-') {
+        Some(rest) if !negative => (true, rest),
+        _ => (negative, digits),
+    };
+
+    if digits.is_empty() || !digits.chars().all(|c| c.is_digit(radix)) {
+        return None;
+    }
+
+    //Anything this large is out of range for every field, clamping it
+    //keeps the error message about the range rather than the syntax.
+    let value = i64::from_str_radix(digits, radix).unwrap_or(i64::MAX).min(0x10_0000) as i32;
+    Some(if negative { -value } else { value })
+}
+
+fn number(token: Token) -> AsmResult<i32> {
+    if token.quoted {
+        return Err(token.error("expected a number, found a string".to_string()));
+    }
+    parse_number(token.text)
+        .ok_or_else(|| token.error(format!("{} is not a valid number", token.text)))
+}
+
+fn register(text: &str) -> Option<u16> {
+    let bytes = text.as_bytes();
+    if bytes.len() == 2 && (bytes[0] == b'R' || bytes[0] == b'r') && (b'0'..=b'7').contains(&bytes[1]) {
+        return Some((bytes[1] - b'0') as u16);
+    }
+    None
+}
+
+fn reg_operand(token: Token) -> AsmResult<u16> {
+    if token.quoted {
+        return Err(token.error("expected a register, found a string".to_string()));
+    }
+    register(token.text)
+        .ok_or_else(|| token.error(format!("{} is not a register (R0-R7)", token.text)))
+}
+
+//Decodes the escape sequences of a .STRINGZ operand.
+fn string(token: Token) -> AsmResult<Vec<u16>> {
+    if !token.quoted {
+        return Err(token.error(".STRINGZ expects a string in double quotes".to_string()));
+    }
+
+    let mut chars = Vec::new();
+    let mut iter = token.text.chars();
+    while let Some(c) = iter.next() {
+        let c = if c == '\\' {
+            match iter.next() {
+                Some('n') => '\n',
+                Some('t') => '\t',
+                Some('r') => '\r',
+                Some('0') => '\0',
+                Some('e') => '\x1b',
+                Some('\\') => '\\',
+                Some('"') => '"',
+                Some(other) => {
+                    return Err(token.error(format!("unknown escape sequence \\{}", other)));
+                },
+                //Only happens in a string that is missing its closing
+                //quote, which already is an error.
+                None => break,
+            }
+        }
+        else {
+            c
+        };
+
+        if !c.is_ascii() {
+            return Err(token.error("strings may only contain ASCII characters".to_string()));
+        }
+        chars.push(c as u16);
+    }
+
+    Ok(chars)
+}
+
+//Checks that `value` fits an unsigned field of `bits` bits.
+fn fits_unsigned(value: i32, bits: u32, token: Token) -> AsmResult<u16> {
+    if value < 0 || value >= 1 << bits {
+        return Err(token.error(format!(
+            "{} does not fit in {} unsigned bits (0 to {})", value, bits, (1 << bits) - 1
+        )));
+    }
+    Ok(value as u16)
+}
+
+//Checks that `value` fits a two's complement field of `bits` bits, the
+//ones opcode_fn.rs sign_extend()s, and returns it masked to that width.
+fn fits_signed(value: i32, bits: u32, what: &str, token: Token) -> AsmResult<u16> {
+    let min = -(1 << (bits - 1));
+    let max = (1 << (bits - 1)) - 1;
+    if value < min || value > max {
+        return Err(token.error(format!("{} {} is out of range ({} to {})", what, value, min, max)));
+    }
+    Ok(value as u16 & ((1 << bits) - 1))
+}
+
+//.FILL style values: anything from -32768 to 65535.
+fn fits_word(value: i32, token: Token) -> AsmResult<u16> {
+    if !(-0x8000..=0xFFFF).contains(&value) {
+        return Err(token.error(format!("{} does not fit in 16 bits", value)));
+    }
+    Ok(value as u16)
+}
+
+fn lookup(token: Token, symbols: &SymbolTable) -> AsmResult<u16> {
+    if token.quoted {
+        return Err(token.error("expected a label, found a string".to_string()));
+    }
+    symbols.get(token.text)
+        .ok_or_else(|| token.error(format!("undefined label {}", token.text)))
+}
+
+//A PC-relative operand: either a label, or a literal offset.
+fn pc_offset(token: Token, bits: u32, statement: &Statement, symbols: &SymbolTable) -> AsmResult<u16> {
+    let what = format!("PCoffset{}", bits);
+
+    if let Some(value) = parse_number(token.text).filter(|_| !token.quoted) {
+        return fits_signed(value, bits, &what, token);
+    }
+
+    let target = lookup(token, symbols)?;
+    //Offsets are relative to the incremented PC.
+    let offset = target as i32 - (statement.addr as i32 + 1);
+    fits_signed(offset, bits, &format!("{} to {}", what, token.text), token)
+}
+
+//Every operand gets checked even after one of them turned out wrong, so
+//that `ADD R1, R9, #40` reports both R9 and #40. Those errors go to
+//`errors`, check() stands in a 0 for the operand. Errors that leave
+//nothing to check, like a missing operand, are returned.
+fn encode(statement: &Statement, symbols: &SymbolTable, words: &mut Vec<u16>, errors: &mut Vec<AsmError>) -> AsmResult<()> {
+    let op = statement.op_name();
+    let operands = &statement.operands;
+
+    let mut check = |result: AsmResult<u16>| result.unwrap_or_else(|err| {
+        errors.push(err);
+        0
+    });
+
+    let opcode = |op: OpCodes| (op as u16) << 12;
+    let trap = |code: TrapCode| opcode(OpCodes::OP_TRAP) | code as u16;
+
+    let word = match op.as_str() {
+        "" => return Ok(()),
+        ".FILL" => {
+            expect_operands(statement, 1)?;
+            let token = operands[0];
+            if token.quoted {
+                return Err(token.error(".FILL expects a number or a label, found a string".to_string()));
+            }
+            match parse_number(token.text) {
+                Some(value) => fits_word(value, token)?,
+                None => lookup(token, symbols)?,
+            }
+        },
+        ".BLKW" => {
+            let count = size(statement)?;
+            let fill = match operands.get(1) {
+                Some(&token) => fits_word(number(token)?, token)?,
+                None => 0,
+            };
+            words.extend(std::iter::repeat(fill).take(count as usize));
+            return Ok(());
+        },
+        ".STRINGZ" => {
+            expect_operands(statement, 1)?;
+            words.extend(string(operands[0])?);
+            words.push(0);
+            return Ok(());
+        },
+
+        "ADD" | "AND" => {
+            expect_operands(statement, 3)?;
+            let base = opcode(if op == "ADD" { OpCodes::OP_ADD } else { OpCodes::OP_AND });
+            let dr = check(reg_operand(operands[0]));
+            let sr1 = check(reg_operand(operands[1]));
+            let last = operands[2];
+            match register(last.text).filter(|_| !last.quoted) {
+                Some(sr2) => base | dr << 9 | sr1 << 6 | sr2,
+                None if !last.quoted && parse_number(last.text).is_none() => {
+                    check(Err(last.error(format!("{} is neither a register (R0-R7) nor a number", last.text))))
+                },
+                None => {
+                    let imm5 = check(number(last).and_then(|value| fits_signed(value, 5, "imm5", last)));
+                    base | dr << 9 | sr1 << 6 | 1 << 5 | imm5
+                },
+            }
+        },
+        "NOT" => {
+            expect_operands(statement, 2)?;
+            let dr = check(reg_operand(operands[0]));
+            let sr = check(reg_operand(operands[1]));
+            opcode(OpCodes::OP_NOT) | dr << 9 | sr << 6 | 0x3F
+        },
+        br if br.starts_with("BR") => {
+            expect_operands(statement, 1)?;
+            let flags = &br[2..];
+            //A plain BR branches always, same as BRnzp.
+            let nzp = if flags.is_empty() {
+                0x7
+            }
+            else {
+                (flags.contains('N') as u16) << 2 | (flags.contains('Z') as u16) << 1 | flags.contains('P') as u16
+            };
+            opcode(OpCodes::OP_BR) | nzp << 9 | pc_offset(operands[0], 9, statement, symbols)?
+        },
+        "JMP" => {
+            expect_operands(statement, 1)?;
+            opcode(OpCodes::OP_JMP) | reg_operand(operands[0])? << 6
+        },
+        "RET" => {
+            expect_operands(statement, 0)?;
+            opcode(OpCodes::OP_JMP) | 7 << 6
+        },
+        "JSR" => {
+            expect_operands(statement, 1)?;
+            opcode(OpCodes::OP_JSR) | 1 << 11 | pc_offset(operands[0], 11, statement, symbols)?
+        },
+        "JSRR" => {
+            expect_operands(statement, 1)?;
+            opcode(OpCodes::OP_JSR) | reg_operand(operands[0])? << 6
+        },
+        "LD" | "LDI" | "LEA" | "ST" | "STI" => {
+            expect_operands(statement, 2)?;
+            let base = opcode(match op.as_str() {
+                "LD" => OpCodes::OP_LD,
+                "LDI" => OpCodes::OP_LDI,
+                "LEA" => OpCodes::OP_LEA,
+                "ST" => OpCodes::OP_ST,
+                _ => OpCodes::OP_STI,
+            });
+            let r = check(reg_operand(operands[0]));
+            base | r << 9 | check(pc_offset(operands[1], 9, statement, symbols))
+        },
+        "LDR" | "STR" => {
+            expect_operands(statement, 3)?;
+            let base = opcode(if op == "LDR" { OpCodes::OP_LDR } else { OpCodes::OP_STR });
+            let r = check(reg_operand(operands[0]));
+            let base_r = check(reg_operand(operands[1]));
+            let offset6 = check(number(operands[2]).and_then(|value| fits_signed(value, 6, "offset6", operands[2])));
+            base | r << 9 | base_r << 6 | offset6
+        },
+        "TRAP" => {
+            expect_operands(statement, 1)?;
+            let vector = fits_unsigned(number(operands[0])?, 8, operands[0])?;
+            opcode(OpCodes::OP_TRAP) | vector
+        },
+        "RTI" => {
+            expect_operands(statement, 0)?;
+            opcode(OpCodes::OP_RTI)
+        },
+        "GETC" | "OUT" | "PUTS" | "IN" | "PUTSP" | "HALT" => {
+            expect_operands(statement, 0)?;
+            trap(match op.as_str() {
+                "GETC" => TrapCode::GETC,
+                "OUT" => TrapCode::OUT,
+                "PUTS" => TrapCode::PUTS,
+                "IN" => TrapCode::IN,
+                "PUTSP" => TrapCode::PUTSP,
+                _ => TrapCode::HALT,
+            })
+        },
+        _ => return Err(statement.op.unwrap().error(format!("unknown opcode {}", op))),
+    };
+
+    words.push(word);
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //The messages of the errors `source` gives, with their line numbers.
+    fn errors(source: &str) -> Vec<(usize, String)> {
+        match assemble(source) {
+            Ok(_) => panic!("expected errors assembling {:?}", source),
+            Err(errors) => errors.into_iter().map(|err| (err.line, err.message)).collect(),
+        }
+    }
+
+    #[test]
+    fn assembles_a_program() {
+        let program = assemble("
+                .ORIG x3000
+        LOOP    ADD R1, R1, #-1
+                BRp LOOP
+                LEA R0, MSG
+                PUTS
+                HALT
+        MSG     .STRINGZ \"hi\"
+                .END
+        ").unwrap();
+
+        assert_eq!(program.origin, 0x3000);
+        assert_eq!(program.words, vec![0x127F, 0x03FE, 0xE002, 0xF022, 0xF025, 0x0068, 0x0069, 0x0000]);
+        assert_eq!(program.symbols.get("LOOP"), Some(0x3000));
+        assert_eq!(program.symbols.get("MSG"), Some(0x3005));
+        assert_eq!(program.to_obj_bytes()[..4], [0x30, 0x00, 0x12, 0x7F]);
+    }
+
+    #[test]
+    fn program_may_end_at_the_end_of_memory() {
+        let program = assemble(".ORIG xFFFE\n.BLKW 2\n.END").unwrap();
+        assert_eq!(program.words.len(), 2);
+    }
+
+    #[test]
+    fn last_statement_running_past_memory_is_an_error() {
+        assert_eq!(errors(".ORIG xFFFF\n.BLKW 5\n.END"), vec![(2, "program runs past the end of memory".to_string())]);
+        assert_eq!(errors(".ORIG xFFFF\nHALT\nHALT"), vec![(3, "program runs past the end of memory".to_string())]);
+    }
+
+    #[test]
+    fn numbers_are_not_labels() {
+        for label in ["x10", "b1", "X3000"] {
+            let source = format!(".ORIG x3000\n{} ADD R1, R1, #1\n.END", label);
+            assert_eq!(errors(&source), vec![(2, format!("{} is a number, it cannot be a label", label))]);
+        }
+        assert!(assemble(".ORIG x3000\nxylophone HALT\nbeef HALT\n.END").is_ok());
+    }
+
+    #[test]
+    fn quoted_operands_are_not_numbers_or_registers() {
+        assert_eq!(
+            errors(".ORIG x3000\n.FILL \"5\"\n.END"),
+            vec![(2, ".FILL expects a number or a label, found a string".to_string())]
+        );
+        assert_eq!(errors(".ORIG x3000\nBR \"5\"\n.END"), vec![(2, "expected a label, found a string".to_string())]);
+        assert_eq!(
+            errors(".ORIG x3000\nADD R1, R1, \"R2\"\n.END"),
+            vec![(2, "expected a number, found a string".to_string())]
+        );
+    }
+
+    #[test]
+    fn every_bad_operand_is_reported() {
+        let source = ".ORIG x3000\nADD R1, R9, #40\nLDR R8, R2, #99\nLD R0, NOWHERE\n.END";
+        assert_eq!(errors(source), vec![
+            (2, "R9 is not a register (R0-R7)".to_string()),
+            (2, "imm5 40 is out of range (-16 to 15)".to_string()),
+            (3, "R8 is not a register (R0-R7)".to_string()),
+            (3, "offset6 99 is out of range (-32 to 31)".to_string()),
+            (4, "undefined label NOWHERE".to_string()),
+        ]);
+    }
+}
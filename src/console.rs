@@ -0,0 +1,357 @@
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::io::{self, Write};
+use std::rc::Rc;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+
+//Everything the VM needs from a terminal. The trap routines (GETC, OUT,
+//PUTS, IN, PUTSP) and the keyboard registers only talk to the outside
+//world through this trait, so the VM can run headless or under test.
+pub trait Console {
+    //Waits until a byte of input is available and returns it. Failing
+    //with ErrorKind::Interrupted hands control back to whoever runs the
+    //VM while it waits: the read is undone and run() returns
+    //StopReason::Interrupted, to retry the read when run again.
+    fn read_byte(&mut self) -> io::Result<u8>;
+
+    //Returns a byte of input if the user has pressed a key. Must not
+    //wait for one, programs call this in their idle loops.
+    fn poll_key(&mut self) -> io::Result<Option<u8>>;
+
+    fn write_byte(&mut self, byte: u8) -> io::Result<()>;
+
+    fn flush(&mut self) -> io::Result<()>;
+
+    //Told how many instructions have run before every instruction, for
+    //consoles that need to know when input happens (see replay.rs).
+    //Only called if clocked() says so, which is checked once.
+    fn clock(&mut self, _instructions: u64) {}
+
+    fn clocked(&self) -> bool {
+        false
+    }
+}
+
+//Lets the caller keep a handle on a console after giving it to the VM,
+//e.g. to look at what a program printed into a BufferConsole.
+impl<C: Console> Console for Rc<RefCell<C>> {
+    fn read_byte(&mut self) -> io::Result<u8> {
+        self.borrow_mut().read_byte()
+    }
+
+    fn poll_key(&mut self) -> io::Result<Option<u8>> {
+        self.borrow_mut().poll_key()
+    }
+
+    fn write_byte(&mut self, byte: u8) -> io::Result<()> {
+        self.borrow_mut().write_byte(byte)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.borrow_mut().flush()
+    }
+
+    fn clock(&mut self, instructions: u64) {
+        self.borrow_mut().clock(instructions)
+    }
+
+    fn clocked(&self) -> bool {
+        self.borrow().clocked()
+    }
+}
+
+
+//The process' own stdin and stdout. This is what the binary uses, with
+//the terminal switched to non-canonical mode by main.rs.
+pub struct StdioConsole {
+    stdout: io::Stdout,
+    //A key meant for us rather than the program, and the flag to raise
+    //when it is pressed.
+    hotkey: Option<(u8, Arc<AtomicBool>)>,
+    //Raised by a signal handler that wants a waiting read_byte() back.
+    interrupt: Option<&'static AtomicBool>,
+}
+
+impl StdioConsole {
+    pub fn new() -> StdioConsole {
+        StdioConsole {
+            stdout: io::stdout(),
+            hotkey: None,
+            interrupt: None,
+        }
+    }
+
+    //Keeps `key` from the program: pressing it sets `pressed` instead,
+    //and interrupts a read_byte() that is waiting for a key.
+    pub fn with_hotkey(mut self, key: u8, pressed: Arc<AtomicBool>) -> StdioConsole {
+        self.hotkey = Some((key, pressed));
+        self
+    }
+
+    //A read_byte() waiting for a key gives up when a signal arrives that
+    //has raised `flag`, like the debugger's Ctrl-C. The handler must be
+    //installed without SA_RESTART for the read to be interrupted at all.
+    pub fn with_interrupt(mut self, flag: &'static AtomicBool) -> StdioConsole {
+        self.interrupt = Some(flag);
+        self
+    }
+
+    //Whether `byte` is the hotkey, noting that it was pressed if so.
+    fn is_hotkey(&self, byte: u8) -> bool {
+        match &self.hotkey {
+            Some((key, pressed)) if *key == byte => {
+                pressed.store(true, Ordering::SeqCst);
+                true
+            },
+            _ => false,
+        }
+    }
+}
+
+impl Default for StdioConsole {
+    fn default() -> StdioConsole {
+        StdioConsole::new()
+    }
+}
+
+impl Console for StdioConsole {
+    fn read_byte(&mut self) -> io::Result<u8> {
+        //Prompts are usually printed without a newline right before
+        //waiting for input, so make sure the user can see them.
+        self.stdout.flush()?;
+
+        let byte = read_stdin(self.interrupt)?;
+        if self.is_hotkey(byte) {
+            return Err(io::Error::new(io::ErrorKind::Interrupted, "hotkey pressed"));
+        }
+        Ok(byte)
+    }
+
+    fn poll_key(&mut self) -> io::Result<Option<u8>> {
+        //Whatever was printed so far should be on screen while the
+        //program keeps polling.
+        self.stdout.flush()?;
+
+        if stdin_ready()? {
+            let byte = read_stdin(self.interrupt)?;
+            Ok(Some(byte).filter(|&byte| !self.is_hotkey(byte)))
+        }
+        else {
+            Ok(None)
+        }
+    }
+
+    fn write_byte(&mut self, byte: u8) -> io::Result<()> {
+        self.stdout.write_all(&[byte])
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stdout.flush()
+    }
+}
+
+
+//Reads one byte from the stdin file descriptor itself. io::Stdin would
+//pull a whole burst of input (an escape sequence, a paste) into its
+//buffer on the first read, where stdin_ready() cannot see it, and the
+//rest would sit there until the next key is pressed. A signal that
+//raised `interrupt` ends the wait with an Interrupted error, any other
+//one just has the read tried again.
+fn read_stdin(interrupt: Option<&AtomicBool>) -> io::Result<u8> {
+    let mut byte = 0u8;
+    loop {
+        let ret = unsafe { libc::read(libc::STDIN_FILENO, &mut byte as *mut u8 as *mut libc::c_void, 1) };
+        match ret {
+            1 => return Ok(byte),
+            0 => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "end of input")),
+            _ => {
+                let err = io::Error::last_os_error();
+                if err.kind() != io::ErrorKind::Interrupted || interrupt.is_some_and(|flag| flag.load(Ordering::SeqCst)) {
+                    return Err(err);
+                }
+            },
+        }
+    }
+}
+
+
+//Asks the OS whether reading stdin would return right away, the same
+//check_key() the C version does with select(), but using poll() with a
+//zero timeout.
+fn stdin_ready() -> io::Result<bool> {
+    let mut fds = libc::pollfd {
+        fd: libc::STDIN_FILENO,
+        events: libc::POLLIN,
+        revents: 0,
+    };
+
+    let ret = unsafe { libc::poll(&mut fds, 1, 0) };
+    if ret < 0 {
+        let err = io::Error::last_os_error();
+        //A signal (e.g. SIGWINCH on a terminal resize) is not an error,
+        //there simply was no key this time.
+        if err.kind() == io::ErrorKind::Interrupted {
+            return Ok(false);
+        }
+        return Err(err);
+    }
+
+    Ok(ret > 0 && fds.revents & (libc::POLLIN | libc::POLLHUP) != 0)
+}
+
+
+//Input comes from a byte buffer given up front and output is collected
+//into another one. Reading past the end of the input is an
+//UnexpectedEof error.
+#[derive(Default)]
+pub struct BufferConsole {
+    input: VecDeque<u8>,
+    output: Vec<u8>,
+}
+
+impl BufferConsole {
+    pub fn new(input: &[u8]) -> BufferConsole {
+        BufferConsole {
+            input: input.iter().copied().collect(),
+            output: Vec::new(),
+        }
+    }
+
+    pub fn push_input(&mut self, input: &[u8]) {
+        self.input.extend(input);
+    }
+
+    pub fn output(&self) -> &[u8] {
+        &self.output
+    }
+
+    pub fn take_output(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.output)
+    }
+}
+
+impl Console for BufferConsole {
+    fn read_byte(&mut self) -> io::Result<u8> {
+        self.input.pop_front().ok_or_else(end_of_input)
+    }
+
+    fn poll_key(&mut self) -> io::Result<Option<u8>> {
+        Ok(self.input.pop_front())
+    }
+
+    fn write_byte(&mut self, byte: u8) -> io::Result<()> {
+        self.output.push(byte);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+
+enum ScriptStep {
+    Input(Vec<u8>),
+    Idle(u32),
+    Interrupt,
+}
+
+//Like BufferConsole, but the input is released over time: `idle(n)`
+//makes the next n keyboard polls come back empty before the input that
+//follows it shows up. Blocking reads skip over idle steps. This is handy
+//for driving programs that poll the keyboard while they do other work.
+//`interrupt()` makes the blocking read that gets to it fail with
+//ErrorKind::Interrupted, like a hotkey does, polls skip over it.
+#[derive(Default)]
+pub struct ScriptedConsole {
+    script: VecDeque<ScriptStep>,
+    pending: VecDeque<u8>,
+    output: Vec<u8>,
+}
+
+impl ScriptedConsole {
+    pub fn new() -> ScriptedConsole {
+        ScriptedConsole::default()
+    }
+
+    pub fn input(mut self, input: &[u8]) -> ScriptedConsole {
+        self.script.push_back(ScriptStep::Input(input.to_vec()));
+        self
+    }
+
+    pub fn idle(mut self, polls: u32) -> ScriptedConsole {
+        self.script.push_back(ScriptStep::Idle(polls));
+        self
+    }
+
+    pub fn interrupt(mut self) -> ScriptedConsole {
+        self.script.push_back(ScriptStep::Interrupt);
+        self
+    }
+
+    pub fn output(&self) -> &[u8] {
+        &self.output
+    }
+
+    pub fn take_output(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.output)
+    }
+
+    //True once every scripted byte has been consumed.
+    pub fn is_finished(&self) -> bool {
+        self.pending.is_empty()
+            && self.script.iter().all(|step| !matches!(step, ScriptStep::Input(_)))
+    }
+}
+
+impl Console for ScriptedConsole {
+    fn read_byte(&mut self) -> io::Result<u8> {
+        while self.pending.is_empty() {
+            match self.script.pop_front() {
+                Some(ScriptStep::Input(input)) => self.pending.extend(input),
+                Some(ScriptStep::Idle(_)) => {},
+                Some(ScriptStep::Interrupt) => {
+                    return Err(io::Error::new(io::ErrorKind::Interrupted, "scripted interrupt"));
+                },
+                None => return Err(end_of_input()),
+            }
+        }
+
+        Ok(self.pending.pop_front().unwrap())
+    }
+
+    fn poll_key(&mut self) -> io::Result<Option<u8>> {
+        while self.pending.is_empty() {
+            match self.script.front_mut() {
+                Some(ScriptStep::Idle(polls)) if *polls > 0 => {
+                    *polls -= 1;
+                    return Ok(None);
+                },
+                Some(_) => {
+                    if let Some(ScriptStep::Input(input)) = self.script.pop_front() {
+                        self.pending.extend(input);
+                    }
+                },
+                None => return Ok(None),
+            }
+        }
+
+        Ok(self.pending.pop_front())
+    }
+
+    fn write_byte(&mut self, byte: u8) -> io::Result<()> {
+        self.output.push(byte);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+
+fn end_of_input() -> io::Error {
+    io::Error::new(io::ErrorKind::UnexpectedEof, "console input exhausted")
+}
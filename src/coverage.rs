@@ -0,0 +1,270 @@
+//Code coverage: which instructions a run executed, and which ways its
+//conditional branches went. The counts are kept per address and mapped
+//back to the source with the line table the assembler makes, giving an
+//annotated listing like gcov's:
+//
+//       Count   Line  Source
+//           5:     4: LOOP    ADD R1, R1, #1
+//           5:     5:         BRp LOOP
+//                     branch taken 4, not taken 1
+//       #####:     6:         PUTS
+//           -:     8: MSG     .STRINGZ "done"
+//
+//and lcov tracefiles, for the tools that make HTML reports out of them.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+use crate::Memory;
+use crate::asm::{Program, SourceLine};
+use crate::opcodes::OpCodes;
+
+
+//How often a conditional branch went each way.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct BranchCount {
+    pub taken: u64,
+    pub not_taken: u64,
+}
+
+pub struct Coverage {
+    counts: Vec<u64>,
+    branches: BTreeMap<u16, BranchCount>,
+}
+
+//Totals for one program: instructions there are and were executed,
+//branch directions there are and were gone.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Summary {
+    pub lines: usize,
+    pub lines_hit: usize,
+    pub branches: usize,
+    pub branches_hit: usize,
+}
+
+//Whether `instr` is a BR that can go either way. BRnzp always branches
+//and BR with no condition never does, so neither has two directions.
+pub fn is_conditional_branch(instr: u16) -> bool {
+    let cond = (instr >> 9) & 0x7;
+    instr >> 12 == OpCodes::OP_BR as u16 && cond != 0 && cond != 0x7
+}
+
+impl Coverage {
+    pub fn new() -> Coverage {
+        Coverage { counts: vec![0; Memory::SIZE], branches: BTreeMap::new() }
+    }
+
+    //Notes the instruction `instr` at `pc`. `branched` says whether it
+    //branched, if it is a BR.
+    pub fn record(&mut self, pc: u16, instr: u16, branched: bool) {
+        self.counts[pc as usize] += 1;
+
+        if is_conditional_branch(instr) {
+            let count = self.branches.entry(pc).or_default();
+            if branched {
+                count.taken += 1;
+            }
+            else {
+                count.not_taken += 1;
+            }
+        }
+    }
+
+    //How often the instruction at `addr` was executed.
+    pub fn count(&self, addr: u16) -> u64 {
+        self.counts[addr as usize]
+    }
+
+    //Which ways the branch at `addr` went, if it ever ran.
+    pub fn branch(&self, addr: u16) -> Option<BranchCount> {
+        self.branches.get(&addr).copied()
+    }
+
+    pub fn summary(&self, program: &Program) -> Summary {
+        let mut summary = Summary::default();
+        for line in program.lines.iter().filter(|line| line.code) {
+            summary.lines += 1;
+            if self.count(line.addr) > 0 {
+                summary.lines_hit += 1;
+            }
+            if is_conditional_branch(word_at(program, line.addr)) {
+                let count = self.branch(line.addr).unwrap_or_default();
+                summary.branches += 2;
+                summary.branches_hit += (count.taken > 0) as usize + (count.not_taken > 0) as usize;
+            }
+        }
+        summary
+    }
+
+    //The annotated listing of `source`, which `program` was assembled
+    //from. `file` is what to call it in the heading.
+    pub fn listing(&self, file: &str, source: &str, program: &Program) -> String {
+        let by_line: BTreeMap<usize, &SourceLine> = program.lines.iter()
+            .map(|line| (line.line, line))
+            .collect();
+
+        let summary = self.summary(program);
+        let mut text = String::new();
+
+        //Writing to a String cannot fail.
+        let _ = writeln!(
+            text,
+            "Coverage of {}: {} of {} instructions ({}), {} of {} branch directions ({})",
+            file,
+            summary.lines_hit, summary.lines, percent(summary.lines_hit, summary.lines),
+            summary.branches_hit, summary.branches, percent(summary.branches_hit, summary.branches),
+        );
+        let _ = writeln!(text);
+        let _ = writeln!(text, "{:>10} {:>6}  Source", "Count", "Line");
+
+        for (index, source_line) in source.lines().enumerate() {
+            let number = index + 1;
+            let addr = by_line.get(&number).filter(|line| line.code).map(|line| line.addr);
+
+            let count = match addr {
+                Some(addr) => match self.count(addr) {
+                    0 => "#####".to_string(),
+                    count => count.to_string(),
+                },
+                _ => "-".to_string(),
+            };
+            let _ = writeln!(text, "{:>10}:{:>6}: {}", count, number, source_line);
+
+            if let Some(addr) = addr.filter(|&addr| is_conditional_branch(word_at(program, addr))) {
+                let count = self.branch(addr).unwrap_or_default();
+                let _ = writeln!(
+                    text,
+                    "{:>10} {:>6}  branch taken {}, not taken {}",
+                    "", "", count.taken, count.not_taken
+                );
+            }
+        }
+        text
+    }
+
+    //An lcov tracefile record for `file`, the source `program` was
+    //assembled from. Every instruction is a line, and every conditional
+    //branch a block with two branches, taken and not taken.
+    pub fn lcov(&self, file: &str, program: &Program) -> String {
+        let summary = self.summary(program);
+        let mut text = String::new();
+
+        let _ = writeln!(text, "TN:");
+        let _ = writeln!(text, "SF:{}", file);
+
+        for line in program.lines.iter().filter(|line| line.code) {
+            if !is_conditional_branch(word_at(program, line.addr)) {
+                continue;
+            }
+            //lcov wants "-" for branches whose block never ran.
+            let (taken, not_taken) = match self.branch(line.addr) {
+                Some(count) => (count.taken.to_string(), count.not_taken.to_string()),
+                None => ("-".to_string(), "-".to_string()),
+            };
+            let _ = writeln!(text, "BRDA:{},0,0,{}", line.line, taken);
+            let _ = writeln!(text, "BRDA:{},0,1,{}", line.line, not_taken);
+        }
+        let _ = writeln!(text, "BRF:{}", summary.branches);
+        let _ = writeln!(text, "BRH:{}", summary.branches_hit);
+
+        for line in program.lines.iter().filter(|line| line.code) {
+            let _ = writeln!(text, "DA:{},{}", line.line, self.count(line.addr));
+        }
+        let _ = writeln!(text, "LF:{}", summary.lines);
+        let _ = writeln!(text, "LH:{}", summary.lines_hit);
+        let _ = writeln!(text, "end_of_record");
+        text
+    }
+}
+
+impl Default for Coverage {
+    fn default() -> Coverage {
+        Coverage::new()
+    }
+}
+
+fn word_at(program: &Program, addr: u16) -> u16 {
+    program.words[addr.wrapping_sub(program.origin) as usize]
+}
+
+fn percent(hit: usize, total: usize) -> String {
+    if total == 0 {
+        return "-".to_string();
+    }
+    format!("{:.1}%", 100.0 * hit as f64 / total as f64)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Vm, StopReason};
+    use crate::asm::assemble;
+    use crate::console::BufferConsole;
+
+    //Counts to 3 and checks that it did. The BAD branch never runs.
+    const COUNT: &str = "        .ORIG x3000
+        AND R1, R1, #0
+        ADD R2, R1, #3
+LOOP    ADD R1, R1, #1
+        ADD R2, R2, #-1
+        BRp LOOP
+        ADD R3, R1, #-3
+        BRnp BAD
+        HALT
+BAD     ADD R3, R3, #0
+        BRn BAD
+        HALT
+        .END
+";
+
+    fn run() -> (Coverage, Program) {
+        let program = assemble(COUNT).unwrap();
+        let mut vm = Vm::with_console(Box::new(BufferConsole::new(b"")));
+        vm.memory_mut()[0x3000..0x3000 + program.words.len()].copy_from_slice(&program.words);
+        vm.start_coverage();
+        assert_eq!(vm.run().unwrap(), StopReason::Halted);
+        (vm.take_coverage().unwrap(), program)
+    }
+
+    #[test]
+    fn listing_counts_lines_and_branches() {
+        let (coverage, program) = run();
+        assert_eq!(coverage.listing("count.asm", COUNT, &program), "\
+Coverage of count.asm: 8 of 11 instructions (72.7%), 3 of 6 branch directions (50.0%)
+
+     Count   Line  Source
+         -:     1:         .ORIG x3000
+         1:     2:         AND R1, R1, #0
+         1:     3:         ADD R2, R1, #3
+         3:     4: LOOP    ADD R1, R1, #1
+         3:     5:         ADD R2, R2, #-1
+         3:     6:         BRp LOOP
+                   branch taken 2, not taken 1
+         1:     7:         ADD R3, R1, #-3
+         1:     8:         BRnp BAD
+                   branch taken 0, not taken 1
+         1:     9:         HALT
+     #####:    10: BAD     ADD R3, R3, #0
+     #####:    11:         BRn BAD
+                   branch taken 0, not taken 0
+     #####:    12:         HALT
+         -:    13:         .END
+");
+    }
+
+    #[test]
+    fn lcov_has_lines_and_branches() {
+        let (coverage, program) = run();
+        let lcov = coverage.lcov("count.asm", &program);
+        let lines: Vec<&str> = lcov.lines().collect();
+        assert_eq!(lines, [
+            "TN:", "SF:count.asm",
+            "BRDA:6,0,0,2", "BRDA:6,0,1,1", "BRDA:8,0,0,0", "BRDA:8,0,1,1", "BRDA:11,0,0,-", "BRDA:11,0,1,-",
+            "BRF:6", "BRH:3",
+            "DA:2,1", "DA:3,1", "DA:4,3", "DA:5,3", "DA:6,3", "DA:7,1", "DA:8,1", "DA:9,1",
+            "DA:10,0", "DA:11,0", "DA:12,0",
+            "LF:11", "LH:8", "end_of_record",
+        ]);
+    }
+}
@@ -0,0 +1,172 @@
+//A simple timing model. Every instruction costs a fixed number of cycles
+//for its opcode, plus the memory latency for every data access it makes
+//(one for LD, LDR, ST and STR, two for LDI and STI, which go through a
+//pointer). The VM adds this up as it runs, and Throttle can slow it down
+//to the speed of a machine with a given clock rate.
+
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::opcodes::OpCodes;
+
+
+//Opcode names in opcode order, as accepted by CycleModel::set_cost().
+pub const OPCODE_NAMES: [&str; 16] = [
+    "BR", "ADD", "LD", "ST", "JSR", "AND", "LDR", "STR", "RTI", "NOT", "LDI", "STI", "JMP", "RES", "LEA", "TRAP",
+];
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CycleModel {
+    //Cycles per opcode, not counting memory accesses.
+    base: [u64; 16],
+    memory_latency: u64,
+    //base plus the latency of the opcode's memory accesses, worked out
+    //up front since the VM looks it up for every instruction.
+    costs: [u64; 16],
+}
+
+impl CycleModel {
+    //Every instruction takes one cycle, and every memory access one more.
+    pub fn new() -> CycleModel {
+        CycleModel::with_costs([1; 16], 1)
+    }
+
+    pub fn with_costs(base: [u64; 16], memory_latency: u64) -> CycleModel {
+        let mut model = CycleModel { base, memory_latency, costs: [0; 16] };
+        model.update();
+        model
+    }
+
+    fn update(&mut self) {
+        for (op, cost) in self.costs.iter_mut().enumerate() {
+            *cost = self.base[op] + self.memory_latency * memory_accesses(op as u16);
+        }
+    }
+
+    //Sets the base cost of the opcode called `name` (BR, ADD, ... TRAP).
+    pub fn set_cost(&mut self, name: &str, cycles: u64) -> Result<(), String> {
+        let op = OPCODE_NAMES.iter().position(|op| op.eq_ignore_ascii_case(name))
+            .ok_or_else(|| format!("Unknown opcode `{}`", name))?;
+        self.base[op] = cycles;
+        self.update();
+        Ok(())
+    }
+
+    pub fn set_memory_latency(&mut self, cycles: u64) {
+        self.memory_latency = cycles;
+        self.update();
+    }
+
+    pub fn memory_latency(&self) -> u64 {
+        self.memory_latency
+    }
+
+    //The cycles `instr` takes, memory accesses included.
+    pub fn cost(&self, instr: u16) -> u64 {
+        self.costs[(instr >> 12) as usize]
+    }
+}
+
+impl Default for CycleModel {
+    fn default() -> CycleModel {
+        CycleModel::new()
+    }
+}
+
+//Data memory accesses made by `op` (the fetch is part of the base cost).
+fn memory_accesses(op: u16) -> u64 {
+    match op {
+        op if op == OpCodes::OP_LD as u16 || op == OpCodes::OP_LDR as u16 => 1,
+        op if op == OpCodes::OP_ST as u16 || op == OpCodes::OP_STR as u16 => 1,
+        op if op == OpCodes::OP_LDI as u16 || op == OpCodes::OP_STI as u16 => 2,
+        _ => 0,
+    }
+}
+
+
+//Keeps a running VM from going faster than `hz` cycles per second. Call
+//pace() every now and then with the cycle count, it sleeps for as long
+//as the VM is ahead of the clock.
+pub struct Throttle {
+    hz: u64,
+    start: Instant,
+    start_cycles: u64,
+}
+
+//Falling further behind than this (the program waited for a key, say)
+//is not made up for by running flat out afterwards.
+const MAX_LAG: Duration = Duration::from_millis(100);
+
+impl Throttle {
+    pub fn new(hz: u64, cycles: u64) -> Throttle {
+        Throttle { hz: hz.max(1), start: Instant::now(), start_cycles: cycles }
+    }
+
+    pub fn pace(&mut self, cycles: u64) {
+        let elapsed = cycles.saturating_sub(self.start_cycles);
+        let due = self.start + Duration::from_nanos((elapsed as u128 * 1_000_000_000 / self.hz as u128) as u64);
+        let now = Instant::now();
+
+        if due > now {
+            thread::sleep(due - now);
+        }
+        else if now - due > MAX_LAG {
+            self.start = now;
+            self.start_cycles = cycles;
+        }
+    }
+
+    //About how many cycles run in a hundredth of a second, a good amount
+    //to run between calls to pace().
+    pub fn chunk(&self) -> u64 {
+        (self.hz / 100).max(1)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Vm;
+    use crate::console::BufferConsole;
+
+    #[test]
+    fn costs_count_memory_accesses() {
+        let mut model = CycleModel::new();
+        assert_eq!(model.cost(0x1021), 1); //ADD R0, R0, #1
+        assert_eq!(model.cost(0x6040), 2); //LDR R0, R1, #0
+        assert_eq!(model.cost(0xB000), 3); //STI R0, #0
+        assert_eq!(model.cost(0xF025), 1); //HALT
+
+        model.set_cost("ldr", 4).unwrap();
+        model.set_memory_latency(3);
+        assert_eq!(model.cost(0x6040), 7);
+        assert_eq!(model.cost(0xB000), 7);
+        assert_eq!(model.cost(0x1021), 1);
+        assert!(model.set_cost("MUL", 2).is_err());
+    }
+
+    #[test]
+    fn runs_stop_on_the_cycle_budget() {
+        let mut vm = Vm::with_console(Box::new(BufferConsole::new(b"")));
+        //LDI R0, #0 over and over, three cycles each.
+        vm.memory_mut()[0x3000..0x3010].fill(0xA000);
+
+        vm.run_for_cycles(10).unwrap();
+        assert_eq!((vm.instruction_count(), vm.cycles()), (4, 12));
+        vm.run_for_cycles(3).unwrap();
+        assert_eq!((vm.instruction_count(), vm.cycles()), (5, 15));
+    }
+
+    #[test]
+    fn throttles_pace_to_the_clock() {
+        assert_eq!(Throttle::new(1000, 0).chunk(), 10);
+        assert_eq!(Throttle::new(50, 0).chunk(), 1);
+
+        //50 cycles at 1 kHz are due 50 ms after the start.
+        let start = Instant::now();
+        let mut throttle = Throttle::new(1000, 100);
+        throttle.pace(150);
+        assert!(start.elapsed() >= Duration::from_millis(50));
+    }
+}
@@ -0,0 +1,500 @@
+//Interactive debugger, started with --debug. It reads commands from a
+//prompt and runs the program in between. The terminal is only switched
+//to raw mode while the program runs, so the prompt gets normal line
+//editing and the program still gets its keys one at a time.
+
+use std::io::{self, BufRead, Write};
+
+use rust_vm::{Vm, VmError, StopReason, TrapMode, PSR_USER, PSR_PRIORITY, PSR_PRIORITY_SHIFT};
+use rust_vm::asm::parse_number;
+use rust_vm::disasm;
+use rust_vm::opcodes::OpCodes;
+use rust_vm::register::Reg;
+use rust_vm::watch::{Watchpoint, WatchHit};
+
+use crate::terminal::{RawMode, CatchInterrupt};
+
+
+const HELP: &str = "\
+Commands:
+  s, step [N]            execute N instructions (default 1)
+  n, next [N]            like step, but run called subroutines and traps to completion
+  finish                 run until the current subroutine returns
+  c, continue            run until a breakpoint, HALT or Ctrl-C
+  rs, reverse-step [N]   undo the last N instructions (default 1)
+  rc, reverse-continue   run backwards to a breakpoint or watchpoint
+  history [N]            show how many instructions can be undone, or keep
+                         the last N from now on (0 turns the history off)
+  b, break <loc>         set a breakpoint
+  d, delete <loc>        remove a breakpoint
+  info break             list breakpoints
+  watch <loc>[..<loc>]   stop when the program writes to these addresses
+  rwatch <loc>[..<loc>]  stop when the program reads them
+  awatch <loc>[..<loc>]  stop when the program reads or writes them
+  info watch             list watchpoints
+  unwatch <N>            remove watchpoint N
+  r, regs                show registers and the N/Z/P flags
+  set <reg> <value>      change a register (R0-R7, PC, PSR)
+  set <loc> <value>      change a word of memory
+  x <loc> [N]            examine N words of memory (default 8)
+  l, list [loc] [N]      disassemble N instructions (default: around PC)
+  h, help                show this text
+  q, quit                leave the debugger
+
+Locations are addresses (x3000, #12288, 0x3000) or labels from a .sym
+file, optionally with an offset (LOOP+2). An empty line repeats the last
+command. Changing memory with `set` forgets the history; devices are not
+rewound, input the program read stays read.";
+
+//How many instructions `list` shows around the PC.
+const LIST_BEFORE: u16 = 4;
+const LIST_LENGTH: u32 = 10;
+
+//Why execute() handed control back to the prompt.
+enum Stop {
+    //The command ran to completion.
+    Done,
+    Breakpoint,
+    Halted,
+    Interrupted,
+    Watchpoint(Vec<WatchHit>),
+    Error(VmError),
+    //Running backwards undid everything in the history.
+    HistoryStart,
+}
+
+pub struct Debugger<'a> {
+    vm: &'a mut Vm,
+    last_command: String,
+}
+
+impl<'a> Debugger<'a> {
+    pub fn new(vm: &'a mut Vm) -> Debugger<'a> {
+        Debugger { vm, last_command: String::new() }
+    }
+
+    //The prompt loop. Returns when the user quits or stdin is closed.
+    pub fn run(&mut self) -> io::Result<()> {
+        println!("rust_vm debugger, type `help` for commands.");
+        self.show_location();
+
+        let stdin = io::stdin();
+        let mut line = String::new();
+        loop {
+            print!("(lc3) ");
+            io::stdout().flush()?;
+
+            line.clear();
+            if stdin.lock().read_line(&mut line)? == 0 {
+                println!();
+                return Ok(());
+            }
+
+            let mut command = line.trim().to_string();
+            if command.is_empty() {
+                command = self.last_command.clone();
+            }
+            else {
+                self.last_command = command.clone();
+            }
+            if command.is_empty() {
+                continue;
+            }
+
+            let args: Vec<&str> = command.split_whitespace().collect();
+            if matches!(args[0], "q" | "quit") {
+                return Ok(());
+            }
+            if let Err(message) = self.command(&args) {
+                println!("{}", message);
+            }
+        }
+    }
+
+    fn command(&mut self, args: &[&str]) -> Result<(), String> {
+        match args {
+            ["h"] | ["help"] => println!("{}", HELP),
+
+            ["s"] | ["step"] => self.step(1),
+            ["s", n] | ["step", n] => self.step(count(n)?),
+            ["n"] | ["next"] => self.next(1),
+            ["n", n] | ["next", n] => self.next(count(n)?),
+            ["finish"] => self.finish(),
+            ["c"] | ["continue"] => {
+                let stop = self.execute(|_, _| false);
+                self.report(stop);
+            },
+
+            ["rs"] | ["reverse-step"] => self.reverse_step(1),
+            ["rs", n] | ["reverse-step", n] => self.reverse_step(count(n)?),
+            ["rc"] | ["reverse-continue"] => {
+                let stop = match self.vm.run_back() {
+                    StopReason::Breakpoint => Stop::Breakpoint,
+                    StopReason::Watchpoint => Stop::Watchpoint(self.vm.take_watch_hits()),
+                    _ => Stop::HistoryStart,
+                };
+                self.report(stop);
+            },
+            ["history"] => println!(
+                "{} of the last {} instructions can be undone.", self.vm.history_len(), self.vm.history_limit()
+            ),
+            ["history", n] => match parse_number(n) {
+                Some(limit) if limit >= 0 => self.vm.set_history_limit(limit as usize),
+                _ => return Err(format!("`{}` is not a count", n)),
+            },
+
+            ["b", loc] | ["break", loc] => {
+                let addr = self.location(loc)?;
+                if self.vm.add_breakpoint(addr) {
+                    println!("Breakpoint at {}", self.vm.describe_addr(addr));
+                }
+                else {
+                    println!("There already is a breakpoint at {}", self.vm.describe_addr(addr));
+                }
+            },
+            ["d", loc] | ["delete", loc] => {
+                let addr = self.location(loc)?;
+                if !self.vm.remove_breakpoint(addr) {
+                    return Err(format!("No breakpoint at {}", self.vm.describe_addr(addr)));
+                }
+            },
+            ["info", "break"] | ["info", "breakpoints"] => {
+                let mut breakpoints: Vec<u16> = self.vm.breakpoints().collect();
+                breakpoints.sort_unstable();
+                if breakpoints.is_empty() {
+                    println!("No breakpoints.");
+                }
+                for addr in breakpoints {
+                    println!("  {}", self.vm.describe_addr(addr));
+                }
+            },
+
+            ["watch", range] => self.watch("w", range)?,
+            ["rwatch", range] => self.watch("r", range)?,
+            ["awatch", range] => self.watch("a", range)?,
+            ["info", "watch"] | ["info", "watchpoints"] => {
+                if self.vm.watchpoints().is_empty() {
+                    println!("No watchpoints.");
+                }
+                for (i, watchpoint) in self.vm.watchpoints().iter().enumerate() {
+                    println!("  {}: {}", i, watchpoint);
+                }
+            },
+            ["unwatch", n] => {
+                let index = parse_number(n).unwrap_or(-1);
+                if index < 0 || self.vm.remove_watchpoint(index as usize).is_none() {
+                    return Err(format!("No watchpoint {}, see `info watch`", n));
+                }
+            },
+
+            ["r"] | ["regs"] | ["info", "registers"] => self.show_registers(),
+            ["set", target, value] => {
+                let value = self.location(value)?;
+                match register(target) {
+                    Some(reg) => self.vm.set_reg(reg, value),
+                    None => {
+                        let addr = self.location(target)?;
+                        self.vm.memory_mut()[addr as usize] = value;
+                    },
+                }
+            },
+            ["x", loc] => self.examine(self.location(loc)?, 8),
+            ["x", loc, n] => self.examine(self.location(loc)?, count(n)?),
+
+            ["l"] | ["list"] => {
+                let start = self.vm.pc().saturating_sub(LIST_BEFORE);
+                self.print_listing(start, LIST_LENGTH);
+            },
+            ["l", loc] | ["list", loc] => self.print_listing(self.location(loc)?, LIST_LENGTH),
+            ["l", loc, n] | ["list", loc, n] => self.print_listing(self.location(loc)?, count(n)?),
+
+            _ => return Err(format!("Unknown command `{}`, try `help`.", args.join(" "))),
+        }
+        Ok(())
+    }
+
+    //Runs the program until `done` says so (it is called after every
+    //instruction with the VM and the instruction that just ran), a
+    //breakpoint is reached, the program halts or fails, or Ctrl-C is
+    //pressed.
+    fn execute<F>(&mut self, mut done: F) -> Stop
+    where F: FnMut(&Vm, u16) -> bool
+    {
+        if !self.vm.is_running() {
+            return Stop::Halted;
+        }
+
+        //Whatever the program does with the terminal is between it and
+        //the user. Both go back to normal for the prompt.
+        let _raw = RawMode::enable().ok();
+        let sigint = CatchInterrupt::new();
+
+        loop {
+            let instr = self.vm.memory()[self.vm.pc() as usize];
+            match self.vm.step() {
+                //Ctrl-C while the program waited for a key, which it
+                //will wait for again.
+                Err(VmError::Io(err)) if err.kind() == io::ErrorKind::Interrupted => {
+                    sigint.interrupted();
+                    return Stop::Interrupted;
+                },
+                Err(err) => return Stop::Error(err),
+                Ok(()) => {},
+            }
+
+            if !self.vm.is_running() {
+                return Stop::Halted;
+            }
+            let hits = self.vm.take_watch_hits();
+            if !hits.is_empty() {
+                return Stop::Watchpoint(hits);
+            }
+            if done(self.vm, instr) {
+                return Stop::Done;
+            }
+            if self.vm.has_breakpoint(self.vm.pc()) {
+                return Stop::Breakpoint;
+            }
+            if sigint.interrupted() {
+                return Stop::Interrupted;
+            }
+        }
+    }
+
+    fn report(&mut self, stop: Stop) {
+        match stop {
+            Stop::Done => {},
+            Stop::Breakpoint => println!("Breakpoint reached."),
+            Stop::Interrupted => println!("Interrupted."),
+            Stop::Watchpoint(hits) => {
+                for hit in hits {
+                    println!("Watchpoint: {}", hit.describe(self.vm.symbols()));
+                }
+            },
+            Stop::Halted => {
+                println!("The program has halted.");
+                return;
+            },
+            Stop::Error(err) => println!("{}.", err.describe(self.vm.symbols())),
+            Stop::HistoryStart => println!("Reached the start of the history."),
+        }
+        self.show_location();
+    }
+
+    fn step(&mut self, count: u32) {
+        let mut left = count;
+        let stop = self.execute(|_, _| {
+            left -= 1;
+            left == 0
+        });
+        self.report(stop);
+    }
+
+    fn reverse_step(&mut self, count: u32) {
+        let mut stop = Stop::Done;
+        for _ in 0..count {
+            if !self.vm.step_back() {
+                stop = Stop::HistoryStart;
+                break;
+            }
+            let hits = self.vm.take_watch_hits();
+            if !hits.is_empty() {
+                stop = Stop::Watchpoint(hits);
+                break;
+            }
+        }
+        self.report(stop);
+    }
+
+    //Steps over subroutine calls, and over traps that go through the trap
+    //vector table. Native traps take a single step anyway.
+    fn next(&mut self, count: u32) {
+        let trap_mode = self.vm.trap_mode();
+        let mut left = count;
+        let mut depth = 0;
+        let stop = self.execute(|vm, instr| {
+            depth += call_depth(vm, instr, trap_mode);
+            if depth > 0 {
+                return false;
+            }
+            depth = 0;
+            left -= 1;
+            left == 0
+        });
+        self.report(stop);
+    }
+
+    fn finish(&mut self) {
+        let trap_mode = self.vm.trap_mode();
+        let mut depth = 0;
+        let stop = self.execute(|vm, instr| {
+            depth += call_depth(vm, instr, trap_mode);
+            depth < 0
+        });
+        self.report(stop);
+    }
+
+    fn show_location(&self) {
+        let pc = self.vm.pc();
+        self.print_listing(pc, 1);
+    }
+
+    fn show_registers(&self) {
+        let vm = &*self.vm;
+        let regs = [Reg::R0, Reg::R1, Reg::R2, Reg::R3, Reg::R4, Reg::R5, Reg::R6, Reg::R7];
+        for row in regs.chunks(4) {
+            let line: Vec<String> = row.iter()
+                .map(|&reg| format!("{:?} x{:04X}", reg, vm.reg(reg)))
+                .collect();
+            println!("{}", line.join("  "));
+        }
+
+        let psr = vm.psr();
+        let cond = vm.cond();
+        let flag = |bit: u16, name: char| if cond & bit != 0 { name } else { '-' };
+        println!(
+            "PC {}  PSR x{:04X}  CC {}{}{}  {} mode  PL{}",
+            vm.describe_addr(vm.pc()),
+            psr,
+            flag(0x4, 'N'), flag(0x2, 'Z'), flag(0x1, 'P'),
+            if psr & PSR_USER != 0 { "user" } else { "supervisor" },
+            (psr & PSR_PRIORITY) >> PSR_PRIORITY_SHIFT,
+        );
+    }
+
+    fn examine(&self, start: u16, count: u32) {
+        let memory = self.vm.memory();
+        let end = (start as u32 + count).min(0x10000);
+        let addrs: Vec<u32> = (start as u32..end).collect();
+
+        for row in addrs.chunks(8) {
+            let words: Vec<String> = row.iter().map(|&addr| format!("{:04X}", memory[addr as usize])).collect();
+            println!("x{:04X}:  {}", row[0], words.join(" "));
+        }
+    }
+
+    fn print_listing(&self, start: u16, count: u32) {
+        let end = (start as usize + count as usize).min(0x10000);
+        let words = &self.vm.memory()[start as usize..end];
+        let pc = self.vm.pc();
+
+        for line in disasm::listing(start, words, self.vm.symbols()) {
+            if let Some(label) = &line.label {
+                println!("   {}:", label);
+            }
+            let marker = if line.addr == pc {
+                "=>"
+            }
+            else if self.vm.has_breakpoint(line.addr) {
+                " *"
+            }
+            else {
+                "  "
+            };
+            println!("{} {}", marker, line);
+        }
+    }
+
+    fn watch(&mut self, kind: &str, range: &str) -> Result<(), String> {
+        let watchpoint = Watchpoint::parse(&format!("{}:{}", kind, range), self.vm.symbols())?;
+        println!("Watchpoint {}: {}", self.vm.watchpoints().len(), watchpoint);
+        self.vm.add_watchpoint(watchpoint);
+        Ok(())
+    }
+
+    fn location(&self, text: &str) -> Result<u16, String> {
+        self.vm.symbols().resolve(text)
+    }
+}
+
+
+fn count(text: &str) -> Result<u32, String> {
+    match parse_number(text) {
+        Some(n) if n > 0 => Ok(n as u32),
+        _ => Err(format!("`{}` is not a positive count", text)),
+    }
+}
+
+fn register(name: &str) -> Option<Reg> {
+    let reg = match name.to_ascii_uppercase().as_str() {
+        "R0" => Reg::R0,
+        "R1" => Reg::R1,
+        "R2" => Reg::R2,
+        "R3" => Reg::R3,
+        "R4" => Reg::R4,
+        "R5" => Reg::R5,
+        "R6" => Reg::R6,
+        "R7" => Reg::R7,
+        "PC" => Reg::PC,
+        "PSR" => Reg::PSR,
+        _ => return None,
+    };
+    Some(reg)
+}
+
+//How the step that executed `instr` changed the subroutine nesting: JSR,
+//JSRR and (when traps run guest code) TRAP go one level deeper, RET and
+//RTI come back out of one. So does going into an interrupt or exception
+//handler after the instruction, the handler's RTI returns from it.
+fn call_depth(vm: &Vm, instr: u16, trap_mode: TrapMode) -> i32 {
+    let op = instr >> 12;
+    let call = op == OpCodes::OP_JSR as u16
+        || (op == OpCodes::OP_TRAP as u16 && trap_mode == TrapMode::Vectored);
+    let ret = (op == OpCodes::OP_JMP as u16 && (instr >> 6) & 0x7 == 7)
+        || op == OpCodes::OP_RTI as u16;
+
+    call as i32 + vm.serviced() as i32 - ret as i32
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_vm::asm::assemble;
+    use rust_vm::console::BufferConsole;
+
+    //Calls SUB, which waits for two timer interrupts.
+    const INTERRUPTED_CALL: &str = "
+            .ORIG x3000
+            LD R6, STACK
+            LEA R0, TIMERH
+            STI R0, TVEC
+            LD R0, IEBIT
+            STI R0, TSR
+            AND R0, R0, #0
+            ADD R0, R0, #5
+            STI R0, TIR
+            AND R4, R4, #0
+            JSR SUB
+    BACK    HALT
+    SUB     ADD R1, R4, #-2
+            BRn SUB
+            RET
+    TIMERH  ADD R4, R4, #1
+            LDI R3, TSR
+            RTI
+    TVEC    .FILL x0181
+    TSR     .FILL xFE08
+    TIR     .FILL xFE0A
+    IEBIT   .FILL x4000
+    STACK   .FILL x3000
+            .END
+    ";
+
+    #[test]
+    fn finish_steps_over_interrupts() {
+        let program = assemble(INTERRUPTED_CALL).unwrap();
+        let mut vm = Vm::with_console(Box::new(BufferConsole::new(b"")));
+        vm.memory_mut()[0x3000..0x3000 + program.words.len()].copy_from_slice(&program.words);
+
+        let sub = program.symbols.get("SUB").unwrap();
+        while vm.pc() != sub {
+            vm.step().unwrap();
+        }
+
+        let mut debugger = Debugger::new(&mut vm);
+        debugger.command(&["finish"]).unwrap();
+        assert_eq!(vm.pc(), program.symbols.get("BACK").unwrap());
+        assert!(vm.reg(Reg::R4) >= 2);
+    }
+}
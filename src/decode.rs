@@ -0,0 +1,85 @@
+//Instructions taken apart once. decode() pulls out the registers and
+//sign-extends the offsets and immediates, so that executing an
+//instruction is a match on its variant and nothing more. The VM keeps
+//what decode() returns for every address it fetched code from and only
+//decodes a word again after it was written to.
+
+use crate::sign_extend;
+use crate::opcodes::OpCodes;
+
+
+//The second operand of ADD and AND.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Operand {
+    Reg(u8),
+    Imm(u16),
+}
+
+//Registers are kept as u8 to keep the cache of decoded instructions
+//small. Offsets are sign-extended already, ready for wrapping_add().
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Instruction {
+    Br { cond: u16, offset: u16 },
+    Add { dr: u8, sr1: u8, src2: Operand },
+    Ld { dr: u8, offset: u16 },
+    St { sr: u8, offset: u16 },
+    Jsr { offset: u16 },
+    Jsrr { base: u8 },
+    And { dr: u8, sr1: u8, src2: Operand },
+    Ldr { dr: u8, base: u8, offset: u16 },
+    Str { sr: u8, base: u8, offset: u16 },
+    Rti,
+    Not { dr: u8, sr: u8 },
+    Ldi { dr: u8, offset: u16 },
+    Sti { sr: u8, offset: u16 },
+    //RET is JMP R7.
+    Jmp { base: u8 },
+    Reserved,
+    Lea { dr: u8, offset: u16 },
+    Trap { vector: u8 },
+}
+
+//Decodes `instr` the way the LC3 executes it: bits the ISA leaves
+//unused are ignored, not checked.
+pub fn decode(instr: u16) -> Instruction {
+    let op = instr >> 12;
+    let r0 = ((instr >> 9) & 0x7) as u8;
+    let r1 = ((instr >> 6) & 0x7) as u8;
+    let offset6 = sign_extend(instr & 0x3F, 6);
+    let offset9 = sign_extend(instr & 0x1FF, 9);
+
+    //imm5 if bit 5 is set, SR2 otherwise.
+    let src2 = if (instr >> 5) & 0x1 == 1 {
+        Operand::Imm(sign_extend(instr & 0x1F, 5))
+    }
+    else {
+        Operand::Reg((instr & 0x7) as u8)
+    };
+
+    match op {
+        op if op == OpCodes::OP_BR as u16 => Instruction::Br { cond: (instr >> 9) & 0x7, offset: offset9 },
+        op if op == OpCodes::OP_ADD as u16 => Instruction::Add { dr: r0, sr1: r1, src2 },
+        op if op == OpCodes::OP_LD as u16 => Instruction::Ld { dr: r0, offset: offset9 },
+        op if op == OpCodes::OP_ST as u16 => Instruction::St { sr: r0, offset: offset9 },
+        op if op == OpCodes::OP_JSR as u16 => {
+            if (instr >> 11) & 0x1 == 1 {
+                Instruction::Jsr { offset: sign_extend(instr & 0x7FF, 11) }
+            }
+            else {
+                Instruction::Jsrr { base: r1 }
+            }
+        },
+        op if op == OpCodes::OP_AND as u16 => Instruction::And { dr: r0, sr1: r1, src2 },
+        op if op == OpCodes::OP_LDR as u16 => Instruction::Ldr { dr: r0, base: r1, offset: offset6 },
+        op if op == OpCodes::OP_STR as u16 => Instruction::Str { sr: r0, base: r1, offset: offset6 },
+        op if op == OpCodes::OP_RTI as u16 => Instruction::Rti,
+        op if op == OpCodes::OP_NOT as u16 => Instruction::Not { dr: r0, sr: r1 },
+        op if op == OpCodes::OP_LDI as u16 => Instruction::Ldi { dr: r0, offset: offset9 },
+        op if op == OpCodes::OP_STI as u16 => Instruction::Sti { sr: r0, offset: offset9 },
+        op if op == OpCodes::OP_JMP as u16 => Instruction::Jmp { base: r1 },
+        op if op == OpCodes::OP_LEA as u16 => Instruction::Lea { dr: r0, offset: offset9 },
+        op if op == OpCodes::OP_TRAP as u16 => Instruction::Trap { vector: (instr & 0xFF) as u8 },
+        //The opcode is 4 bits, OP_RES is all that is left.
+        _ => Instruction::Reserved,
+    }
+}
@@ -0,0 +1,575 @@
+use std::io;
+use std::ops::RangeInclusive;
+
+use crate::{MemMapReg, VmError};
+use crate::console::Console;
+use crate::interrupt::{Interrupt, KEYBOARD_VECTOR, KEYBOARD_PRIORITY, TIMER_VECTOR, TIMER_PRIORITY};
+
+
+//The last 512 words of memory are the I/O page. Devices live there and
+//everything below it is plain RAM.
+pub const IO_PAGE_START: u16 = 0xFE00;
+
+//Bit 15 of a status register (KBSR, DSR, TSR) means the device is ready.
+pub const STATUS_READY: u16 = 1 << 15;
+
+//Bit 14 of a status register lets the device raise interrupts.
+pub const STATUS_INTERRUPT_ENABLE: u16 = 1 << 14;
+
+//Bit 15 of MCR enables the clock. The machine stops once it is cleared.
+pub const MCR_CLOCK_ENABLE: u16 = 1 << 15;
+
+
+//A memory mapped device. It claims a range of addresses in the I/O page
+//and gets called whenever an instruction reads or writes one of them,
+//instead of that access going to memory.
+pub trait Device {
+    //The addresses this device answers to. Must lie in the I/O page.
+    fn range(&self) -> RangeInclusive<u16>;
+
+    fn read(&mut self, addr: u16, console: &mut dyn Console) -> io::Result<u16>;
+
+    fn write(&mut self, addr: u16, val: u16, console: &mut dyn Console) -> io::Result<()>;
+
+    //Called after every instruction, for devices that need to notice
+    //things (time passing, keys arriving) without being read.
+    fn tick(&mut self, _console: &mut dyn Console) -> io::Result<()> {
+        Ok(())
+    }
+
+    //The interrupt this device wants serviced right now, if any.
+    fn interrupt(&self) -> Option<Interrupt> {
+        None
+    }
+
+    //Whether the device needs tick() calls or may want an interrupt at
+    //the moment. The bus skips both for devices that say no. Devices that
+    //do not keep track are always asked.
+    fn armed(&self) -> bool {
+        true
+    }
+
+    //The device's registers and whatever else it keeps track of, for
+    //snapshots. Devices without state keep the default.
+    fn save_state(&self) -> Vec<u16> {
+        Vec::new()
+    }
+
+    //Takes back what save_state() returned.
+    fn load_state(&mut self, _state: &[u16]) {}
+}
+
+
+//Routes accesses to the I/O page to whichever device claimed the address.
+//Addresses nobody claimed behave like normal memory. The Machine Control
+//Register is part of the machine itself rather than a device, so the bus
+//serves it directly and no device can claim its address.
+pub struct DeviceBus {
+    devices: Vec<Box<dyn Device>>,
+    //For every address of the I/O page, the index of the device owning it.
+    owners: Vec<Option<usize>>,
+    mcr: u16,
+    //Whether any device is armed, worked out again after every access
+    //since that is what arms and disarms them.
+    armed: bool,
+}
+
+impl DeviceBus {
+    pub fn new() -> DeviceBus {
+        DeviceBus {
+            devices: Vec::new(),
+            owners: vec![None; 0x10000 - IO_PAGE_START as usize],
+            mcr: MCR_CLOCK_ENABLE,
+            armed: false,
+        }
+    }
+
+    //Whether tick() and interrupt_requests() can do anything. Checked
+    //after every instruction, so that running with the devices idle
+    //costs next to nothing.
+    pub fn armed(&self) -> bool {
+        self.armed
+    }
+
+    fn update_armed(&mut self) {
+        self.armed = self.devices.iter().any(|device| device.armed());
+    }
+
+    pub fn clock_enabled(&self) -> bool {
+        self.mcr & MCR_CLOCK_ENABLE != 0
+    }
+
+    pub fn set_clock_enabled(&mut self, enabled: bool) {
+        if enabled {
+            self.mcr |= MCR_CLOCK_ENABLE;
+        }
+        else {
+            self.mcr &= !MCR_CLOCK_ENABLE;
+        }
+    }
+
+    pub fn mcr(&self) -> u16 {
+        self.mcr
+    }
+
+    pub fn set_mcr(&mut self, mcr: u16) {
+        self.mcr = mcr;
+    }
+
+    //The state of every device, in the order they were attached.
+    pub fn save_state(&self) -> Vec<Vec<u16>> {
+        self.devices.iter().map(|device| device.save_state()).collect()
+    }
+
+    //Fails when `states` is not from the same devices.
+    pub fn load_state(&mut self, states: &[Vec<u16>]) -> Result<(), VmError> {
+        if states.len() != self.devices.len() {
+            return Err(VmError::BadSnapshot("the snapshot was taken with other devices attached"));
+        }
+        for (device, state) in self.devices.iter_mut().zip(states) {
+            device.load_state(state);
+        }
+        self.update_armed();
+        Ok(())
+    }
+
+    pub fn attach(&mut self, device: Box<dyn Device>) -> Result<(), VmError> {
+        let range = device.range();
+
+        for addr in range.clone() {
+            if addr < IO_PAGE_START {
+                return Err(VmError::NotIoAddress { addr });
+            }
+            if addr == MemMapReg::MR_MCR as u16
+                || self.owners[(addr - IO_PAGE_START) as usize].is_some() {
+                return Err(VmError::AddressInUse { addr });
+            }
+        }
+
+        let index = self.devices.len();
+        for addr in range {
+            self.owners[(addr - IO_PAGE_START) as usize] = Some(index);
+        }
+        self.devices.push(device);
+        self.update_armed();
+
+        Ok(())
+    }
+
+    fn owner(&self, addr: u16) -> Option<usize> {
+        if addr < IO_PAGE_START {
+            return None;
+        }
+        self.owners[(addr - IO_PAGE_START) as usize]
+    }
+
+    //None if no device claimed `addr`.
+    pub fn read(&mut self, addr: u16, console: &mut dyn Console) -> Option<io::Result<u16>> {
+        if addr == MemMapReg::MR_MCR as u16 {
+            return Some(Ok(self.mcr));
+        }
+
+        let index = self.owner(addr)?;
+        let result = self.devices[index].read(addr, console);
+        self.update_armed();
+        Some(result)
+    }
+
+    //None if no device claimed `addr`.
+    pub fn write(&mut self, addr: u16, val: u16, console: &mut dyn Console) -> Option<io::Result<()>> {
+        if addr == MemMapReg::MR_MCR as u16 {
+            self.mcr = val;
+            return Some(Ok(()));
+        }
+
+        let index = self.owner(addr)?;
+        let result = self.devices[index].write(addr, val, console);
+        self.update_armed();
+        Some(result)
+    }
+
+    pub fn tick(&mut self, console: &mut dyn Console) -> io::Result<()> {
+        for device in self.devices.iter_mut().filter(|device| device.armed()) {
+            device.tick(console)?;
+        }
+        self.update_armed();
+        Ok(())
+    }
+
+    pub fn interrupt_requests(&self) -> impl Iterator<Item = Interrupt> + '_ {
+        self.devices.iter().filter(|device| device.armed()).filter_map(|device| device.interrupt())
+    }
+}
+
+
+impl Default for DeviceBus {
+    fn default() -> DeviceBus {
+        DeviceBus::new()
+    }
+}
+
+
+//KBSR and KBDR. Bit 15 of KBSR stays set until the program reads the key
+//out of KBDR, so we only go looking for a new key when there is none
+//waiting. Polling never blocks, a program checking KBSR in a loop keeps
+//running while no key is pressed.
+//With bit 14 of KBSR set the keyboard also checks for keys on its own
+//and interrupts the program when one arrives. Asking the console after
+//every instruction would be far too slow, so it only does so every
+//KEYBOARD_POLL_INTERVAL instructions.
+#[derive(Default)]
+pub struct Keyboard {
+    status: u16,
+    data: u16,
+    ticks: u32,
+}
+
+const KEYBOARD_POLL_INTERVAL: u32 = 1024;
+
+impl Keyboard {
+    pub fn new() -> Keyboard {
+        Keyboard::default()
+    }
+
+    fn check_key(&mut self, console: &mut dyn Console) -> io::Result<()> {
+        if self.status & STATUS_READY == 0 {
+            if let Some(key) = console.poll_key()? {
+                self.status |= STATUS_READY;
+                self.data = key as u16;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Device for Keyboard {
+    fn range(&self) -> RangeInclusive<u16> {
+        MemMapReg::MR_KBSR as u16..=MemMapReg::MR_KBDR as u16 + 1
+    }
+
+    fn read(&mut self, addr: u16, console: &mut dyn Console) -> io::Result<u16> {
+        if addr == MemMapReg::MR_KBSR as u16 {
+            self.check_key(console)?;
+            Ok(self.status)
+        }
+        else if addr == MemMapReg::MR_KBDR as u16 {
+            //Reading the key consumes it.
+            self.status &= !STATUS_READY;
+            Ok(self.data)
+        }
+        else {
+            Ok(0)
+        }
+    }
+
+    fn write(&mut self, addr: u16, val: u16, _console: &mut dyn Console) -> io::Result<()> {
+        //Only the non-ready bits of KBSR are writable.
+        if addr == MemMapReg::MR_KBSR as u16 {
+            self.status = (self.status & STATUS_READY) | (val & !STATUS_READY);
+        }
+        Ok(())
+    }
+
+    fn tick(&mut self, console: &mut dyn Console) -> io::Result<()> {
+        if self.status & STATUS_INTERRUPT_ENABLE == 0 {
+            return Ok(());
+        }
+
+        self.ticks += 1;
+        if self.ticks >= KEYBOARD_POLL_INTERVAL {
+            self.ticks = 0;
+            self.check_key(console)?;
+        }
+        Ok(())
+    }
+
+    fn interrupt(&self) -> Option<Interrupt> {
+        if self.status & (STATUS_READY | STATUS_INTERRUPT_ENABLE) == STATUS_READY | STATUS_INTERRUPT_ENABLE {
+            return Some(Interrupt { vector: KEYBOARD_VECTOR, priority: KEYBOARD_PRIORITY });
+        }
+        None
+    }
+
+    //Without interrupts the keyboard only does anything when read.
+    fn armed(&self) -> bool {
+        self.status & STATUS_INTERRUPT_ENABLE != 0
+    }
+
+    fn save_state(&self) -> Vec<u16> {
+        vec![self.status, self.data, (self.ticks >> 16) as u16, self.ticks as u16]
+    }
+
+    fn load_state(&mut self, state: &[u16]) {
+        if let &[status, data, ticks_high, ticks_low] = state {
+            self.status = status;
+            self.data = data;
+            self.ticks = (ticks_high as u32) << 16 | ticks_low as u32;
+        }
+    }
+}
+
+
+//DSR and DDR. Our console takes characters as fast as they come, so the
+//display is always ready and a write to DDR goes straight out.
+#[derive(Default)]
+pub struct Display;
+
+impl Display {
+    pub fn new() -> Display {
+        Display
+    }
+}
+
+impl Device for Display {
+    fn range(&self) -> RangeInclusive<u16> {
+        MemMapReg::MR_DSR as u16..=MemMapReg::MR_DDR as u16 + 1
+    }
+
+    fn read(&mut self, addr: u16, _console: &mut dyn Console) -> io::Result<u16> {
+        if addr == MemMapReg::MR_DSR as u16 {
+            Ok(STATUS_READY)
+        }
+        else {
+            Ok(0)
+        }
+    }
+
+    fn write(&mut self, addr: u16, val: u16, console: &mut dyn Console) -> io::Result<()> {
+        if addr == MemMapReg::MR_DDR as u16 {
+            console.write_byte(val as u8)?;
+            console.flush()?;
+        }
+        Ok(())
+    }
+
+    fn armed(&self) -> bool {
+        false
+    }
+}
+
+
+//A programmable interval timer. Writing N to TIR makes it expire every N
+//instructions (0 stops it). Expiring sets bit 15 of TSR, and with bit 14
+//of TSR set it also raises an interrupt. Reading TSR acknowledges the
+//expiry and clears bit 15 again.
+#[derive(Default)]
+pub struct Timer {
+    status: u16,
+    interval: u16,
+    count: u16,
+}
+
+impl Timer {
+    pub fn new() -> Timer {
+        Timer::default()
+    }
+}
+
+impl Device for Timer {
+    fn range(&self) -> RangeInclusive<u16> {
+        MemMapReg::MR_TSR as u16..=MemMapReg::MR_TIR as u16 + 1
+    }
+
+    fn read(&mut self, addr: u16, _console: &mut dyn Console) -> io::Result<u16> {
+        if addr == MemMapReg::MR_TSR as u16 {
+            let status = self.status;
+            self.status &= !STATUS_READY;
+            Ok(status)
+        }
+        else if addr == MemMapReg::MR_TIR as u16 {
+            Ok(self.interval)
+        }
+        else {
+            Ok(0)
+        }
+    }
+
+    fn write(&mut self, addr: u16, val: u16, _console: &mut dyn Console) -> io::Result<()> {
+        if addr == MemMapReg::MR_TSR as u16 {
+            self.status = (self.status & STATUS_READY) | (val & !STATUS_READY);
+        }
+        else if addr == MemMapReg::MR_TIR as u16 {
+            self.interval = val;
+            self.count = 0;
+        }
+        Ok(())
+    }
+
+    fn tick(&mut self, _console: &mut dyn Console) -> io::Result<()> {
+        if self.interval == 0 {
+            return Ok(());
+        }
+
+        self.count += 1;
+        if self.count >= self.interval {
+            self.count = 0;
+            self.status |= STATUS_READY;
+        }
+        Ok(())
+    }
+
+    fn interrupt(&self) -> Option<Interrupt> {
+        if self.status & (STATUS_READY | STATUS_INTERRUPT_ENABLE) == STATUS_READY | STATUS_INTERRUPT_ENABLE {
+            return Some(Interrupt { vector: TIMER_VECTOR, priority: TIMER_PRIORITY });
+        }
+        None
+    }
+
+    //Counting down, or an expiry still waiting to interrupt.
+    fn armed(&self) -> bool {
+        self.interval != 0 || self.interrupt().is_some()
+    }
+
+    fn save_state(&self) -> Vec<u16> {
+        vec![self.status, self.interval, self.count]
+    }
+
+    fn load_state(&mut self, state: &[u16]) {
+        if let &[status, interval, count] = state {
+            self.status = status;
+            self.interval = interval;
+            self.count = count;
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::console::BufferConsole;
+
+    //Answers reads with the last value written to it, plus one.
+    struct Latch {
+        range: RangeInclusive<u16>,
+        value: u16,
+    }
+
+    impl Device for Latch {
+        fn range(&self) -> RangeInclusive<u16> {
+            self.range.clone()
+        }
+
+        fn read(&mut self, _addr: u16, _console: &mut dyn Console) -> io::Result<u16> {
+            Ok(self.value + 1)
+        }
+
+        fn write(&mut self, _addr: u16, val: u16, _console: &mut dyn Console) -> io::Result<()> {
+            self.value = val;
+            Ok(())
+        }
+    }
+
+    fn latch(range: RangeInclusive<u16>) -> Box<dyn Device> {
+        Box::new(Latch { range, value: 0 })
+    }
+
+    fn read(bus: &mut DeviceBus, addr: u16, console: &mut dyn Console) -> Option<u16> {
+        bus.read(addr, console).map(|result| result.unwrap())
+    }
+
+    fn write(bus: &mut DeviceBus, addr: u16, val: u16, console: &mut dyn Console) {
+        bus.write(addr, val, console).unwrap().unwrap();
+    }
+
+    #[test]
+    fn devices_answer_for_their_addresses() {
+        let mut console = BufferConsole::new(b"");
+        let mut bus = DeviceBus::new();
+        bus.attach(latch(0xFE10..=0xFE11)).unwrap();
+
+        write(&mut bus, 0xFE11, 41, &mut console);
+        assert_eq!(read(&mut bus, 0xFE10, &mut console), Some(42));
+        assert_eq!(read(&mut bus, 0xFE12, &mut console), None);
+        assert_eq!(read(&mut bus, 0x3000, &mut console), None);
+    }
+
+    #[test]
+    fn attach_refuses_addresses_that_are_not_free() {
+        let mut bus = DeviceBus::new();
+        bus.attach(Box::new(Keyboard::new())).unwrap();
+
+        assert!(matches!(bus.attach(latch(0xFDFF..=0xFE10)), Err(VmError::NotIoAddress { addr: 0xFDFF })));
+        assert!(matches!(bus.attach(latch(0xFE03..=0xFE04)), Err(VmError::AddressInUse { addr: 0xFE03 })));
+        assert!(matches!(bus.attach(latch(0xFFFE..=0xFFFF)), Err(VmError::AddressInUse { addr: 0xFFFE })));
+        //Nothing was claimed by the attempts that failed.
+        bus.attach(latch(0xFE04..=0xFE10)).unwrap();
+    }
+
+    #[test]
+    fn the_keyboard_holds_a_key_until_it_is_read() {
+        let mut console = BufferConsole::new(b"xy");
+        let mut bus = DeviceBus::new();
+        bus.attach(Box::new(Keyboard::new())).unwrap();
+
+        assert_eq!(read(&mut bus, 0xFE00, &mut console), Some(STATUS_READY));
+        assert_eq!(read(&mut bus, 0xFE00, &mut console), Some(STATUS_READY));
+        assert_eq!(read(&mut bus, 0xFE02, &mut console), Some(u16::from(b'x')));
+        assert_eq!(read(&mut bus, 0xFE00, &mut console), Some(STATUS_READY));
+        assert_eq!(read(&mut bus, 0xFE02, &mut console), Some(u16::from(b'y')));
+        assert_eq!(read(&mut bus, 0xFE00, &mut console), Some(0));
+    }
+
+    #[test]
+    fn mcr_belongs_to_the_bus() {
+        let mut console = BufferConsole::new(b"");
+        let mut bus = DeviceBus::new();
+        assert_eq!(read(&mut bus, 0xFFFE, &mut console), Some(MCR_CLOCK_ENABLE));
+
+        write(&mut bus, 0xFFFE, 0x7FFF, &mut console);
+        assert!(!bus.clock_enabled());
+        assert_eq!(bus.mcr(), 0x7FFF);
+    }
+
+    #[test]
+    fn the_timer_expires_every_interval() {
+        let mut console = BufferConsole::new(b"");
+        let mut bus = DeviceBus::new();
+        bus.attach(Box::new(Timer::new())).unwrap();
+        assert!(!bus.armed());
+
+        write(&mut bus, 0xFE0A, 3, &mut console);
+        assert!(bus.armed());
+        for _ in 0..2 {
+            bus.tick(&mut console).unwrap();
+        }
+        assert_eq!(read(&mut bus, 0xFE08, &mut console), Some(0));
+        bus.tick(&mut console).unwrap();
+        assert_eq!(bus.interrupt_requests().count(), 0);
+        //Reading TSR acknowledges the expiry.
+        assert_eq!(read(&mut bus, 0xFE08, &mut console), Some(STATUS_READY));
+        assert_eq!(read(&mut bus, 0xFE08, &mut console), Some(0));
+
+        write(&mut bus, 0xFE08, STATUS_INTERRUPT_ENABLE, &mut console);
+        for _ in 0..3 {
+            bus.tick(&mut console).unwrap();
+        }
+        let timer = Interrupt { vector: TIMER_VECTOR, priority: TIMER_PRIORITY };
+        assert_eq!(bus.interrupt_requests().collect::<Vec<_>>(), [timer]);
+        read(&mut bus, 0xFE08, &mut console);
+        assert_eq!(bus.interrupt_requests().count(), 0);
+
+        write(&mut bus, 0xFE0A, 0, &mut console);
+        assert!(!bus.armed());
+    }
+
+    #[test]
+    fn the_keyboard_interrupts_once_it_sees_a_key() {
+        let mut console = BufferConsole::new(b"k");
+        let mut bus = DeviceBus::new();
+        bus.attach(Box::new(Keyboard::new())).unwrap();
+        assert!(!bus.armed());
+
+        write(&mut bus, 0xFE00, STATUS_INTERRUPT_ENABLE, &mut console);
+        for _ in 1..KEYBOARD_POLL_INTERVAL {
+            bus.tick(&mut console).unwrap();
+        }
+        assert_eq!(bus.interrupt_requests().count(), 0);
+        bus.tick(&mut console).unwrap();
+        let keyboard = Interrupt { vector: KEYBOARD_VECTOR, priority: KEYBOARD_PRIORITY };
+        assert_eq!(bus.interrupt_requests().collect::<Vec<_>>(), [keyboard]);
+
+        assert_eq!(read(&mut bus, 0xFE02, &mut console), Some(u16::from(b'k')));
+        assert_eq!(bus.interrupt_requests().count(), 0);
+    }
+}
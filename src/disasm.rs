@@ -0,0 +1,304 @@
+//Disassembler. Decodes words back into the assembly asm.rs accepts,
+//taking the instruction fields apart with the same decode() the VM uses.
+
+use std::fmt;
+
+use crate::TrapCode;
+use crate::decode::{decode, Instruction, Operand};
+use crate::symbols::SymbolTable;
+
+
+//Decodes `instr`, found at `addr`, into assembly. PC-relative operands
+//are shown as the label at the address they end up at, or as the address
+//if there is no label there. Words that are not an instruction come out
+//as a .FILL.
+pub fn disassemble(instr: u16, addr: u16, symbols: &SymbolTable) -> String {
+    assembly(instr, addr, symbols).unwrap_or_else(|| format!(".FILL x{:04X}", instr))
+}
+
+//Whether `instr` looks like code: not RES, not a BR that never branches,
+//and none of the bits the ISA leaves unused are set.
+pub fn is_instruction(instr: u16) -> bool {
+    assembly(instr, 0, &SymbolTable::new()).is_some()
+}
+
+fn target(addr: u16, symbols: &SymbolTable) -> String {
+    match symbols.name_at(addr) {
+        Some(name) => name.to_string(),
+        None => format!("x{:04X}", addr),
+    }
+}
+
+//The fields come from decode(), like the VM executes them. decode()
+//ignores the bits the ISA leaves unused, so they are checked here: a
+//word with any of them set is data, not code.
+fn assembly(instr: u16, addr: u16, symbols: &SymbolTable) -> Option<String> {
+    //Targets are relative to the incremented PC.
+    let target = |offset: u16| target(addr.wrapping_add(1).wrapping_add(offset), symbols);
+    let alu = |name: &str, dr: u8, sr1: u8, src2: Operand| match src2 {
+        Operand::Imm(imm5) => Some(format!("{} R{}, R{}, #{}", name, dr, sr1, imm5 as i16)),
+        Operand::Reg(sr2) if instr & 0x18 == 0 => Some(format!("{} R{}, R{}, R{}", name, dr, sr1, sr2)),
+        Operand::Reg(_) => None,
+    };
+
+    let text = match decode(instr) {
+        Instruction::Add { dr, sr1, src2 } => return alu("ADD", dr, sr1, src2),
+        Instruction::And { dr, sr1, src2 } => return alu("AND", dr, sr1, src2),
+        Instruction::Not { dr, sr } if instr & 0x3F == 0x3F => format!("NOT R{}, R{}", dr, sr),
+        //With none of n, z or p set it never branches.
+        Instruction::Br { cond, offset } if cond != 0 => {
+            let n = if cond & 0x4 != 0 { "n" } else { "" };
+            let z = if cond & 0x2 != 0 { "z" } else { "" };
+            let p = if cond & 0x1 != 0 { "p" } else { "" };
+            format!("BR{}{}{} {}", n, z, p, target(offset))
+        },
+        Instruction::Jmp { base: 7 } if instr & 0x0E3F == 0 => "RET".to_string(),
+        Instruction::Jmp { base } if instr & 0x0E3F == 0 => format!("JMP R{}", base),
+        Instruction::Jsr { offset } => format!("JSR {}", target(offset)),
+        Instruction::Jsrr { base } if instr & 0x063F == 0 => format!("JSRR R{}", base),
+        Instruction::Ld { dr, offset } => format!("LD R{}, {}", dr, target(offset)),
+        Instruction::Ldi { dr, offset } => format!("LDI R{}, {}", dr, target(offset)),
+        Instruction::Lea { dr, offset } => format!("LEA R{}, {}", dr, target(offset)),
+        Instruction::St { sr, offset } => format!("ST R{}, {}", sr, target(offset)),
+        Instruction::Sti { sr, offset } => format!("STI R{}, {}", sr, target(offset)),
+        Instruction::Ldr { dr, base, offset } => format!("LDR R{}, R{}, #{}", dr, base, offset as i16),
+        Instruction::Str { sr, base, offset } => format!("STR R{}, R{}, #{}", sr, base, offset as i16),
+        Instruction::Rti if instr & 0x0FFF == 0 => "RTI".to_string(),
+        Instruction::Trap { vector } if instr & 0x0F00 == 0 => {
+            let alias = match vector as u16 {
+                v if v == TrapCode::GETC as u16 => "GETC",
+                v if v == TrapCode::OUT as u16 => "OUT",
+                v if v == TrapCode::PUTS as u16 => "PUTS",
+                v if v == TrapCode::IN as u16 => "IN",
+                v if v == TrapCode::PUTSP as u16 => "PUTSP",
+                v if v == TrapCode::HALT as u16 => "HALT",
+                v => return Some(format!("TRAP x{:02X}", v)),
+            };
+            alias.to_string()
+        },
+        //RES, and the words above with unused bits set.
+        _ => return None,
+    };
+
+    Some(text)
+}
+
+
+//One line of a listing. `text` is empty for the words of a string after
+//its first one, the .STRINGZ on the first line covers them. `label` is
+//the symbol at `addr`, if any. It is left out of the Display output,
+//listings print it on a line of its own.
+pub struct Line {
+    pub label: Option<String>,
+    pub addr: u16,
+    pub word: u16,
+    pub text: String,
+}
+
+impl fmt::Display for Line {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.text.is_empty() {
+            write!(f, "x{:04X}  {:04X}", self.addr, self.word)
+        }
+        else {
+            write!(f, "x{:04X}  {:04X}  {}", self.addr, self.word, self.text)
+        }
+    }
+}
+
+//Strings shorter than this are left as code. Two characters already are
+//rare among real instructions: every printable character decodes as a
+//BR that never branches.
+const MIN_STRING_LEN: usize = 2;
+
+//Disassembles `words`, which start at `origin`, one line per word.
+//Runs of printable characters followed by a zero are shown as a
+//.STRINGZ and words that are not an instruction as a .FILL. A label
+//starts a new run: the words before it were a .FILL or string of their
+//own.
+pub fn listing(origin: u16, words: &[u16], symbols: &SymbolTable) -> Vec<Line> {
+    let mut lines = Vec::with_capacity(words.len());
+    let mut i = 0;
+
+    while i < words.len() {
+        let addr = origin.wrapping_add(i as u16);
+
+        if let Some(len) = string_at(&words[i..], addr, symbols) {
+            let text: String = words[i..i + len].iter().map(|&c| escape(c as u8)).collect();
+            lines.push(Line {
+                label: label(addr, symbols),
+                addr,
+                word: words[i],
+                text: format!(".STRINGZ \"{}\"", text),
+            });
+            //The rest of the characters and the terminating zero.
+            for (j, &word) in words.iter().enumerate().take(i + len + 1).skip(i + 1) {
+                let addr = origin.wrapping_add(j as u16);
+                lines.push(Line { label: label(addr, symbols), addr, word, text: String::new() });
+            }
+            i += len + 1;
+            continue;
+        }
+
+        lines.push(Line {
+            label: label(addr, symbols),
+            addr,
+            word: words[i],
+            text: disassemble(words[i], addr, symbols),
+        });
+        i += 1;
+    }
+
+    lines
+}
+
+fn label(addr: u16, symbols: &SymbolTable) -> Option<String> {
+    symbols.name_at(addr).map(str::to_string)
+}
+
+fn is_text(word: u16) -> bool {
+    matches!(word, 0x20..=0x7E | 0x09 | 0x0A | 0x0D | 0x1B)
+}
+
+//The number of characters of the string starting at words[0], which is
+//at `addr`, if there is one there.
+fn string_at(words: &[u16], addr: u16, symbols: &SymbolTable) -> Option<usize> {
+    let unlabeled = |offset: usize| symbols.name_at(addr.wrapping_add(offset as u16)).is_none();
+
+    let len = words.iter()
+        .enumerate()
+        .take_while(|&(offset, &word)| is_text(word) && (offset == 0 || unlabeled(offset)))
+        .count();
+    if len >= MIN_STRING_LEN && words.get(len) == Some(&0) && unlabeled(len) {
+        return Some(len);
+    }
+    None
+}
+
+//The escapes asm.rs understands in a .STRINGZ.
+fn escape(c: u8) -> String {
+    match c {
+        b'\n' => "\\n".to_string(),
+        b'\t' => "\\t".to_string(),
+        b'\r' => "\\r".to_string(),
+        0x1B => "\\e".to_string(),
+        b'"' => "\\\"".to_string(),
+        b'\\' => "\\\\".to_string(),
+        c => (c as char).to_string(),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble;
+
+    //The listing as source asm.rs accepts: labels in front of their line
+    //and the words a .STRINGZ covers left out. Only targets with a label
+    //come back the same, asm.rs takes a bare number for an offset.
+    fn source(origin: u16, lines: &[Line]) -> String {
+        let mut text = format!(".ORIG x{:04X}\n", origin);
+        for line in lines.iter().filter(|line| !line.text.is_empty()) {
+            text += &format!("{} {}\n", line.label.as_deref().unwrap_or(""), line.text);
+        }
+        text + ".END\n"
+    }
+
+    #[test]
+    fn disassembles_instructions() {
+        let mut symbols = SymbolTable::new();
+        symbols.insert("LOOP", 0x3000);
+
+        assert_eq!(disassemble(0x127F, 0x3000, &symbols), "ADD R1, R1, #-1");
+        assert_eq!(disassemble(0x03FE, 0x3001, &symbols), "BRp LOOP");
+        assert_eq!(disassemble(0x0E05, 0x3001, &symbols), "BRnzp x3007");
+        assert_eq!(disassemble(0xC1C0, 0x3000, &symbols), "RET");
+        assert_eq!(disassemble(0xF025, 0x3000, &symbols), "HALT");
+        assert_eq!(disassemble(0xF0FF, 0x3000, &symbols), "TRAP xFF");
+        //NOT with the low bits clear and RES are not instructions.
+        assert_eq!(disassemble(0x9240, 0x3000, &symbols), ".FILL x9240");
+        assert_eq!(disassemble(0xD000, 0x3000, &symbols), ".FILL xD000");
+    }
+
+    #[test]
+    fn listing_assembles_back_to_the_same_words() {
+        let program = assemble("
+                .ORIG x3000
+                LEA R0, MSG
+                PUTS
+                AND R2, R2, #0
+                ADD R2, R2, R3
+        LOOP    LDR R1, R0, #-3
+                NOT R1, R1
+                BRnp LOOP
+                JSR SUB
+                LDI R4, VAL
+                STI R4, VAL
+                ST R4, VAL
+                JSRR R5
+                JMP R6
+                TRAP x26
+                HALT
+        SUB     RET
+        VAL     .FILL x0041
+        MSG     .STRINGZ \"hi\\n\"
+                .END
+        ").unwrap();
+
+        let lines = listing(program.origin, &program.words, &program.symbols);
+        let again = assemble(&source(program.origin, &lines)).unwrap();
+        assert_eq!(again.words, program.words);
+        assert_eq!(again.symbols.get("MSG"), program.symbols.get("MSG"));
+    }
+
+    #[test]
+    fn strings_stop_at_labels() {
+        let program = assemble("
+                .ORIG x3000
+        VAL     .FILL x0041
+        MSG     .STRINGZ \"hi\\n\"
+        NUM     .FILL x0042
+                .FILL x0043
+        END     .FILL #0
+                .END
+        ").unwrap();
+
+        let lines = listing(program.origin, &program.words, &program.symbols);
+        let texts: Vec<&str> = lines.iter().map(|line| line.text.as_str()).collect();
+        assert_eq!(texts, vec![
+            ".FILL x0041",
+            ".STRINGZ \"hi\\n\"", "", "", "",
+            ".FILL x0042",
+            ".FILL x0043",
+            ".FILL x0000",
+        ]);
+    }
+
+    #[test]
+    fn strings_and_data_get_their_own_lines() {
+        let program = assemble("
+                .ORIG x3000
+                LEA R0, MSG
+                PUTS
+                HALT
+        VAL     .FILL xD000
+        MSG     .STRINGZ \"hi\\n\"
+                .FILL x0041
+                .END
+        ").unwrap();
+
+        let lines = listing(program.origin, &program.words, &program.symbols);
+        let texts: Vec<&str> = lines.iter().map(|line| line.text.as_str()).collect();
+        assert_eq!(texts, vec![
+            "LEA R0, MSG",
+            "PUTS",
+            "HALT",
+            ".FILL xD000",
+            ".STRINGZ \"hi\\n\"", "", "", "",
+            ".FILL x0041",
+        ]);
+        assert_eq!(lines[4].to_string(), "x3004  0068  .STRINGZ \"hi\\n\"");
+        assert_eq!(lines[5].to_string(), "x3005  0069");
+    }
+}
@@ -0,0 +1,127 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+use crate::symbols::SymbolTable;
+
+
+//Everything that can go wrong while loading an image or executing
+//instructions. These used to end the process with an exit code, now they
+//are handed back to whoever is driving the VM.
+#[derive(Debug)]
+pub enum VmError {
+    //The instruction at `pc` has an opcode we cannot execute (RES) and no
+    //illegal opcode exception handler is installed.
+    IllegalOpcode { pc: u16, instr: u16 },
+    //User code at `pc` ran RTI and no privilege mode exception handler
+    //is installed (or exceptions are turned off).
+    PrivilegeViolation { pc: u16 },
+    //User code at `pc` accessed `addr`, outside of user space, and no
+    //access control violation handler is installed.
+    AccessViolation { pc: u16, addr: u16 },
+    //A TRAP instruction used a vector with no trap routine behind it.
+    UnknownTrap { vector: u8 },
+    //The image would run past the last memory address (0xFFFF).
+    ImageTooLarge { origin: u16, words: usize },
+    //The image is missing its origin word or ends in the middle of a word.
+    TruncatedImage,
+    //A device tried to claim an address outside the I/O page.
+    NotIoAddress { addr: u16 },
+    //A device tried to claim an address another device already owns.
+    AddressInUse { addr: u16 },
+    //A snapshot file could not be read back, for the reason given.
+    BadSnapshot(&'static str),
+    Io(io::Error),
+}
+
+impl fmt::Display for VmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VmError::IllegalOpcode { pc, instr } => {
+                write!(f, "Illegal opcode {:#06x} at address {:#06x}", instr, pc)
+            },
+            VmError::PrivilegeViolation { pc } => {
+                write!(f, "Privilege mode violation at address {:#06x}", pc)
+            },
+            VmError::AccessViolation { pc, addr } => {
+                write!(f, "Access control violation at address {:#06x} accessing {:#06x}", pc, addr)
+            },
+            VmError::UnknownTrap { vector } => {
+                write!(f, "Invalid trap vector {:#04x}", vector)
+            },
+            VmError::ImageTooLarge { origin, words } => {
+                write!(f, "Image of {} words does not fit in memory at origin {:#06x}", words, origin)
+            },
+            VmError::TruncatedImage => write!(f, "Image is truncated"),
+            VmError::NotIoAddress { addr } => {
+                write!(f, "Address {:#06x} is not in the I/O page", addr)
+            },
+            VmError::AddressInUse { addr } => {
+                write!(f, "Address {:#06x} is already claimed by another device", addr)
+            },
+            VmError::BadSnapshot(reason) => write!(f, "Bad snapshot: {}", reason),
+            VmError::Io(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl VmError {
+    //Like the Display output, but addresses in the program are followed
+    //by the label they belong to: "at address 0x3005 (LOOP+1)".
+    pub fn describe(&self, symbols: &SymbolTable) -> String {
+        let at = |addr: u16| match symbols.describe(addr) {
+            Some(name) => format!("{:#06x} ({})", addr, name),
+            None => format!("{:#06x}", addr),
+        };
+
+        match self {
+            VmError::IllegalOpcode { pc, instr } => {
+                format!("Illegal opcode {:#06x} at address {}", instr, at(*pc))
+            },
+            VmError::PrivilegeViolation { pc } => {
+                format!("Privilege mode violation at address {}", at(*pc))
+            },
+            VmError::AccessViolation { pc, addr } => {
+                format!("Access control violation at address {} accessing {}", at(*pc), at(*addr))
+            },
+            _ => self.to_string(),
+        }
+    }
+}
+
+impl Error for VmError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            VmError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for VmError {
+    fn from(err: io::Error) -> VmError {
+        VmError::Io(err)
+    }
+}
+
+
+//Why `Vm::run()` handed control back without an error.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StopReason {
+    //The program executed HALT.
+    Halted,
+    //PC reached an address with a breakpoint set on it.
+    Breakpoint,
+    //The step budget given to `Vm::run_for()` (or the cycle budget given
+    //to `Vm::run_for_cycles()`) ran out.
+    StepLimit,
+    //The last instruction made an access a watchpoint is set on. The hits
+    //are waiting in `Vm::take_watch_hits()`.
+    Watchpoint,
+    //`Vm::run_back()` undid every instruction in the history.
+    HistoryStart,
+    //The console interrupted a read the program was waiting in (see
+    //Console::read_byte). The PC is still at the instruction that read,
+    //running again retries it.
+    Interrupted,
+}
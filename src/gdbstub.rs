@@ -0,0 +1,686 @@
+//A stub for the GDB Remote Serial Protocol, so that gdb (or anything
+//else speaking the protocol) can drive the VM over a socket.
+//Reference: https://sourceware.org/gdb/current/onlinedocs/gdb.html/Remote-Protocol.html
+//
+//The LC3 addresses words, but gdb only knows byte addressed memory for
+//an architecture it has never heard of. So every address the stub sends
+//or takes is a byte address: word W is bytes 2W and 2W+1, big-endian like
+//in .obj images. That goes for memory packets, breakpoints, watchpoints,
+//the address to resume at and the PC, which gdb sees as twice the LC3's
+//PC (so it is 32 bits wide). `x/i $pc`, `break *$pc` and `x/xh $pc` then
+//all mean the instruction the LC3 is at, and `x/xh 0x6000` shows the word
+//at x3000. The registers are R0-R7 (0-7), PC (8) and PSR (9), as
+//described by the target description the stub hands out.
+
+use std::collections::VecDeque;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::ops::Range;
+use std::os::unix::net::{UnixListener, UnixStream};
+
+use crate::{Vm, VmError, StopReason};
+use crate::register::Reg;
+use crate::watch::{Watchpoint, WatchHit, WatchKind};
+
+
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.lc3.core">
+    <flags id="psr_flags" size="2">
+      <field name="P" start="0" end="0"/>
+      <field name="Z" start="1" end="1"/>
+      <field name="N" start="2" end="2"/>
+      <field name="PL" start="8" end="10"/>
+      <field name="USER" start="15" end="15"/>
+    </flags>
+    <reg name="r0" bitsize="16" type="int16" regnum="0"/>
+    <reg name="r1" bitsize="16" type="int16"/>
+    <reg name="r2" bitsize="16" type="int16"/>
+    <reg name="r3" bitsize="16" type="int16"/>
+    <reg name="r4" bitsize="16" type="int16"/>
+    <reg name="r5" bitsize="16" type="int16"/>
+    <reg name="r6" bitsize="16" type="data_ptr"/>
+    <reg name="r7" bitsize="16" type="code_ptr"/>
+    <reg name="pc" bitsize="32" type="code_ptr"/>
+    <reg name="psr" bitsize="16" type="psr_flags"/>
+  </feature>
+</target>
+"#;
+
+//The registers in the order of the target description.
+const REGISTERS: [Reg; 10] = [
+    Reg::R0, Reg::R1, Reg::R2, Reg::R3, Reg::R4, Reg::R5, Reg::R6, Reg::R7, Reg::PC, Reg::PSR,
+];
+
+//While the program runs, the stub checks for gdb's interrupt request
+//after this many instructions.
+const RUN_CHUNK: u64 = 10_000;
+
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+const SIGSEGV: u8 = 11;
+
+
+//The socket gdb is connected through.
+pub trait Connection: Read + Write {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()>;
+}
+
+impl Connection for TcpStream {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        TcpStream::set_nonblocking(self, nonblocking)
+    }
+}
+
+impl Connection for UnixStream {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        UnixStream::set_nonblocking(self, nonblocking)
+    }
+}
+
+//Waits for gdb to connect. `addr` is a TCP port, host:port, or
+//unix:/path/to/socket.
+pub fn accept(addr: &str) -> io::Result<Box<dyn Connection>> {
+    if let Some(path) = addr.strip_prefix("unix:") {
+        let listener = UnixListener::bind(path)?;
+        let (stream, _) = listener.accept()?;
+        //Nobody else is going to connect, the socket file can go.
+        let _ = fs::remove_file(path);
+        return Ok(Box::new(stream));
+    }
+
+    let addr = if addr.contains(':') { addr.to_string() } else { format!("127.0.0.1:{}", addr) };
+    let listener = TcpListener::bind(addr)?;
+    let (stream, _) = listener.accept()?;
+    stream.set_nodelay(true)?;
+    Ok(Box::new(stream))
+}
+
+
+//Why the program stopped, in the stop reply sent to gdb.
+enum Stop {
+    Signal(u8),
+    Breakpoint,
+    Watch(WatchHit),
+    Exited,
+    //Running backwards got to the oldest instruction in the history.
+    HistoryStart,
+}
+
+pub struct GdbStub<'a> {
+    vm: &'a mut Vm,
+    conn: Box<dyn Connection>,
+    //Bytes received but not looked at yet.
+    input: VecDeque<u8>,
+    no_ack: bool,
+    last_stop: Stop,
+}
+
+impl<'a> GdbStub<'a> {
+    pub fn new(vm: &'a mut Vm, conn: Box<dyn Connection>) -> GdbStub<'a> {
+        GdbStub {
+            vm,
+            conn,
+            input: VecDeque::new(),
+            no_ack: false,
+            last_stop: Stop::Signal(SIGTRAP),
+        }
+    }
+
+    //Serves gdb until it detaches, kills the program or disconnects.
+    pub fn serve(&mut self) -> io::Result<()> {
+        loop {
+            let packet = match self.read_packet()? {
+                Some(packet) => packet,
+                None => return Ok(()),
+            };
+
+            match packet.first() {
+                Some(b'k') => return Ok(()),
+                Some(b'D') => {
+                    self.send("OK")?;
+                    return Ok(());
+                },
+                _ => {},
+            }
+
+            let reply = self.handle(&packet)?;
+            self.send(&reply)?;
+        }
+    }
+
+    fn handle(&mut self, packet: &[u8]) -> io::Result<String> {
+        let text = String::from_utf8_lossy(packet);
+        let (command, args) = text.split_at(1.min(text.len()));
+
+        let reply = match command {
+            "?" => self.stop_reply(),
+            "g" => REGISTERS.iter().map(|&reg| self.read_register(reg)).collect(),
+            "G" => {
+                let mut rest = args;
+                let mut ok = true;
+                for &reg in REGISTERS.iter() {
+                    let (value, tail) = rest.split_at(register_digits(reg).min(rest.len()));
+                    ok &= self.write_register(reg, value);
+                    rest = tail;
+                }
+                ok_or_error(ok && rest.is_empty())
+            },
+            "p" => match parse_hex(args).and_then(|n| REGISTERS.get(n as usize)) {
+                Some(&reg) => self.read_register(reg),
+                None => "E00".to_string(),
+            },
+            "P" => {
+                let write = args.split_once('=')
+                    .and_then(|(n, value)| Some((*REGISTERS.get(parse_hex(n)? as usize)?, value)));
+                match write {
+                    Some((reg, value)) => ok_or_error(self.write_register(reg, value)),
+                    None => "E00".to_string(),
+                }
+            },
+            "m" => match parse_range(args) {
+                Some(bytes) => {
+                    let memory = self.vm.memory();
+                    bytes.map(|addr| format!("{:02x}", word_bytes(memory, addr)[(addr & 1) as usize])).collect()
+                },
+                None => "E00".to_string(),
+            },
+            "M" => {
+                let write = args.split_once(':')
+                    .and_then(|(range, data)| Some((parse_range(range)?, parse_bytes(data))));
+                match write {
+                    Some((range, bytes)) if bytes.len() == range.len() => {
+                        let memory = self.vm.memory_mut();
+                        for (addr, byte) in range.zip(bytes) {
+                            let mut word = word_bytes(memory, addr);
+                            word[(addr & 1) as usize] = byte;
+                            memory[(addr >> 1) as usize] = u16::from_be_bytes(word);
+                        }
+                        "OK".to_string()
+                    },
+                    _ => "E00".to_string(),
+                }
+            },
+            "Z" | "z" => self.breakpoint(command == "Z", args),
+            "s" => {
+                self.resume_at(args);
+                self.last_stop = self.step();
+                self.stop_reply()
+            },
+            "c" => {
+                self.resume_at(args);
+                self.last_stop = self.cont()?;
+                self.stop_reply()
+            },
+            //bs and bc, reverse step and continue.
+            "b" => {
+                self.last_stop = match args {
+                    "s" => {
+                        if self.vm.step_back() {
+                            self.after_run(Ok(StopReason::StepLimit))
+                        }
+                        else {
+                            Stop::HistoryStart
+                        }
+                    },
+                    "c" => {
+                        let result = self.vm.run_back();
+                        self.after_run(Ok(result))
+                    },
+                    _ => return Ok(String::new()),
+                };
+                self.stop_reply()
+            },
+            "H" => "OK".to_string(),
+            "q" | "Q" => self.query(&text),
+            //Anything else, including vCont and the binary X packet, is
+            //not supported: an empty reply makes gdb fall back to the
+            //plain packets.
+            _ => String::new(),
+        };
+
+        Ok(reply)
+    }
+
+    fn query(&mut self, text: &str) -> String {
+        if text.starts_with("qSupported") {
+            return "PacketSize=4000;qXfer:features:read+;QStartNoAckMode+;swbreak+;ReverseStep+;ReverseContinue+".to_string();
+        }
+        if text == "QStartNoAckMode" {
+            self.no_ack = true;
+            return "OK".to_string();
+        }
+        if let Some(range) = text.strip_prefix("qXfer:features:read:target.xml:") {
+            return match range.split_once(',').and_then(|(off, len)| Some((parse_hex(off)?, parse_hex(len)?))) {
+                Some((offset, len)) => {
+                    let xml = TARGET_XML.as_bytes();
+                    let start = (offset as usize).min(xml.len());
+                    let end = (start + len as usize).min(xml.len());
+                    let chunk = String::from_utf8_lossy(&xml[start..end]);
+                    format!("{}{}", if end == xml.len() { "l" } else { "m" }, chunk)
+                },
+                None => "E00".to_string(),
+            };
+        }
+
+        match text {
+            "qAttached" => "1".to_string(),
+            "qC" => "QC1".to_string(),
+            "qfThreadInfo" => "m1".to_string(),
+            "qsThreadInfo" => "l".to_string(),
+            _ => String::new(),
+        }
+    }
+
+    //The register in the hex gdb expects, the PC as a byte address.
+    fn read_register(&self, reg: Reg) -> String {
+        match reg {
+            Reg::PC => format!("{:08x}", 2 * self.vm.reg(reg) as u32),
+            _ => hex_word(self.vm.reg(reg)),
+        }
+    }
+
+    //False if `value` isn't a value for the register: not enough hex
+    //digits, or for the PC an odd byte address or one past memory.
+    fn write_register(&mut self, reg: Reg, value: &str) -> bool {
+        let value = match parse_hex(value) {
+            Some(parsed) if value.len() == register_digits(reg) => parsed,
+            _ => return false,
+        };
+        match reg {
+            Reg::PC => match byte_to_word(value) {
+                Some(addr) => self.vm.set_pc(addr),
+                None => return false,
+            },
+            _ => self.vm.set_reg(reg, value as u16),
+        }
+        true
+    }
+
+    //Z/z TYPE,ADDR,KIND. Types 0 and 1 are breakpoints, at the word the
+    //byte address ADDR starts. 2 to 4 are write, read and access
+    //watchpoints on the KIND bytes from ADDR.
+    fn breakpoint(&mut self, insert: bool, args: &str) -> String {
+        let mut fields = args.split(',');
+        let kind = fields.next().and_then(parse_hex);
+        let addr = fields.next().and_then(parse_hex);
+        let len = fields.next().and_then(parse_hex);
+        let (kind, addr) = match (kind, addr) {
+            (Some(kind), Some(addr)) if addr <= 0x1FFFF => (kind, addr),
+            _ => return "E00".to_string(),
+        };
+
+        let watch = match kind {
+            0 | 1 => {
+                let addr = match byte_to_word(addr) {
+                    Some(addr) => addr,
+                    None => return "E00".to_string(),
+                };
+                if insert {
+                    self.vm.add_breakpoint(addr);
+                }
+                else {
+                    self.vm.remove_breakpoint(addr);
+                }
+                return "OK".to_string();
+            },
+            2 => WatchKind::Write,
+            3 => WatchKind::Read,
+            4 => WatchKind::Access,
+            _ => return String::new(),
+        };
+
+        //The words the bytes are in, none of which may be past memory.
+        let last = match addr.checked_add(len.unwrap_or(1).max(1) - 1) {
+            Some(last) if last <= 0x1FFFF => last,
+            _ => return "E00".to_string(),
+        };
+        let watchpoint = Watchpoint::new(watch, (addr >> 1) as u16..=(last >> 1) as u16);
+        if insert {
+            self.vm.add_watchpoint(watchpoint);
+        }
+        else if let Some(index) = self.vm.watchpoints().iter().position(|w| *w == watchpoint) {
+            self.vm.remove_watchpoint(index);
+        }
+        "OK".to_string()
+    }
+
+    //s and c may give the byte address to resume at.
+    fn resume_at(&mut self, args: &str) {
+        if let Some(addr) = parse_hex(args).and_then(byte_to_word) {
+            self.vm.set_pc(addr);
+        }
+    }
+
+    fn step(&mut self) -> Stop {
+        if !self.vm.is_running() {
+            return Stop::Exited;
+        }
+        if let Err(err) = self.vm.step() {
+            return error_stop(&err);
+        }
+        self.after_run(Ok(StopReason::StepLimit))
+    }
+
+    fn cont(&mut self) -> io::Result<Stop> {
+        loop {
+            let result = self.vm.run_for(RUN_CHUNK);
+            if !matches!(result, Ok(StopReason::StepLimit)) {
+                return Ok(self.after_run(result));
+            }
+            if self.interrupt_requested()? {
+                return Ok(Stop::Signal(SIGINT));
+            }
+        }
+    }
+
+    fn after_run(&mut self, result: Result<StopReason, VmError>) -> Stop {
+        if let Some(hit) = self.vm.take_watch_hits().into_iter().next() {
+            return Stop::Watch(hit);
+        }
+        match result {
+            Ok(StopReason::Halted) => Stop::Exited,
+            Ok(StopReason::Breakpoint) => Stop::Breakpoint,
+            Ok(StopReason::HistoryStart) => Stop::HistoryStart,
+            Ok(_) => Stop::Signal(SIGTRAP),
+            Err(err) => error_stop(&err),
+        }
+    }
+
+    fn stop_reply(&self) -> String {
+        match &self.last_stop {
+            Stop::Signal(signal) => format!("S{:02x}", signal),
+            Stop::Breakpoint => format!("T{:02x}swbreak:;", SIGTRAP),
+            Stop::Watch(hit) => {
+                let kind = match hit.kind {
+                    WatchKind::Write => "watch",
+                    _ => "rwatch",
+                };
+                format!("T{:02x}{}:{:x};", SIGTRAP, kind, 2 * hit.addr as u32)
+            },
+            Stop::Exited => "W00".to_string(),
+            Stop::HistoryStart => format!("T{:02x}replaylog:begin;", SIGTRAP),
+        }
+    }
+
+    //gdb asks to stop a running program by sending a lone 0x03.
+    fn interrupt_requested(&mut self) -> io::Result<bool> {
+        let mut buf = [0u8; 256];
+        self.conn.set_nonblocking(true)?;
+        let read = self.conn.read(&mut buf);
+        self.conn.set_nonblocking(false)?;
+
+        match read {
+            Ok(0) => Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(n) => {
+                let interrupt = buf[..n].contains(&0x03);
+                self.input.extend(buf[..n].iter().filter(|&&byte| byte != 0x03));
+                Ok(interrupt)
+            },
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+
+    //None once gdb has hung up.
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        if self.input.is_empty() {
+            let mut buf = [0u8; 4096];
+            let n = match self.conn.read(&mut buf) {
+                Ok(n) => n,
+                Err(err) if err.kind() == io::ErrorKind::ConnectionReset => 0,
+                Err(err) => return Err(err),
+            };
+            if n == 0 {
+                return Ok(None);
+            }
+            self.input.extend(&buf[..n]);
+        }
+        Ok(self.input.pop_front())
+    }
+
+    //Reads the next `$data#cs` packet, acknowledging it.
+    fn read_packet(&mut self) -> io::Result<Option<Vec<u8>>> {
+        loop {
+            //Acks for our replies and stray interrupts end up here.
+            loop {
+                match self.read_byte()? {
+                    Some(b'$') => break,
+                    Some(_) => continue,
+                    None => return Ok(None),
+                }
+            }
+
+            let mut data = Vec::new();
+            loop {
+                match self.read_byte()? {
+                    Some(b'#') => break,
+                    Some(byte) => data.push(byte),
+                    None => return Ok(None),
+                }
+            }
+
+            let mut checksum = [0u8; 2];
+            for byte in checksum.iter_mut() {
+                match self.read_byte()? {
+                    Some(c) => *byte = c,
+                    None => return Ok(None),
+                }
+            }
+
+            let expected = std::str::from_utf8(&checksum).ok().and_then(|cs| u8::from_str_radix(cs, 16).ok());
+            if self.no_ack {
+                return Ok(Some(data));
+            }
+            if expected == Some(checksum_of(&data)) {
+                self.conn.write_all(b"+")?;
+                return Ok(Some(data));
+            }
+            self.conn.write_all(b"-")?;
+        }
+    }
+
+    fn send(&mut self, data: &str) -> io::Result<()> {
+        let packet = format!("${}#{:02x}", data, checksum_of(data.as_bytes()));
+        loop {
+            self.conn.write_all(packet.as_bytes())?;
+            self.conn.flush()?;
+            if self.no_ack {
+                return Ok(());
+            }
+
+            match self.read_byte()? {
+                Some(b'-') => continue,
+                _ => return Ok(()),
+            }
+        }
+    }
+}
+
+
+fn error_stop(err: &VmError) -> Stop {
+    match err {
+        VmError::IllegalOpcode { .. } | VmError::PrivilegeViolation { .. } => Stop::Signal(SIGILL),
+        VmError::AccessViolation { .. } => Stop::Signal(SIGSEGV),
+        _ => Stop::Signal(SIGTRAP),
+    }
+}
+
+fn checksum_of(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte))
+}
+
+fn ok_or_error(ok: bool) -> String {
+    if ok { "OK" } else { "E00" }.to_string()
+}
+
+fn hex_word(word: u16) -> String {
+    format!("{:04x}", word)
+}
+
+//How many hex digits the register takes in g, G, p and P packets.
+fn register_digits(reg: Reg) -> usize {
+    if reg == Reg::PC { 8 } else { 4 }
+}
+
+//The word a byte address is the start of, None for the low byte of a
+//word or an address past memory.
+fn byte_to_word(addr: u32) -> Option<u16> {
+    if addr % 2 != 0 || addr > 0x1FFFF {
+        return None;
+    }
+    Some((addr >> 1) as u16)
+}
+
+fn parse_hex(text: &str) -> Option<u32> {
+    u32::from_str_radix(text, 16).ok()
+}
+
+fn parse_bytes(text: &str) -> Vec<u8> {
+    text.as_bytes()
+        .chunks(2)
+        .filter_map(|chunk| u8::from_str_radix(std::str::from_utf8(chunk).ok()?, 16).ok())
+        .collect()
+}
+
+//ADDR,LENGTH as used by m and M, the byte addresses they cover. Memory
+//ends at byte x1FFFF, the low half of word xFFFF.
+fn parse_range(text: &str) -> Option<Range<u32>> {
+    let (addr, len) = text.split_once(',')?;
+    let addr = parse_hex(addr)?;
+    let len = parse_hex(len)?;
+    if addr > 0x1FFFF || len > 0x20000 - addr {
+        return None;
+    }
+    Some(addr..addr + len)
+}
+
+//The word byte address `addr` is in, as its high and low byte.
+fn word_bytes(memory: &[u16], addr: u32) -> [u8; 2] {
+    memory[(addr >> 1) as usize].to_be_bytes()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use super::*;
+    use crate::console::BufferConsole;
+
+    //Plays back what gdb sent and keeps what the stub answered.
+    struct Script {
+        input: io::Cursor<Vec<u8>>,
+        output: Rc<RefCell<Vec<u8>>>,
+    }
+
+    impl Read for Script {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for Script {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.output.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Connection for Script {
+        fn set_nonblocking(&self, _nonblocking: bool) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn packet(data: &str) -> String {
+        format!("${}#{:02x}", data, checksum_of(data.as_bytes()))
+    }
+
+    fn quiet_vm() -> Vm {
+        Vm::with_console(Box::new(BufferConsole::new(b"")))
+    }
+
+    //Serves `input` and returns everything the stub sent.
+    fn serve(vm: &mut Vm, input: &str) -> String {
+        let output = Rc::new(RefCell::new(Vec::new()));
+        let script = Script { input: io::Cursor::new(input.as_bytes().to_vec()), output: output.clone() };
+        GdbStub::new(vm, Box::new(script)).serve().unwrap();
+        let sent = output.borrow().clone();
+        String::from_utf8(sent).unwrap()
+    }
+
+    #[test]
+    fn checksums() {
+        assert_eq!(packet("OK"), "$OK#9a");
+        assert_eq!(packet("g"), "$g#67");
+        assert_eq!(packet(""), "$#00");
+    }
+
+    #[test]
+    fn memory_is_byte_addressed() {
+        let mut vm = quiet_vm();
+        vm.memory_mut()[0x3000..0x3003].copy_from_slice(&[0x1234, 0xABCD, 0x0042]);
+
+        let sent = serve(&mut vm, &(packet("m6000,6") + "+" + &packet("m6003,2") + "+"));
+        assert_eq!(sent, format!("+{}+{}", packet("1234abcd0042"), packet("cd00")));
+
+        let sent = serve(&mut vm, &(packet("M6001,2:5678") + "+" + &packet("m1fffe,3") + "+"));
+        assert_eq!(sent, format!("+{}+{}", packet("OK"), packet("E00")));
+        assert_eq!(vm.memory()[0x3000..0x3002], [0x1256, 0x78CD]);
+    }
+
+    #[test]
+    fn watchpoints_cover_the_words_of_their_bytes() {
+        let mut vm = quiet_vm();
+        let sent = serve(&mut vm, &(packet("Z2,6001,2") + "+" + &packet("Z3,1fffe,2") + "+"));
+        assert_eq!(sent, format!("+{}+{}", packet("OK"), packet("OK")));
+        assert_eq!(vm.watchpoints(), [
+            Watchpoint::new(WatchKind::Write, 0x3000..=0x3001),
+            Watchpoint::new(WatchKind::Read, 0xFFFF..=0xFFFF),
+        ]);
+    }
+
+    #[test]
+    fn watchpoints_past_memory_are_refused() {
+        let mut vm = quiet_vm();
+        let sent = serve(&mut vm, &(packet("Z2,10,ffffffff") + "+" + &packet("Z2,1ffff,2") + "+"));
+        assert_eq!(sent, format!("+{}+{}", packet("E00"), packet("E00")));
+        assert!(vm.watchpoints().is_empty());
+    }
+
+    #[test]
+    fn the_pc_and_breakpoints_are_byte_addresses() {
+        let mut vm = quiet_vm();
+        vm.set_pc(0x3000);
+        vm.set_reg(Reg::R1, 0x0102);
+
+        let sent = serve(&mut vm, &(packet("p8") + "+" + &packet("Z0,6002,2") + "+" + &packet("Z0,6003,2") + "+"));
+        assert_eq!(sent, format!("+{}+{}+{}", packet("00006000"), packet("OK"), packet("E00")));
+        assert!(vm.has_breakpoint(0x3001));
+
+        let sent = serve(&mut vm, &(packet("P8=0000a000") + "+" + &packet("P8=0000a001") + "+" + &packet("g") + "+"));
+        let registers: String = REGISTERS[..8].iter().map(|&reg| hex_word(vm.reg(reg))).collect();
+        assert_eq!(registers[4..8], *"0102");
+        let registers = registers + "0000a000" + &hex_word(vm.reg(Reg::PSR));
+        assert_eq!(sent, format!("+{}+{}+{}", packet("OK"), packet("E00"), packet(&registers)));
+        assert_eq!(vm.reg(Reg::PC), 0x5000);
+    }
+
+    #[test]
+    fn bad_checksums_are_refused() {
+        let mut vm = quiet_vm();
+        vm.set_reg(Reg::R1, 0x0102);
+
+        let sent = serve(&mut vm, &("$p1#00".to_string() + &packet("p1") + "+"));
+        assert_eq!(sent, format!("-+{}", packet("0102")));
+    }
+}
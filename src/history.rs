@@ -0,0 +1,73 @@
+//Execution history, for running a program backwards. Before every
+//instruction the VM notes what it needs to undo it: the registers, the
+//stack pointers, the pending interrupts and, for every word of memory
+//the instruction writes, the value that was there before. Only the last
+//`limit` instructions are kept.
+//
+//Devices are not rewound: a key the program read stays read, and what
+//it printed stays printed.
+
+use std::collections::VecDeque;
+
+use crate::interrupt::InterruptController;
+use crate::trace::MemAccess;
+
+
+pub(crate) struct Undo {
+    pub(crate) pc: u16,
+    pub(crate) instructions: u64,
+    pub(crate) cycles: u64,
+    pub(crate) registers: Vec<u16>,
+    pub(crate) saved_usp: u16,
+    pub(crate) saved_ssp: u16,
+    pub(crate) interrupts: InterruptController,
+    pub(crate) running: bool,
+    //Address and old value of every word written, in the order of the writes.
+    pub(crate) writes: Vec<(u16, u16)>,
+    //What the instruction accessed, to check watchpoints against on the
+    //way back.
+    pub(crate) accesses: Vec<MemAccess>,
+}
+
+pub(crate) struct History {
+    limit: usize,
+    entries: VecDeque<Undo>,
+}
+
+impl History {
+    pub(crate) fn new(limit: usize) -> History {
+        History { limit, entries: VecDeque::new() }
+    }
+
+    pub(crate) fn limit(&self) -> usize {
+        self.limit
+    }
+
+    pub(crate) fn set_limit(&mut self, limit: usize) {
+        self.limit = limit;
+        while self.entries.len() > limit {
+            self.entries.pop_front();
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    //Notes one more instruction, forgetting the oldest one if the history
+    //is full.
+    pub(crate) fn push(&mut self, undo: Undo) {
+        if self.entries.len() == self.limit {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(undo);
+    }
+
+    pub(crate) fn pop(&mut self) -> Option<Undo> {
+        self.entries.pop_back()
+    }
+}
@@ -0,0 +1,132 @@
+//Interrupt vectors live in the upper half of the vector table at 0x0100,
+//the lower half being used by exceptions.
+pub const KEYBOARD_VECTOR: u8 = 0x80;
+pub const TIMER_VECTOR: u8 = 0x81;
+
+pub const KEYBOARD_PRIORITY: u8 = 4;
+pub const TIMER_PRIORITY: u8 = 5;
+
+
+//A request to run the service routine for `vector` at PL `priority`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Interrupt {
+    pub vector: u8,
+    pub priority: u8,
+}
+
+
+//Decides which interrupt, if any, gets to run next.
+//Devices request an interrupt for as long as the condition behind it
+//holds (a key is waiting and KBSR has interrupts enabled, say), so their
+//requests are collected again before every decision. Interrupts raised
+//from outside the machine with raise() are one-shot and stay pending
+//until they are serviced.
+#[derive(Clone, Default)]
+pub struct InterruptController {
+    pending: Vec<Interrupt>,
+}
+
+impl InterruptController {
+    pub fn new() -> InterruptController {
+        InterruptController::default()
+    }
+
+    pub fn raise(&mut self, interrupt: Interrupt) {
+        if !self.pending.contains(&interrupt) {
+            self.pending.push(interrupt);
+        }
+    }
+
+    pub fn pending(&self) -> &[Interrupt] {
+        &self.pending
+    }
+
+    pub fn clear(&mut self) {
+        self.pending.clear();
+    }
+
+    //Picks the highest priority request that is above the priority of the
+    //running program. Only requests with a higher priority preempt it.
+    //Requests `handled` says no to are passed over, raised ones stay
+    //pending.
+    pub fn select<I, F>(&mut self, current_priority: u8, device_requests: I, handled: F) -> Option<Interrupt>
+    where I: Iterator<Item = Interrupt>, F: Fn(&Interrupt) -> bool
+    {
+        let mut best: Option<(Interrupt, Option<usize>)> = None;
+
+        let raised = self.pending.iter().copied().enumerate().map(|(i, irq)| (irq, Some(i)));
+        let requested = device_requests.map(|irq| (irq, None));
+
+        for (irq, index) in raised.chain(requested) {
+            if irq.priority <= current_priority || !handled(&irq) {
+                continue;
+            }
+            if best.map_or(true, |(b, _)| irq.priority > b.priority) {
+                best = Some((irq, index));
+            }
+        }
+
+        let (irq, index) = best?;
+        if let Some(index) = index {
+            self.pending.remove(index);
+        }
+        Some(irq)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LOW: Interrupt = Interrupt { vector: 0x90, priority: 2 };
+    const HIGH: Interrupt = Interrupt { vector: 0x91, priority: 6 };
+
+    fn raised(interrupts: &[Interrupt]) -> InterruptController {
+        let mut controller = InterruptController::new();
+        for &irq in interrupts {
+            controller.raise(irq);
+        }
+        controller
+    }
+
+    #[test]
+    fn the_highest_priority_goes_first() {
+        let mut controller = raised(&[LOW, HIGH, LOW]);
+        assert_eq!(controller.pending(), &[LOW, HIGH]);
+
+        assert_eq!(controller.select(0, std::iter::empty(), |_| true), Some(HIGH));
+        assert_eq!(controller.select(0, std::iter::empty(), |_| true), Some(LOW));
+        assert_eq!(controller.select(0, std::iter::empty(), |_| true), None);
+    }
+
+    #[test]
+    fn only_a_higher_priority_preempts() {
+        let mut controller = raised(&[LOW, HIGH]);
+        assert_eq!(controller.select(6, std::iter::empty(), |_| true), None);
+        assert_eq!(controller.select(2, std::iter::empty(), |_| true), Some(HIGH));
+        assert_eq!(controller.select(2, std::iter::empty(), |_| true), None);
+        assert_eq!(controller.pending(), &[LOW]);
+    }
+
+    #[test]
+    fn device_requests_compete_with_raised_interrupts() {
+        let timer = Interrupt { vector: TIMER_VECTOR, priority: TIMER_PRIORITY };
+        let keyboard = Interrupt { vector: KEYBOARD_VECTOR, priority: KEYBOARD_PRIORITY };
+
+        let mut controller = raised(&[LOW]);
+        assert_eq!(controller.select(0, vec![keyboard, timer].into_iter(), |_| true), Some(timer));
+        assert_eq!(controller.pending(), &[LOW]);
+
+        let mut controller = raised(&[HIGH]);
+        assert_eq!(controller.select(0, vec![timer].into_iter(), |_| true), Some(HIGH));
+        assert!(controller.pending().is_empty());
+    }
+
+    #[test]
+    fn unhandled_interrupts_are_passed_over() {
+        let mut controller = raised(&[LOW, HIGH]);
+        assert_eq!(controller.select(0, std::iter::empty(), |irq| irq.vector != HIGH.vector), Some(LOW));
+        assert_eq!(controller.pending(), &[HIGH]);
+    }
+}
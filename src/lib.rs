@@ -2,6 +2,36 @@ use std::fs::File;
 use std::path::Path;
 use std::io::Read;
 
+pub mod opcode_fn;
+pub mod decode;
+pub mod trapcode_fn;
+pub mod console;
+pub mod device;
+pub mod interrupt;
+pub mod os;
+pub mod asm;
+pub mod disasm;
+pub mod symbols;
+pub mod watch;
+pub mod gdbstub;
+pub mod trace;
+pub mod snapshot;
+pub mod replay;
+pub mod cycles;
+pub mod profile;
+pub mod coverage;
+
+mod memory;
+pub use memory::Memory;
+
+mod history;
+
+mod error;
+pub use error::{VmError, StopReason};
+
+mod vm;
+pub use vm::Vm;
+
 
 pub mod register {
     use std::ops::{Index, IndexMut};
@@ -10,11 +40,13 @@ pub mod register {
     //We will be storing the register in an array of type
     //u16. Each register storing 16 bits only.
     //R0 to R8 are general purpose registers.
-    //PC is program counter register's index. R_COND is
-    //the index for register which stores information of
-    //previous calculations.
+    //PC is program counter register's index. PSR is the
+    //Processor Status Register: the privilege mode, the
+    //priority level and the condition flags which store
+    //information of previous calculations.
     //COUNT has the index 10, which indicates the count of
     //total registers in our architecture.
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
     pub enum Reg {
         R0,
         R1,
@@ -25,7 +57,7 @@ pub mod register {
         R6,
         R7,
         PC,
-        COND,
+        PSR,
         COUNT,
     }
 
@@ -48,6 +80,23 @@ pub mod register {
         }
     }
 
+    //Same as above, but for slices so that functions can borrow the
+    //registers as `&mut [u16]` instead of requiring a whole Vec.
+    impl<T> Index<Reg> for [T]
+    {
+        type Output = T;
+        fn index(&self, reg: Reg) -> &T {
+            &self[reg as usize]
+        }
+    }
+
+    impl<T> IndexMut<Reg> for [T]
+    {
+        fn index_mut(&mut self, reg: Reg) -> &mut T {
+            &mut self[reg as usize]
+        }
+    }
+
 }
 
 pub mod opcodes {
@@ -104,9 +153,22 @@ pub enum TrapCode {
 }
 
 
+//How TRAP instructions are carried out.
+//Native runs the routines in trapcode_fn.rs directly in Rust and ignores
+//the trap vector table. Vectored does what the LC3 does: push the PSR and
+//PC onto the supervisor stack and, in supervisor mode, jump to the address
+//stored in the trap vector table, which has to be filled by an operating
+//system (see os.rs) or by the program itself. The routine returns with RTI.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TrapMode {
+    Native,
+    Vectored,
+}
+
+
 //Condition Flag in register stores information about last calculation
 //execution. LC3 only had 3 condition flags in this register which stores
-//the sign of the previous calculation.
+//the sign of the previous calculation. They live in bits [2:0] of the PSR.
 #[allow(non_camel_case_types)]
 pub enum CondFlags {
     FL_POS = 1<<0, //Positive
@@ -115,98 +177,215 @@ pub enum CondFlags {
 }
 
 
+//Processor Status Register layout.
+//Bit 15 is the privilege mode (set while running user code), bits [10:8]
+//are the priority level of the running program and bits [2:0] are the
+//N/Z/P condition flags.
+pub const PSR_USER: u16 = 1 << 15;
+pub const PSR_PRIORITY: u16 = 0x0700;
+pub const PSR_PRIORITY_SHIFT: u16 = 8;
+pub const PSR_COND: u16 = 0x0007;
+
+
+//Exceptions vector through the table at 0x0100 (the lower half of the
+//interrupt vector table) just like interrupts do.
+#[allow(non_camel_case_types)]
+pub enum Exception {
+    EX_PRIVILEGE = 0x00,        //RTI executed in user mode
+    EX_ILLEGAL_OPCODE = 0x01,   //RES, the opcode that does not exist
+    EX_ACCESS_VIOLATION = 0x02, //user mode access outside of user space
+}
+
+pub const INTERRUPT_VECTOR_TABLE: u16 = 0x0100;
+
+//User mode code may only access 0x3000 to 0xFDFF. Below is the operating
+//system, above are the device registers.
+pub const USER_SPACE_START: u16 = 0x3000;
+pub const USER_SPACE_END: u16 = 0xFDFF;
+
+
 //Memory Mapped Registers
 //These special register has address reserved for them in memory. So
 //to read and write to this register, we read/write into the memory.
 //KBSR identifies whether a key was pressed. KBDR tells us what key was
-//pressed
+//pressed. DSR tells whether the display can take another character and
+//writing DDR prints one. The registers are served by the devices in
+//device.rs. TSR and TIR belong to the interval timer. Clearing bit 15 of
+//MCR stops the machine's clock.
 #[allow(non_camel_case_types)]
 pub enum MemMapReg {
     MR_KBSR = 0xFE00, //Keyboard Status Register. 0xFE00 = 65024.
     MR_KBDR = 0xFE02, //Keyboard Data Register. 0xFE02 = 65026.
+    MR_DSR = 0xFE04,  //Display Status Register. 0xFE04 = 65028.
+    MR_DDR = 0xFE06,  //Display Data Register. 0xFE06 = 65030.
+    MR_TSR = 0xFE08,  //Timer Status Register. 0xFE08 = 65032.
+    MR_TIR = 0xFE0A,  //Timer Interval Register. 0xFE0A = 65034.
+    MR_MCR = 0xFFFE,  //Machine Control Register. 0xFFFE = 65534.
 }
 
 use register::Reg;
+use watch::WatchKind;
+use decode::{decode, Instruction};
+use device::IO_PAGE_START;
 
 pub fn sign_extend(mut x: u16, bit_count: u16) -> u16 {
     //this checks if the last bit has a 1 (indicating negative number)
     if (x >> (bit_count-1)) & 1 == 1 {
         //we extend the left side with 1's as it is a -ve number
-        x |= (0xFFFF << bit_count);
+        x |= 0xFFFF << bit_count;
     }
     x
 }
 
 
-pub fn update_flags(r: usize, reg: &mut Vec<u16>) {
+pub fn update_flags(r: usize, reg: &mut [u16]) {
     let val: u16 = reg[r];
 
-    if val == 0 {
-        reg[Reg::COND] = CondFlags::FL_ZRO as u16;
+    let flag = if val == 0 {
+        CondFlags::FL_ZRO
     }
 
     else if val >> 15 == 1 { //1 in leftmost bit means negative
-        reg[Reg::COND] = CondFlags::FL_NEG as u16;
+        CondFlags::FL_NEG
     }
 
     else {
-        reg[Reg::COND] = CondFlags::FL_POS as u16;
-    }
+        CondFlags::FL_POS
+    };
 
+    //Only the flags change, privilege and priority are left alone.
+    reg[Reg::PSR] = (reg[Reg::PSR] & !PSR_COND) | flag as u16;
 }
 
 
-pub fn read_image(image: &str, memory: &mut Vec<u16>) -> bool {
-    //println!("read {}", image);
-
+pub fn read_image(image: &str, memory: &mut [u16]) -> Result<(), VmError> {
     let path = Path::new(image);
-    let mut file = File::open(path).expect("No such file exists.");
+    let mut file = File::open(path)?;
 
     //data is a Vec<u8> where the data is read and stored in the
     //form of bytes using `read_to_end()` method.
     let mut data = Vec::new();
-    file.read_to_end(&mut data).expect("buffer offerflow");
+    file.read_to_end(&mut data)?;
+
+    load_image_bytes(&data, memory)
+}
+
+
+//Same as read_image() but for an image that is already in memory, e.g.
+//one embedded with include_bytes!().
+pub fn load_image_bytes(data: &[u8], memory: &mut [u16]) -> Result<(), VmError> {
+    let (origin, words) = parse_image(data)?;
+
+    let pc = origin as usize;
+    if pc + words.len() > memory.len() {
+        return Err(VmError::ImageTooLarge { origin, words: words.len() });
+    }
+
+    //We now store the rest of the program data into memory
+    memory[pc..pc + words.len()].copy_from_slice(&words);
+
+    Ok(())
+}
+
+
+//Splits an image into its origin and the words to be loaded there.
+pub fn parse_image(data: &[u8]) -> Result<(u16, Vec<u16>), VmError> {
+    //Every word is two bytes and the first word is the origin, so anything
+    //shorter than that or with a dangling byte is not a complete image.
+    if data.len() < 2 || data.len() % 2 != 0 {
+        return Err(VmError::TruncatedImage);
+    }
 
     //chunks(2) method combines the elements as [[val0, val1], ...]
     //chunks returns an iterator over data vector.
-    let mut iter = data.chunks(2);
+    //We are combining two bytes into a u16 word as that is how our memory
+    //stores data. That is, word size of our memory is 16 bits.
+    let mut iter = data.chunks(2).map(|elem| (elem[0] as u16) << 8 | elem[1] as u16);
 
     //The first element specifies the address in memory where program
     //should start. It is the general value 0x3000 or 12288 in rogue.obj
-    let pc = iter.next().unwrap();
+    let origin = iter.next().unwrap();
 
-    //We are combining two bytes into a u16 word as that is how our memory
-    //stores data. That is, word size of our memory is 16 bits.
-    let mut pc: usize = ((pc[0] as u16) << 8 | pc[1] as u16) as usize;
+    Ok((origin, iter.collect()))
+}
 
-    //We now store the rest of the program data into memory
-    for elem in iter {
-        memory[pc] = (elem[0] as u16) << 8 | elem[1] as u16;
-        pc = pc+1;
+
+//Blocks a user mode access outside of user space, noting the address
+//for the VM to raise an access control violation with.
+fn access_violation(addr: u16, memory: &mut Memory) -> bool {
+    if memory.protect && !(USER_SPACE_START..=USER_SPACE_END).contains(&addr) {
+        memory.violation.get_or_insert(addr);
+        return true;
     }
+    false
+}
 
-    true
+pub fn mem_read(addr: u16, memory: &mut Memory) -> u16 {
+    if access_violation(addr, memory) {
+        return 0;
+    }
+
+    //Addresses claimed by a device are served by it, not by memory.
+    let val = match memory.bus.read(addr, memory.console.as_mut()) {
+        Some(Ok(val)) => val,
+        Some(Err(err)) => {
+            memory.fault = Some(err);
+            0
+        },
+        None => memory.words[addr as usize],
+    };
+
+    if !memory.watchpoints.is_empty() {
+        memory.watch(addr, WatchKind::Read, val, val);
+    }
+    memory.log_access(false, addr, val);
+    val
 }
 
+pub fn mem_write(addr: u16, val: u16, memory: &mut Memory) {
+    if access_violation(addr, memory) {
+        return;
+    }
 
-pub fn mem_read(addr: u16, memory: &mut Vec<u16>) -> u16 {
-    //let instr: u16 = 0b1111_0000_00100100;
-    if addr == MemMapReg::MR_KBSR as u16 {
-        let mut buffer = [0; 1];
-        std::io::stdin().read_exact(&mut buffer).unwrap();
+    //Device registers are not backed by memory, their old value reads as
+    //whatever is underneath them (normally 0).
+    if !memory.watchpoints.is_empty() {
+        let old = memory.words[addr as usize];
+        memory.watch(addr, WatchKind::Write, old, val);
+    }
+    memory.log_access(true, addr, val);
+
+    match memory.bus.write(addr, val, memory.console.as_mut()) {
+        Some(Ok(())) => {},
+        Some(Err(err)) => memory.fault = Some(err),
+        None => {
+            memory.words[addr as usize] = val;
+            memory.decoded[addr as usize] = None;
+        },
+    }
+}
 
-        if buffer[0] != 0 {
-            memory[MemMapReg::MR_KBSR as usize] = 1 << 15;
-            memory[MemMapReg::MR_KBDR as usize] = buffer[0] as u16;
-        }
-        else {
-            memory[MemMapReg::MR_KBSR as usize] = 0;
+//Reads the instruction at `addr` for the VM to execute, and decodes it.
+//Words in memory are only decoded the first time they are fetched, device
+//registers every time. Fetching is subject to access control like any
+//other read, but is not logged or watched.
+pub(crate) fn fetch(addr: u16, memory: &mut Memory) -> (u16, Instruction) {
+    if !access_violation(addr, memory) {
+        if let Some(instruction) = memory.decoded[addr as usize] {
+            return (memory.words[addr as usize], instruction);
         }
     }
 
-    memory[addr as usize]
-}
+    let first_hit = memory.watch_hits.len();
+    let instr = mem_read(addr, memory);
+    memory.watch_hits.truncate(first_hit);
+    if let Some(accesses) = &mut memory.accesses {
+        accesses.clear();
+    }
 
-pub fn mem_write(addr: u16, val: u16, memory: &mut Vec<u16>) {
-    memory[addr as usize] = val;
+    let instruction = decode(instr);
+    if addr < IO_PAGE_START && memory.violation.is_none() {
+        memory.decoded[addr as usize] = Some(instruction);
+    }
+    (instr, instruction)
 }
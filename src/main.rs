@@ -1,210 +1,766 @@
 extern crate termios;
 
+mod debugger;
+mod terminal;
+
 use std::env;
+use std::fs::{self, File};
+use std::ops::RangeInclusive;
+use std::path::Path;
 use std::process;
-use termios::*;
-
-use rust_vm::{TrapCode, read_image, mem_read};
-use rust_vm::register::Reg;
-use rust_vm::opcodes::OpCodes;
-
-mod opcode_fn;
-use opcode_fn::*;
-
-mod trapcode_fn;
-use trapcode_fn::*;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use rust_vm::{Vm, VmError, StopReason, TrapMode};
+use rust_vm::{asm, disasm, gdbstub};
+use rust_vm::console::{Console, StdioConsole};
+use rust_vm::cycles::{CycleModel, Throttle};
+use rust_vm::replay::{RecordingConsole, ReplayConsole};
+use rust_vm::gdbstub::GdbStub;
+use rust_vm::symbols::SymbolTable;
+use rust_vm::trace::{OpClass, TraceFilter, TraceFormat, TraceWriter};
+use rust_vm::watch::Watchpoint;
+
+use debugger::Debugger;
+use terminal::RawMode;
+
+
+//How many instructions the debugger and gdb can step back by default.
+const DEFAULT_HISTORY: usize = 100_000;
+
+//Ctrl-T, pressed while a program runs, writes a snapshot of it.
+const SNAPSHOT_KEY: u8 = 0x14;
+
+//How many of the hottest addresses --profile lists.
+const PROFILE_HOT_SPOTS: usize = 20;
+
+//How many instructions run between checks for the snapshot key.
+const RUN_CHUNK: u64 = 10_000;
+
+fn usage() -> ! {
+    println!("Usage: rust-vm [options] <image-file1> [image-file2]..");
+    println!("       rust-vm [options] --load-snapshot <file> [image-file]..");
+    println!("       rust-vm asm <source.asm> [-o <image.obj>]");
+    println!("       rust-vm disasm <image.obj>");
+    println!();
+    println!("Options:");
+    println!("  --os     run TRAPs through the trap vector table, using the built-in LC3 OS");
+    println!("  --debug  start the program under the interactive debugger");
+    println!("  --gdb <port|host:port|unix:path>");
+    println!("           wait for gdb to connect and let it control the program");
+    println!("  --watch [r|w|a:]<start>[..<end>]");
+    println!("           log reads, writes or any access to the given addresses to stderr");
+    println!("           (writes if no kind is given); may be repeated");
+    println!("  --load-snapshot <file>");
+    println!("           carry on from a snapshot instead of starting the images afresh");
+    println!("           (images given as well only provide their symbols)");
+    println!("  --snapshot-file <file>");
+    println!("           where Ctrl-T writes a snapshot of the running program (default: the");
+    println!("           snapshot loaded, or the first image with a .snap extension)");
+    println!("  --record <file>");
+    println!("           write every byte of input the program gets to <file>, with the");
+    println!("           instruction count it got it at");
+    println!("  --replay <file>");
+    println!("           feed the program the input recorded in <file>, at the same points,");
+    println!("           then carry on with input from the terminal");
+    println!("  --profile");
+    println!("           print where the program spent its time once it is done");
+    println!("  --profile-folded <file>");
+    println!("           write the cycles per chain of subroutine calls to <file>, in the");
+    println!("           folded stacks format flamegraph tools take");
+    println!("  --coverage <source.asm>");
+    println!("           note which instructions of the program assembled from <source.asm>");
+    println!("           run and which ways its branches go; may be repeated");
+    println!("  --coverage-listing <file>");
+    println!("           write the source annotated with the counts to <file>; without this");
+    println!("           or --coverage-lcov it is printed once the program is done");
+    println!("  --coverage-lcov <file>");
+    println!("           write the coverage to <file> in lcov's tracefile format");
+    println!("  --clock-hz <N>");
+    println!("           run no faster than a machine doing N cycles a second");
+    println!("  --cycle-cost <OPCODE>=<N>");
+    println!("           cycles an opcode (BR, ADD, .. TRAP) takes, not counting memory");
+    println!("           accesses (default 1); may be repeated");
+    println!("  --memory-latency <N>");
+    println!("           cycles LD, LDR, ST and STR take on top of that, LDI and STI twice");
+    println!("           as many (default 1)");
+    println!("  --history <N>");
+    println!("           with --debug or --gdb, how many instructions can be undone by");
+    println!("           running backwards (default {}, 0 turns it off)", DEFAULT_HISTORY);
+    println!("  --trace <file>");
+    println!("           write every executed instruction to <file>");
+    println!("  --trace-format jsonl|binary");
+    println!("           JSON Lines (the default) or the compact binary format");
+    println!("  --trace-pc <start>..<end>");
+    println!("           only trace instructions at these addresses; may be repeated");
+    println!("  --trace-op <class>[,<class>..]");
+    println!("           only trace these kinds of instructions: alu, load, store, control,");
+    println!("           trap, illegal");
+    process::exit(2);
+}
 
 
 fn main() {
     //Collect CLI arguments
     let args: Vec<String> = env::args().collect();
 
-    if args.len() < 2  {
-        println!("Error: provide atleast one VM image");
-        println!("Usage: rust-vm <image-file1> [image-file2]..");
-        process::exit(2);
+    if args.get(1).map(String::as_str) == Some("asm") {
+        assemble(&args[2..]);
+        return;
+    }
+    if args.get(1).map(String::as_str) == Some("disasm") {
+        disassemble(&args[2..]);
+        return;
     }
 
-    //memory of the computer.
-    //LC3 has 65536 memory locations, each storing 16 bits.
-    //So in total, it has a memory of 128KBs
-    let mut memory = vec![0u16; 65536]; //0u16 stands for 0 of type u16
-
-    for i in 1..args.len() {
-        if !read_image(&args[i], &mut memory)  {
-            println!("Failed to load image: {}", args[i]);
-            process::exit(1);
+    let mut images = Vec::new();
+    let mut use_os = false;
+    let mut debug = false;
+    let mut gdb = None;
+    let mut history = DEFAULT_HISTORY;
+    let mut load_snapshot = None;
+    let mut snapshot_file = None;
+    let mut record = None;
+    let mut clock_hz = None;
+    let mut profile = false;
+    let mut profile_folded = None;
+    let mut coverage_sources = Vec::new();
+    let mut coverage_listing = None;
+    let mut coverage_lcov = None;
+    let mut cycle_model = CycleModel::new();
+    let mut replay = None;
+
+    let mut watches = Vec::new();
+
+    let mut trace = None;
+    let mut trace_format = TraceFormat::JsonLines;
+    let mut trace_ranges = Vec::new();
+    let mut trace_filter = TraceFilter::default();
+
+    let mut iter = args[1..].iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--os" => use_os = true,
+            "--debug" => debug = true,
+            "--gdb" => match iter.next() {
+                Some(addr) => gdb = Some(addr.as_str()),
+                None => {
+                    println!("Error: --gdb needs a port or unix:<path>");
+                    usage();
+                },
+            },
+            "--watch" => match iter.next() {
+                Some(spec) => watches.push(spec.as_str()),
+                None => {
+                    println!("Error: --watch needs a watchpoint");
+                    usage();
+                },
+            },
+            "--load-snapshot" => match iter.next() {
+                Some(path) => load_snapshot = Some(path.as_str()),
+                None => {
+                    println!("Error: --load-snapshot needs a file name");
+                    usage();
+                },
+            },
+            "--snapshot-file" => match iter.next() {
+                Some(path) => snapshot_file = Some(path.as_str()),
+                None => {
+                    println!("Error: --snapshot-file needs a file name");
+                    usage();
+                },
+            },
+            "--record" => match iter.next() {
+                Some(path) => record = Some(path.as_str()),
+                None => {
+                    println!("Error: --record needs a file name");
+                    usage();
+                },
+            },
+            "--replay" => match iter.next() {
+                Some(path) => replay = Some(path.as_str()),
+                None => {
+                    println!("Error: --replay needs a file name");
+                    usage();
+                },
+            },
+            "--profile" => profile = true,
+            "--profile-folded" => match iter.next() {
+                Some(path) => profile_folded = Some(path.as_str()),
+                None => {
+                    println!("Error: --profile-folded needs a file name");
+                    usage();
+                },
+            },
+            "--coverage" => match iter.next() {
+                Some(path) => coverage_sources.push(path.as_str()),
+                None => {
+                    println!("Error: --coverage needs a source file");
+                    usage();
+                },
+            },
+            "--coverage-listing" => match iter.next() {
+                Some(path) => coverage_listing = Some(path.as_str()),
+                None => {
+                    println!("Error: --coverage-listing needs a file name");
+                    usage();
+                },
+            },
+            "--coverage-lcov" => match iter.next() {
+                Some(path) => coverage_lcov = Some(path.as_str()),
+                None => {
+                    println!("Error: --coverage-lcov needs a file name");
+                    usage();
+                },
+            },
+            "--clock-hz" => match iter.next().and_then(|n| n.parse().ok()) {
+                Some(hz) if hz > 0 => clock_hz = Some(hz),
+                _ => {
+                    println!("Error: --clock-hz needs a number of cycles per second");
+                    usage();
+                },
+            },
+            "--cycle-cost" => {
+                let cost = iter.next()
+                    .and_then(|cost| cost.split_once('='))
+                    .ok_or_else(|| "--cycle-cost needs <OPCODE>=<N>".to_string())
+                    .and_then(|(op, n)| {
+                        let n = n.parse().map_err(|_| format!("`{}` is not a number of cycles", n))?;
+                        cycle_model.set_cost(op, n)
+                    });
+                if let Err(err) = cost {
+                    println!("Error: {}", err);
+                    usage();
+                }
+            },
+            "--memory-latency" => match iter.next().and_then(|n| n.parse().ok()) {
+                Some(cycles) => cycle_model.set_memory_latency(cycles),
+                None => {
+                    println!("Error: --memory-latency needs a number of cycles");
+                    usage();
+                },
+            },
+            "--history" => match iter.next().and_then(|n| n.parse().ok()) {
+                Some(n) => history = n,
+                None => {
+                    println!("Error: --history needs a number of instructions");
+                    usage();
+                },
+            },
+            "--trace" => match iter.next() {
+                Some(path) => trace = Some(path.as_str()),
+                None => {
+                    println!("Error: --trace needs a file name");
+                    usage();
+                },
+            },
+            "--trace-format" => match iter.next().map(String::as_str) {
+                Some("jsonl") => trace_format = TraceFormat::JsonLines,
+                Some("binary") => trace_format = TraceFormat::Binary,
+                _ => {
+                    println!("Error: --trace-format needs jsonl or binary");
+                    usage();
+                },
+            },
+            "--trace-pc" => match iter.next() {
+                Some(range) => trace_ranges.push(range.as_str()),
+                None => {
+                    println!("Error: --trace-pc needs an address range");
+                    usage();
+                },
+            },
+            "--trace-op" => match iter.next() {
+                Some(classes) => for class in classes.split(',') {
+                    match OpClass::parse(class) {
+                        Some(class) => trace_filter.classes.push(class),
+                        None => {
+                            println!("Error: unknown instruction class {}", class);
+                            usage();
+                        },
+                    }
+                },
+                None => {
+                    println!("Error: --trace-op needs an instruction class");
+                    usage();
+                },
+            },
+            "-h" | "--help" => usage(),
+            flag if flag.starts_with("--") => {
+                println!("Error: unknown option {}", flag);
+                usage();
+            },
+            image => images.push(image),
         }
     }
 
+    if images.is_empty() && load_snapshot.is_none() {
+        println!("Error: provide atleast one VM image");
+        usage();
+    }
+    if coverage_sources.is_empty() && (coverage_listing.is_some() || coverage_lcov.is_some()) {
+        println!("Error: name the source to measure the coverage of with --coverage");
+        usage();
+    }
 
-    //Platform Specifics (Unix here)
-    //Setting terminal input/output behaviour such as accepting
-    //character without the need for a newline character
-    //Refer: https://stackoverflow.com/questions/26321592/how-can-i-read-one-character-from-stdin-without-having-to-hit-enter
-    let stdin = 0; 
-
-    let termios = Termios::from_fd(stdin).unwrap();
+    let snapshot_file = snapshot_file.or(load_snapshot).map(Path::new).map(Path::to_path_buf)
+        .unwrap_or_else(|| Path::new(images[0]).with_extension("snap"));
 
-    let mut new_termios = termios.clone(); // make a mutable copy of termios
-    // that we will modify
-    new_termios.c_iflag &= IGNBRK | BRKINT | PARMRK | ISTRIP | INLCR | IGNCR | ICRNL | IXON;
-    new_termios.c_lflag &= !(ICANON | ECHO); // no echo and canonical mode
-    tcsetattr(stdin, TCSANOW, &mut new_termios).unwrap();
+    //Only a plain run has the snapshot key, the debugger and gdb can stop
+    //the program themselves.
+    let snapshot_requested = Arc::new(AtomicBool::new(false));
+    let mut stdio = StdioConsole::new();
+    if !debug && gdb.is_none() {
+        stdio = stdio.with_hotkey(SNAPSHOT_KEY, snapshot_requested.clone());
+    }
+    if debug {
+        stdio = stdio.with_interrupt(terminal::interrupt_flag());
+    }
 
-    //Platform specific end
+    let console: Box<dyn Console> = match (record, replay) {
+        (Some(_), Some(_)) => {
+            println!("Error: --record and --replay cannot be used together");
+            usage();
+        },
+        (Some(path), None) => {
+            let recorder = File::create(path).and_then(|log| RecordingConsole::new(Box::new(stdio), log));
+            match recorder {
+                Ok(recorder) => Box::new(recorder),
+                Err(err) => {
+                    println!("Failed to create input log {}: {}", path, err);
+                    process::exit(1);
+                },
+            }
+        },
+        (None, Some(path)) => match ReplayConsole::load(Box::new(stdio), Path::new(path)) {
+            Ok(replayer) => Box::new(replayer),
+            Err(err) => {
+                println!("Failed to load input log {}: {}", path, err);
+                process::exit(1);
+            },
+        },
+        (None, None) => Box::new(stdio),
+    };
+    let mut vm = Vm::with_console(console);
+    vm.set_cycle_model(cycle_model);
+
+    //The OS goes in first so that images can still install their own
+    //trap routines over it.
+    if use_os {
+        vm.set_trap_mode(TrapMode::Vectored);
+        vm.load_os();
+    }
 
-    let args: Vec<String> = env::args().collect();
+    for image in images {
+        if let Err(err) = vm.load_image(image)  {
+            println!("Failed to load image {}: {}", image, err);
+            process::exit(1);
+        }
+    }
 
+    if let Some(path) = load_snapshot {
+        if let Err(err) = vm.load_snapshot(Path::new(path)) {
+            println!("Failed to load snapshot {}: {}", path, err);
+            process::exit(1);
+        }
+    }
 
-    #[allow(non_snake_case)]
-    let PC_START: u16 = 0x3000; //default starting address for PC
 
-    //LC3 register
-    let mut registers: Vec<u16> = vec![0; Reg::COUNT as usize];
+    //Labels only are known once the images (and their .sym files) are in.
+    for spec in watches {
+        match Watchpoint::parse(spec, vm.symbols()) {
+            Ok(watchpoint) => vm.add_watchpoint(watchpoint),
+            Err(err) => {
+                println!("Error: bad watchpoint {}: {}", spec, err);
+                process::exit(2);
+            },
+        }
+    }
 
-    registers[Reg::PC] = PC_START; //Default starting address
+    if let Some(path) = trace {
+        for range in trace_ranges {
+            match parse_range(range, vm.symbols()) {
+                Ok(range) => trace_filter.pc_ranges.push(range),
+                Err(err) => {
+                    println!("Error: bad trace range {}: {}", range, err);
+                    process::exit(2);
+                },
+            }
+        }
 
-    let mut running: bool = true;
+        let tracer = File::create(path).and_then(|file| {
+            TraceWriter::new(file, trace_format, trace_filter, vm.symbols().clone())
+        });
+        match tracer {
+            Ok(tracer) => vm.set_tracer(Box::new(tracer)),
+            Err(err) => {
+                println!("Failed to create trace {}: {}", path, err);
+                process::exit(1);
+            },
+        }
+    }
 
-    while running {
-        let instr: u16 = mem_read(registers[Reg::PC], &mut memory);
+    //The sources are assembled again for their line tables, and had
+    //better be what was loaded.
+    let coverage_sources: Vec<CoverageSource> = coverage_sources.into_iter()
+        .map(|path| CoverageSource::load(path, &vm))
+        .collect();
+    if !coverage_sources.is_empty() {
+        vm.start_coverage();
+    }
 
-        registers[Reg::PC] = registers[Reg::PC] + 1; //increment PC
+    if debug || gdb.is_some() {
+        vm.set_history_limit(history);
+    }
+    if profile || profile_folded.is_some() {
+        vm.start_profile();
+    }
 
-        let op: u16 = instr >> 12; //opcode is in left 4 bits.
-        //println!("Executing Instr {:#018b} and Opcode bit: {}", instr, op);
+    if debug {
+        let result = Debugger::new(&mut vm).run();
+        finish_trace(&mut vm);
+        finish_profile(&mut vm, profile, profile_folded);
+        finish_coverage(&mut vm, &coverage_sources, coverage_listing, coverage_lcov);
+        if let Err(err) = result {
+            println!("Debugger failed: {}", err);
+            process::exit(1);
+        }
+        return;
+    }
 
-        match op {
-            op if op == OpCodes::OP_BR as u16 => {
-                //println!("Executing BRANCH, Instr {:#018b}", instr);
-                op_branch(&mut registers, instr);
+    if let Some(addr) = gdb {
+        eprintln!("Waiting for gdb to connect on {}...", addr);
+        let conn = match gdbstub::accept(addr) {
+            Ok(conn) => conn,
+            Err(err) => {
+                println!("Failed to listen on {}: {}", addr, err);
+                process::exit(1);
             },
+        };
 
-            op if op == OpCodes::OP_ADD as u16 => {
-                //println!("Executing ADD, Instr {:#018b}", instr);
-                op_add(&mut registers, instr);
-            },
+        let raw_mode = RawMode::enable().ok();
+        let result = GdbStub::new(&mut vm, conn).serve();
+        drop(raw_mode);
+        finish_trace(&mut vm);
+        finish_profile(&mut vm, profile, profile_folded);
+        finish_coverage(&mut vm, &coverage_sources, coverage_listing, coverage_lcov);
 
-            op if op == OpCodes::OP_LD as u16 => {
-                //println!("Executing LD , Instr {:#018b}", instr);
-                op_load(&mut registers, instr, &mut memory);
-            },
+        if let Err(err) = result {
+            println!("gdb connection failed: {}", err);
+            process::exit(1);
+        }
+        return;
+    }
 
-            op if op == OpCodes::OP_ST as u16 => {
-                //println!("Executing ST , Instr {:#018b}", instr);
-                op_st(&mut registers, instr, &mut memory);
-            },
+    //Keys go to the program as they are typed. Without a terminal (input
+    //piped in, say) there is nothing to switch over.
+    let raw_mode = RawMode::enable().ok();
 
-            op if op == OpCodes::OP_JSR as u16 => {
-                //println!("Executing JSR, Instr {:#018b}", instr);
-                op_jsr(&mut registers, instr);
-            },
+    //Watched accesses are logged to stderr, out of the program's way, and
+    //the program carries on. So it does after a snapshot.
+    let mut throttle = clock_hz.map(|hz| Throttle::new(hz, vm.cycles()));
 
-            op if op == OpCodes::OP_AND as u16 => {
-                //println!("Executing AND, Instr {:#018b}", instr);
-                op_and(&mut registers, instr);
+    let save_if_requested = |vm: &Vm| {
+        if snapshot_requested.swap(false, Ordering::SeqCst) {
+            match vm.save_snapshot(&snapshot_file) {
+                Ok(()) => eprintln!("snapshot: written to {}", snapshot_file.display()),
+                Err(err) => eprintln!("snapshot: failed to write {}: {}", snapshot_file.display(), err),
+            }
+        }
+    };
+
+    let result = loop {
+        //The throttle's chunk is cycles, not instructions.
+        let result = match &throttle {
+            Some(throttle) => vm.run_for_cycles(throttle.chunk().min(RUN_CHUNK)),
+            None => vm.run_for(RUN_CHUNK),
+        };
+        match result {
+            Ok(StopReason::StepLimit) => {
+                if let Some(throttle) = &mut throttle {
+                    throttle.pace(vm.cycles());
+                }
+                save_if_requested(&vm);
             },
+            //The snapshot key, pressed while the program waits for a key
+            //in GETC or IN. The snapshot is taken before the read, which
+            //waits again once the program carries on.
+            Ok(StopReason::Interrupted) => save_if_requested(&vm),
+            Ok(StopReason::Watchpoint) => {
+                for hit in vm.take_watch_hits() {
+                    eprintln!("watch: {}", hit.describe(vm.symbols()));
+                }
+            },
+            result => break result,
+        }
+    };
+
+    drop(raw_mode);
+    finish_trace(&mut vm);
+    finish_profile(&mut vm, profile, profile_folded);
+    finish_coverage(&mut vm, &coverage_sources, coverage_listing, coverage_lcov);
+
+    match result {
+        Ok(StopReason::Halted) => println!("HALT Trapcode received, Halting."),
+        Ok(_) => {},
+        Err(err) => {
+            println!("{}, aborting current image.", err.describe(vm.symbols()));
+            process::exit(exit_code(&err));
+        },
+    }
 
-            op if op == OpCodes::OP_LDR as u16 => {
-                //println!("Executing LDR, Instr {:#018b}", instr);
-                op_ldr(&mut registers, instr, &mut memory);
-            }, 
+    println!("Shutting Down VM...");
+}
 
-            op if op == OpCodes::OP_STR as u16 => {
-                //println!("Executing STR, Instr {:#018b}", instr);
-                op_str(&mut registers, instr, &mut memory);
-            },
 
-            op if op == OpCodes::OP_RTI as u16 => {
-                println!("Bad OpCode 'RTI' received. Aborting.");
-                process::exit(10);
-            },
+//Flushes what the tracer still has buffered, if there is one.
+fn finish_trace(vm: &mut Vm) {
+    if let Some(mut tracer) = vm.take_tracer() {
+        if let Err(err) = tracer.finish() {
+            println!("Failed to write trace: {}", err);
+            process::exit(1);
+        }
+    }
+}
 
-            op if op == OpCodes::OP_NOT as u16 => {
-                //println!("Executing NOT, Instr {:#018b}", instr);
-                op_not(&mut registers, instr);
-            },
 
-            op if op == OpCodes::OP_LDI as u16 => {
-                //println!("Executing LDI, Instr {:#018b}", instr);
-                op_ldi(&mut registers, instr, &mut memory);
-            },
+//Prints the profile report if `report` is set, and writes the folded
+//stacks to `folded` if given.
+fn finish_profile(vm: &mut Vm, report: bool, folded: Option<&str>) {
+    let profile = match vm.take_profile() {
+        Some(profile) => profile,
+        None => return,
+    };
 
-            op if op == OpCodes::OP_STI as u16 => {
-                //println!("Executing STI, Instr {:#018b}", instr);
-                op_sti(&mut registers, instr, &mut memory);
-            },
+    if report {
+        print!("{}", profile.report(vm.memory(), vm.symbols(), PROFILE_HOT_SPOTS));
+    }
+    if let Some(path) = folded {
+        if let Err(err) = fs::write(path, profile.folded(vm.symbols())) {
+            println!("Failed to write {}: {}", path, err);
+            process::exit(1);
+        }
+    }
+}
 
-            op if op == OpCodes::OP_JMP as u16 => {
-                //println!("Executing JMP, Instr {:#018b}", instr);
-                op_jump(&mut registers, instr);
-            },
 
-            op if op == OpCodes::OP_RES as u16 => {
-                println!("Bad OpCode 'RES' received. Aborting.");
-                process::exit(10);
-            },
+//A source file given to --coverage, and what it assembles to.
+struct CoverageSource {
+    path: String,
+    text: String,
+    program: asm::Program,
+}
 
-            op if op == OpCodes::OP_LEA as u16 => {
-                //println!("Executing ADD, Instr {:#018b}", instr);
-                op_lea(&mut registers, instr);
+impl CoverageSource {
+    //Assembles `path`, making sure that its instructions are the ones in
+    //the VM's memory.
+    fn load(path: &str, vm: &Vm) -> CoverageSource {
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(err) => {
+                println!("Failed to read {}: {}", path, err);
+                process::exit(1);
+            },
+        };
+        let program = match asm::assemble(&text) {
+            Ok(program) => program,
+            Err(errors) => {
+                for err in &errors {
+                    println!("{}", err.render(path, &text));
+                }
+                println!("Error: {} does not assemble, no coverage for it", path);
+                process::exit(1);
             },
+        };
+
+        let memory = vm.memory();
+        for line in program.lines.iter().filter(|line| line.code) {
+            let offset = line.addr.wrapping_sub(program.origin) as usize;
+            if memory[line.addr as usize] != program.words[offset] {
+                println!("Error: {} does not match what is loaded at x{:04X} (line {})", path, line.addr, line.line);
+                process::exit(1);
+            }
+        }
 
-            //first 4 bits = 1111, is for trap code
-            op if op == OpCodes::OP_TRAP as u16 => {
-                //0xFF = 255, trapcode is identified by the last 8
-                //bits of the instruction
-                let trap: u16 = instr & 0xFF;
-                match trap {
-                    trap if trap == TrapCode::GETC as u16 => {
-                        //println!("Executing GETC TRAP, Instr {:#018b}", instr);
-                        trap_getc(&mut registers);
-                    },
+        CoverageSource { path: path.to_string(), text, program }
+    }
+}
 
-                    trap if trap == TrapCode::OUT as u16 => {
-                        //println!("Executing OUT TRAP, Instr {:#018b}", instr);
-                        trap_out(&mut registers);
-                    },
+//Writes the annotated listings (or prints them) and the lcov tracefile
+//of the sources given to --coverage.
+fn finish_coverage(vm: &mut Vm, sources: &[CoverageSource], listing: Option<&str>, lcov: Option<&str>) {
+    let coverage = match vm.take_coverage() {
+        Some(coverage) => coverage,
+        None => return,
+    };
+
+    let listings: Vec<String> = sources.iter()
+        .map(|source| coverage.listing(&source.path, &source.text, &source.program))
+        .collect();
+    match listing {
+        Some(path) => {
+            if let Err(err) = fs::write(path, listings.join("\n")) {
+                println!("Failed to write {}: {}", path, err);
+                process::exit(1);
+            }
+        },
+        None if lcov.is_none() => print!("{}", listings.join("\n")),
+        None => {},
+    }
 
-                    trap if trap == TrapCode::PUTS as u16 => {
-                        //println!("Executing PUTS TRAP, Instr {:#018b}", instr);
-                        trap_puts(&mut registers, &mut memory);
-                    },
+    if let Some(path) = lcov {
+        //lcov tools find the sources by the path in SF:, so make it one
+        //that works from anywhere.
+        let tracefile: String = sources.iter()
+            .map(|source| {
+                let file = fs::canonicalize(&source.path)
+                    .map(|path| path.to_string_lossy().into_owned())
+                    .unwrap_or_else(|_| source.path.clone());
+                coverage.lcov(&file, &source.program)
+            })
+            .collect();
+        if let Err(err) = fs::write(path, tracefile) {
+            println!("Failed to write {}: {}", path, err);
+            process::exit(1);
+        }
+    }
+}
 
-                    trap if trap == TrapCode::IN as u16 => {
-                        //println!("Executing IN  TRAP, Instr {:#018b}", instr);
-                        trap_in(&mut registers);
-                    },
 
-                    trap if trap == TrapCode::PUTSP as u16 => {
-                        //println!("Executing PUTSP TRAP, Instr {:#018b}", instr);
-                        trap_putsp(&mut registers, &mut memory);
-                    },
+//START..END, or a single address, for --trace-pc.
+fn parse_range(text: &str, symbols: &SymbolTable) -> Result<RangeInclusive<u16>, String> {
+    let (start, end) = match text.split_once("..") {
+        Some((start, end)) => (symbols.resolve(start)?, symbols.resolve(end)?),
+        None => {
+            let addr = symbols.resolve(text)?;
+            (addr, addr)
+        },
+    };
+    if end < start {
+        return Err("the range ends before it starts".to_string());
+    }
+    Ok(start..=end)
+}
 
-                    trap if trap == TrapCode::HALT as u16 => { 
-                        println!("HALT Trapcode received, Halting.");
-                        running = false;
-                    },
 
-                    _ => {
-                        println!("Invalid Trap Code received, aborting.");
-                        process::exit(21);
-                    }
-                }
+//rust-vm asm <source.asm> [-o <image.obj>]
+//Without -o the image is written next to the source, with a .obj extension.
+//The symbol table is written next to the image, with a .sym extension.
+fn assemble(args: &[String]) {
+    let mut source = None;
+    let mut output = None;
+
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "-o" => match iter.next() {
+                Some(path) => output = Some(path.as_str()),
+                None => {
+                    println!("Error: -o needs a file name");
+                    usage();
+                },
             },
-
+            "-h" | "--help" => usage(),
+            path if source.is_none() => source = Some(path),
             _ => {
-                println!("Invalid Opcode recieved, aborting current image.");
-                process::exit(20);
-            }
+                println!("Error: asm takes a single source file");
+                usage();
+            },
         }
+    }
+
+    let source = match source {
+        Some(source) => source,
+        None => {
+            println!("Error: provide a source file to assemble");
+            usage();
+        },
+    };
+    let output = match output {
+        Some(output) => output.to_string(),
+        None => Path::new(source).with_extension("obj").to_string_lossy().into_owned(),
+    };
+
+    let text = match fs::read_to_string(source) {
+        Ok(text) => text,
+        Err(err) => {
+            println!("Failed to read {}: {}", source, err);
+            process::exit(1);
+        },
+    };
+
+    let program = match asm::assemble(&text) {
+        Ok(program) => program,
+        Err(errors) => {
+            for err in &errors {
+                println!("{}", err.render(source, &text));
+            }
+            println!("{} error{} in {}, no image written",
+                errors.len(), if errors.len() == 1 { "" } else { "s" }, source);
+            process::exit(1);
+        },
+    };
+
+    if let Err(err) = fs::write(&output, program.to_obj_bytes()) {
+        println!("Failed to write {}: {}", output, err);
+        process::exit(1);
+    }
 
+    //The symbols go next to the image, where the VM looks for them.
+    let sym = Path::new(&output).with_extension("sym");
+    if let Err(err) = program.symbols.save(&sym) {
+        println!("Failed to write {}: {}", sym.display(), err);
+        process::exit(1);
     }
+}
 
-    // reset the stdin to original termios data
 
+//rust-vm disasm <image.obj>
+//Prints address, word and instruction for every word of the image, and
+//the labels from the image's .sym file if it has one.
+fn disassemble(args: &[String]) {
+    let image = match args {
+        [image] if !image.starts_with('-') => image,
+        _ => usage(),
+    };
+
+    let image_words = fs::read(image)
+        .map_err(VmError::from)
+        .and_then(|data| rust_vm::parse_image(&data));
+    let (origin, words) = match image_words {
+        Ok(image_words) => image_words,
+        Err(err) => {
+            println!("Failed to load image {}: {}", image, err);
+            process::exit(1);
+        },
+    };
 
-    tcsetattr(stdin, TCSANOW, &termios).unwrap();
-    println!("Shutting Down VM...");
+    let sym = Path::new(image).with_extension("sym");
+    let symbols = if sym.is_file() {
+        SymbolTable::load(&sym).unwrap_or_else(|err| {
+            println!("Failed to load symbols {}: {}", sym.display(), err);
+            process::exit(1);
+        })
+    }
+    else {
+        SymbolTable::new()
+    };
+
+    for line in disasm::listing(origin, &words, &symbols) {
+        if let Some(label) = &line.label {
+            println!("{}:", label);
+        }
+        println!("{}", line);
+    }
+}
+
+
+//Exit codes the VM has always used for the different kinds of aborts.
+fn exit_code(err: &VmError) -> i32 {
+    match err {
+        VmError::IllegalOpcode { .. }
+        | VmError::PrivilegeViolation { .. }
+        | VmError::AccessViolation { .. } => 10,
+        VmError::UnknownTrap { .. } => 21,
+        _ => 1,
+    }
 }
@@ -0,0 +1,114 @@
+use std::io;
+
+use crate::console::Console;
+use crate::decode::Instruction;
+use crate::device::{DeviceBus, Keyboard, Display, Timer};
+use crate::trace::MemAccess;
+use crate::watch::{Watchpoint, WatchHit, WatchKind};
+
+
+//The 64K words of LC3 memory together with the devices in the I/O page
+//and the console they talk to. mem_read() and mem_write() in lib.rs are
+//the only way instructions touch it.
+pub struct Memory {
+    pub(crate) words: Vec<u16>,
+    pub(crate) bus: DeviceBus,
+    pub(crate) console: Box<dyn Console>,
+    //Whether the console wants Console::clock() calls.
+    pub(crate) clocked: bool,
+    //A console error hit while accessing a memory mapped register. Memory
+    //accesses cannot fail, so it is parked here for the VM to pick up
+    //once the instruction is done.
+    pub(crate) fault: Option<io::Error>,
+    //Set while user mode code runs with exceptions on. Accesses outside of
+    //user space are then blocked and the first such address noted.
+    pub(crate) protect: bool,
+    pub(crate) violation: Option<u16>,
+    pub(crate) watchpoints: Vec<Watchpoint>,
+    //Accesses that matched a watchpoint, in the order they happened. The
+    //VM fills in the PC once the instruction is done.
+    pub(crate) watch_hits: Vec<WatchHit>,
+    //Every access made, while the VM is tracing an instruction.
+    pub(crate) accesses: Option<Vec<MemAccess>>,
+    //Address and old value of every word written, while the VM keeps an
+    //execution history.
+    pub(crate) undo: Option<Vec<(u16, u16)>>,
+    //The instruction at every address code was fetched from, decoded.
+    //Writing to an address drops what was decoded there.
+    pub(crate) decoded: Vec<Option<Instruction>>,
+}
+
+impl Memory {
+    //LC3 has 65536 memory locations, each storing 16 bits.
+    //So in total, it has a memory of 128KBs
+    pub const SIZE: usize = 65536;
+
+    //Memory with the standard keyboard, display and timer devices attached.
+    pub fn new(console: Box<dyn Console>) -> Memory {
+        let mut bus = DeviceBus::new();
+        bus.attach(Box::new(Keyboard::new())).unwrap();
+        bus.attach(Box::new(Display::new())).unwrap();
+        bus.attach(Box::new(Timer::new())).unwrap();
+
+        Memory {
+            words: vec![0u16; Memory::SIZE], //0u16 stands for 0 of type u16
+            bus,
+            clocked: console.clocked(),
+            console,
+            fault: None,
+            protect: false,
+            violation: None,
+            watchpoints: Vec::new(),
+            watch_hits: Vec::new(),
+            accesses: None,
+            undo: None,
+            decoded: vec![None; Memory::SIZE],
+        }
+    }
+
+    pub fn words(&self) -> &[u16] {
+        &self.words
+    }
+
+    //The caller may change any word, so everything decoded so far is
+    //forgotten.
+    pub fn words_mut(&mut self) -> &mut [u16] {
+        self.decoded.fill(None);
+        &mut self.words
+    }
+
+    //Changes a word behind the program's back: no watchpoints, no logging.
+    pub(crate) fn set_word(&mut self, addr: u16, val: u16) {
+        self.words[addr as usize] = val;
+        self.decoded[addr as usize] = None;
+    }
+
+    pub fn bus_mut(&mut self) -> &mut DeviceBus {
+        &mut self.bus
+    }
+
+    pub fn console_mut(&mut self) -> &mut dyn Console {
+        self.console.as_mut()
+    }
+
+    pub fn take_fault(&mut self) -> Option<io::Error> {
+        self.fault.take()
+    }
+
+    //Notes an access to `addr` if a watchpoint covers it.
+    pub(crate) fn watch(&mut self, addr: u16, kind: WatchKind, old: u16, new: u16) {
+        if self.watchpoints.iter().any(|watch| watch.matches(addr, kind)) {
+            self.watch_hits.push(WatchHit { pc: 0, addr, kind, old, new });
+        }
+    }
+
+    //Called by mem_read() and mem_write(), before a write is made.
+    pub(crate) fn log_access(&mut self, write: bool, addr: u16, val: u16) {
+        if let Some(accesses) = &mut self.accesses {
+            accesses.push(MemAccess { write, addr, val });
+        }
+        if let (true, Some(undo)) = (write, &mut self.undo) {
+            undo.push((addr, self.words[addr as usize]));
+        }
+    }
+}
@@ -0,0 +1,185 @@
+//A small LC3 operating system providing the standard trap routines in
+//LC3 code. With TrapMode::Vectored, TRAP jumps to whatever the trap vector
+//table at 0x0000 points to, and load_os() fills that table with the
+//routines below. They only talk to the outside world through the device
+//registers (KBSR/KBDR, DSR/DDR, MCR), just like on real hardware.
+//
+//The routines were assembled by hand from the listing in the comments.
+//TRAP pushes the PSR and PC onto the supervisor stack and runs the
+//routine in supervisor mode, so the entry points end with RTI. Behind
+//them are plain subroutines, which the OS also calls from within with
+//JSR, so the entry points keep the caller's R7 on the stack. Every
+//register is kept except the ones a routine returns a value in.
+
+use crate::TrapCode;
+
+pub const TRAP_VECTOR_TABLE: u16 = 0x0000;
+
+pub const OS_ORIGIN: u16 = 0x0200;
+pub const OS_SIZE: usize = 180;
+
+pub const GETC_ROUTINE: u16 = 0x0200;
+pub const OUT_ROUTINE: u16 = 0x0204;
+pub const PUTS_ROUTINE: u16 = 0x0208;
+pub const IN_ROUTINE: u16 = 0x020C;
+pub const PUTSP_ROUTINE: u16 = 0x0210;
+pub const HALT_ROUTINE: u16 = 0x0214;
+//Every other trap vector ends up here, which prints a message and halts.
+pub const BAD_TRAP_ROUTINE: u16 = 0x0218;
+
+pub static OS_CODE: [u16; OS_SIZE] = [
+    0x1DBF, // x0200  EGETC   ADD R6, R6, #-1
+    0x7F80, // x0201          STR R7, R6, #0
+    0x481D, // x0202          JSR TGETC
+    0x0E19, // x0203          BRnzp DONE
+    0x1DBF, // x0204  EOUT    ADD R6, R6, #-1
+    0x7F80, // x0205          STR R7, R6, #0
+    0x481D, // x0206          JSR TOUT
+    0x0E15, // x0207          BRnzp DONE
+    0x1DBF, // x0208  EPUTS   ADD R6, R6, #-1
+    0x7F80, // x0209          STR R7, R6, #0
+    0x481F, // x020A          JSR TPUTS
+    0x0E11, // x020B          BRnzp DONE
+    0x1DBF, // x020C  EIN     ADD R6, R6, #-1
+    0x7F80, // x020D          STR R7, R6, #0
+    0x4829, // x020E          JSR TIN
+    0x0E0D, // x020F          BRnzp DONE
+    0x1DBF, // x0210  EPUTSP  ADD R6, R6, #-1
+    0x7F80, // x0211          STR R7, R6, #0
+    0x482C, // x0212          JSR TPUTSP
+    0x0E09, // x0213          BRnzp DONE
+    0x1DBF, // x0214  EHALT   ADD R6, R6, #-1
+    0x7F80, // x0215          STR R7, R6, #0
+    0x484A, // x0216          JSR THALT
+    0x0E05, // x0217          BRnzp DONE
+    0x1DBF, // x0218  EBAD    ADD R6, R6, #-1
+    0x7F80, // x0219          STR R7, R6, #0
+    0xE077, // x021A          LEA R0, BADMSG
+    0x480E, // x021B          JSR TPUTS
+    0x4844, // x021C          JSR THALT
+    0x6F80, // x021D  DONE    LDR R7, R6, #0
+    0x1DA1, // x021E          ADD R6, R6, #1
+    0x8000, // x021F          RTI
+    0xA049, // x0220  TGETC   LDI R0, KBSRP
+    0x07FE, // x0221          BRzp TGETC
+    0xA048, // x0222          LDI R0, KBDRP
+    0xC1C0, // x0223          RET
+    0x324C, // x0224  TOUT    ST R1, OUT1
+    0xA246, // x0225  OUTW    LDI R1, DSRP
+    0x07FE, // x0226          BRzp OUTW
+    0xB045, // x0227          STI R0, DDRP
+    0x2248, // x0228          LD R1, OUT1
+    0xC1C0, // x0229          RET
+    0x3047, // x022A  TPUTS   ST R0, PUTS0
+    0x3247, // x022B          ST R1, PUTS1
+    0x3447, // x022C          ST R2, PUTS2
+    0x6200, // x022D  PUTSL   LDR R1, R0, #0
+    0x0405, // x022E          BRz PUTSD
+    0xA43C, // x022F  PUTSW   LDI R2, DSRP
+    0x07FE, // x0230          BRzp PUTSW
+    0xB23B, // x0231          STI R1, DDRP
+    0x1021, // x0232          ADD R0, R0, #1
+    0x0FF9, // x0233          BRnzp PUTSL
+    0x203D, // x0234  PUTSD   LD R0, PUTS0
+    0x223D, // x0235          LD R1, PUTS1
+    0x243D, // x0236          LD R2, PUTS2
+    0xC1C0, // x0237          RET
+    0x3E3C, // x0238  TIN     ST R7, IN7
+    0xE044, // x0239          LEA R0, INMSG
+    0x4FEF, // x023A          JSR TPUTS
+    0x4FE4, // x023B          JSR TGETC
+    0x4FE7, // x023C          JSR TOUT
+    0x2E37, // x023D          LD R7, IN7
+    0xC1C0, // x023E          RET
+    0x3E3B, // x023F  TPUTSP  ST R7, PSP7
+    0x3035, // x0240          ST R0, PSP0
+    0x3235, // x0241          ST R1, PSP1
+    0x3435, // x0242          ST R2, PSP2
+    0x3635, // x0243          ST R3, PSP3
+    0x3835, // x0244          ST R4, PSP4
+    0x1220, // x0245          ADD R1, R0, #0
+    0x6440, // x0246  PSPL    LDR R2, R1, #0
+    0x0412, // x0247          BRz PSPD
+    0x2027, // x0248          LD R0, LOMASK
+    0x5080, // x0249          AND R0, R2, R0
+    0x4FD9, // x024A          JSR TOUT
+    0x56E0, // x024B          AND R3, R3, #0
+    0x5920, // x024C          AND R4, R4, #0
+    0x1928, // x024D          ADD R4, R4, #8
+    0x16C3, // x024E  PSPS    ADD R3, R3, R3
+    0x14A0, // x024F          ADD R2, R2, #0
+    0x0601, // x0250          BRzp PSPZ
+    0x16E1, // x0251          ADD R3, R3, #1
+    0x1482, // x0252  PSPZ    ADD R2, R2, R2
+    0x193F, // x0253          ADD R4, R4, #-1
+    0x03F9, // x0254          BRp PSPS
+    0x10E0, // x0255          ADD R0, R3, #0
+    0x0401, // x0256          BRz PSPN
+    0x4FCC, // x0257          JSR TOUT
+    0x1261, // x0258  PSPN    ADD R1, R1, #1
+    0x0FEC, // x0259          BRnzp PSPL
+    0x201B, // x025A  PSPD    LD R0, PSP0
+    0x221B, // x025B          LD R1, PSP1
+    0x241B, // x025C          LD R2, PSP2
+    0x261B, // x025D          LD R3, PSP3
+    0x281B, // x025E          LD R4, PSP4
+    0x2E1B, // x025F          LD R7, PSP7
+    0xC1C0, // x0260          RET
+    0x301A, // x0261  THALT   ST R0, HALT0
+    0x321A, // x0262          ST R1, HALT1
+    0xA00A, // x0263          LDI R0, MCRP
+    0x220A, // x0264          LD R1, CLKMASK
+    0x5001, // x0265          AND R0, R0, R1
+    0xB007, // x0266          STI R0, MCRP
+    0x2014, // x0267          LD R0, HALT0
+    0x2214, // x0268          LD R1, HALT1
+    0xC1C0, // x0269          RET
+    0xFE00, // x026A  KBSRP   .FILL xFE00
+    0xFE02, // x026B  KBDRP   .FILL xFE02
+    0xFE04, // x026C  DSRP    .FILL xFE04
+    0xFE06, // x026D  DDRP    .FILL xFE06
+    0xFFFE, // x026E  MCRP    .FILL xFFFE
+    0x7FFF, // x026F  CLKMASK .FILL x7FFF
+    0x00FF, // x0270  LOMASK  .FILL x00FF
+    0x0000, // x0271  OUT1    .FILL x0000
+    0x0000, // x0272  PUTS0   .FILL x0000
+    0x0000, // x0273  PUTS1   .FILL x0000
+    0x0000, // x0274  PUTS2   .FILL x0000
+    0x0000, // x0275  IN7     .FILL x0000
+    0x0000, // x0276  PSP0    .FILL x0000
+    0x0000, // x0277  PSP1    .FILL x0000
+    0x0000, // x0278  PSP2    .FILL x0000
+    0x0000, // x0279  PSP3    .FILL x0000
+    0x0000, // x027A  PSP4    .FILL x0000
+    0x0000, // x027B  PSP7    .FILL x0000
+    0x0000, // x027C  HALT0   .FILL x0000
+    0x0000, // x027D  HALT1   .FILL x0000
+    // x027E  INMSG   .STRINGZ "Enter a character: "
+    0x0045, 0x006E, 0x0074, 0x0065, 0x0072, 0x0020, 0x0061, 0x0020,
+    0x0063, 0x0068, 0x0061, 0x0072, 0x0061, 0x0063, 0x0074, 0x0065,
+    0x0072, 0x003A, 0x0020, 0x0000,
+    // x0292  BADMSG  .STRINGZ "\nUnknown TRAP executed, halting.\n"
+    0x000A, 0x0055, 0x006E, 0x006B, 0x006E, 0x006F, 0x0077, 0x006E,
+    0x0020, 0x0054, 0x0052, 0x0041, 0x0050, 0x0020, 0x0065, 0x0078,
+    0x0065, 0x0063, 0x0075, 0x0074, 0x0065, 0x0064, 0x002C, 0x0020,
+    0x0068, 0x0061, 0x006C, 0x0074, 0x0069, 0x006E, 0x0067, 0x002E,
+    0x000A, 0x0000,
+];
+
+
+//Copies the OS into memory and points the trap vector table at it.
+pub fn load_os(memory: &mut [u16]) {
+    let table = &mut memory[TRAP_VECTOR_TABLE as usize..][..0x100];
+    for entry in table.iter_mut() {
+        *entry = BAD_TRAP_ROUTINE;
+    }
+
+    table[TrapCode::GETC as usize] = GETC_ROUTINE;
+    table[TrapCode::OUT as usize] = OUT_ROUTINE;
+    table[TrapCode::PUTS as usize] = PUTS_ROUTINE;
+    table[TrapCode::IN as usize] = IN_ROUTINE;
+    table[TrapCode::PUTSP as usize] = PUTSP_ROUTINE;
+    table[TrapCode::HALT as usize] = HALT_ROUTINE;
+
+    memory[OS_ORIGIN as usize..][..OS_CODE.len()].copy_from_slice(&OS_CODE);
+}
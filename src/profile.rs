@@ -0,0 +1,329 @@
+//An execution profiler. It counts how often every address is executed
+//and how many cycles go to every subroutine. Subroutines are followed
+//by watching calls (JSR, JSRR, TRAP to a routine in memory, and the VM
+//entering an interrupt or exception handler) and returns (RET, RTI).
+//The cycles are kept per calling context, the chain of subroutines the
+//program was in, which is also what flamegraph tools want as "folded
+//stacks": `START;DRAW;PUTS 1234`.
+
+use std::collections::HashMap;
+use std::fmt::Write;
+
+use crate::Memory;
+use crate::disasm::disassemble;
+use crate::opcodes::OpCodes;
+use crate::symbols::SymbolTable;
+
+
+//A subroutine called from a particular chain of subroutines.
+struct Frame {
+    //The subroutine's first instruction.
+    entry: u16,
+    parent: usize,
+    children: HashMap<u16, usize>,
+    calls: u64,
+    //Cycles spent in the subroutine itself, not in what it called.
+    cycles: u64,
+}
+
+pub struct Profile {
+    counts: Vec<u64>,
+    cycles: Vec<u64>,
+    //frames[0] is where the program started, `current` the one it is in.
+    frames: Vec<Frame>,
+    current: usize,
+    instructions: u64,
+    total_cycles: u64,
+}
+
+//One line of the per-subroutine report.
+pub struct SubroutineStats {
+    pub entry: u16,
+    pub calls: u64,
+    pub self_cycles: u64,
+    //Including everything it called.
+    pub total_cycles: u64,
+}
+
+impl SubroutineStats {
+    fn new(entry: u16) -> SubroutineStats {
+        SubroutineStats { entry, calls: 0, self_cycles: 0, total_cycles: 0 }
+    }
+}
+
+impl Profile {
+    pub fn new() -> Profile {
+        Profile {
+            counts: vec![0; Memory::SIZE],
+            cycles: vec![0; Memory::SIZE],
+            frames: Vec::new(),
+            current: 0,
+            instructions: 0,
+            total_cycles: 0,
+        }
+    }
+
+    //Notes the instruction `instr` at `pc`, which took `cost` cycles and
+    //left the PC at `next_pc`. `serviced` says whether the VM went into an
+    //interrupt or exception handler after it.
+    pub fn record(&mut self, pc: u16, instr: u16, cost: u64, next_pc: u16, serviced: bool) {
+        if self.frames.is_empty() {
+            self.frames.push(Frame { entry: pc, parent: 0, children: HashMap::new(), calls: 1, cycles: 0 });
+        }
+
+        self.counts[pc as usize] += 1;
+        self.cycles[pc as usize] += cost;
+        self.frames[self.current].cycles += cost;
+        self.instructions += 1;
+        self.total_cycles += cost;
+
+        //A TRAP the VM carried out natively carries on with the next
+        //instruction. One that went to a routine in memory, through the
+        //vector table, is a call that routine returns from.
+        let op = instr >> 12;
+        let trapped = op == OpCodes::OP_TRAP as u16 && next_pc != pc.wrapping_add(1);
+        if op == OpCodes::OP_JSR as u16 || trapped {
+            self.call(next_pc);
+        }
+        else if (op == OpCodes::OP_JMP as u16 && (instr >> 6) & 0x7 == 7) || op == OpCodes::OP_RTI as u16 {
+            self.ret();
+        }
+        if serviced {
+            self.call(next_pc);
+        }
+    }
+
+    fn call(&mut self, entry: u16) {
+        let current = self.current;
+        let next = self.frames.len();
+        let child = *self.frames[current].children.entry(entry).or_insert(next);
+        if child == next {
+            self.frames.push(Frame { entry, parent: current, children: HashMap::new(), calls: 0, cycles: 0 });
+        }
+        self.frames[child].calls += 1;
+        self.current = child;
+    }
+
+    //A return with no call to go back to (the program jumping through R7
+    //for its own reasons) leaves us where we are.
+    fn ret(&mut self) {
+        if self.current != 0 {
+            self.current = self.frames[self.current].parent;
+        }
+    }
+
+    pub fn instructions(&self) -> u64 {
+        self.instructions
+    }
+
+    pub fn total_cycles(&self) -> u64 {
+        self.total_cycles
+    }
+
+    //How often the instruction at `addr` was executed.
+    pub fn count(&self, addr: u16) -> u64 {
+        self.counts[addr as usize]
+    }
+
+    //Executed addresses, the most cycles first.
+    pub fn hot_spots(&self) -> Vec<(u16, u64, u64)> {
+        let mut spots: Vec<(u16, u64, u64)> = (0..Memory::SIZE)
+            .filter(|&addr| self.counts[addr] > 0)
+            .map(|addr| (addr as u16, self.counts[addr], self.cycles[addr]))
+            .collect();
+        spots.sort_by_key(|&(addr, count, cycles)| (std::cmp::Reverse(cycles), std::cmp::Reverse(count), addr));
+        spots
+    }
+
+    //Every subroutine, the most cycles (including callees) first.
+    pub fn subroutines(&self) -> Vec<SubroutineStats> {
+        let mut stats: HashMap<u16, SubroutineStats> = HashMap::new();
+
+        for (index, frame) in self.frames.iter().enumerate() {
+            let entry = stats.entry(frame.entry).or_insert_with(|| SubroutineStats::new(frame.entry));
+            entry.calls += frame.calls;
+            entry.self_cycles += frame.cycles;
+
+            //The cycles of a frame count towards every subroutine on its
+            //chain, once, however deep a subroutine recursed.
+            let mut seen = Vec::new();
+            let mut at = index;
+            loop {
+                let entry = self.frames[at].entry;
+                if !seen.contains(&entry) {
+                    seen.push(entry);
+                }
+                if at == 0 {
+                    break;
+                }
+                at = self.frames[at].parent;
+            }
+            for entry in seen {
+                stats.entry(entry).or_insert_with(|| SubroutineStats::new(entry)).total_cycles += frame.cycles;
+            }
+        }
+
+        let mut stats: Vec<SubroutineStats> = stats.into_values().collect();
+        stats.sort_by_key(|s| (std::cmp::Reverse(s.total_cycles), std::cmp::Reverse(s.self_cycles), s.entry));
+        stats
+    }
+
+    //The report --profile prints: the `top` hottest addresses, then every
+    //subroutine.
+    pub fn report(&self, memory: &[u16], symbols: &SymbolTable, top: usize) -> String {
+        let mut text = String::new();
+        let percent = |cycles: u64| 100.0 * cycles as f64 / self.total_cycles.max(1) as f64;
+
+        //Writing to a String cannot fail.
+        let _ = writeln!(text, "Profile: {} instructions, {} cycles", self.instructions, self.total_cycles);
+        let _ = writeln!(text);
+        let _ = writeln!(text, "Hot spots:");
+        let _ = writeln!(text, "{:>12} {:>12} {:>7}  address", "count", "cycles", "%");
+        for (addr, count, cycles) in self.hot_spots().into_iter().take(top) {
+            let _ = writeln!(
+                text,
+                "{:>12} {:>12} {:>6.2}%  {:<20} {}",
+                count, cycles, percent(cycles), symbols.annotate(addr), disassemble(memory[addr as usize], addr, symbols)
+            );
+        }
+
+        let _ = writeln!(text);
+        let _ = writeln!(text, "Subroutines:");
+        let _ = writeln!(text, "{:>10} {:>12} {:>7} {:>12} {:>7}  subroutine", "calls", "self", "%", "total", "%");
+        for stats in self.subroutines() {
+            let _ = writeln!(
+                text,
+                "{:>10} {:>12} {:>6.2}% {:>12} {:>6.2}%  {}",
+                stats.calls,
+                stats.self_cycles, percent(stats.self_cycles),
+                stats.total_cycles, percent(stats.total_cycles),
+                symbols.annotate(stats.entry),
+            );
+        }
+        text
+    }
+
+    //Folded stacks, one line per calling context with the cycles spent
+    //in it: `START;DRAW;PUTS 1234`. Subroutines are named by their label,
+    //or their address if they have none.
+    pub fn folded(&self, symbols: &SymbolTable) -> String {
+        let name = |entry: u16| symbols.describe(entry).unwrap_or_else(|| format!("x{:04X}", entry));
+        let mut text = String::new();
+
+        for (index, frame) in self.frames.iter().enumerate() {
+            if frame.cycles == 0 {
+                continue;
+            }
+            let mut chain = Vec::new();
+            let mut at = index;
+            loop {
+                chain.push(name(self.frames[at].entry));
+                if at == 0 {
+                    break;
+                }
+                at = self.frames[at].parent;
+            }
+            chain.reverse();
+            let _ = writeln!(text, "{} {}", chain.join(";"), frame.cycles);
+        }
+        text
+    }
+}
+
+impl Default for Profile {
+    fn default() -> Profile {
+        Profile::new()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Vm, StopReason, TrapMode};
+    use crate::asm::{assemble, Program};
+    use crate::console::BufferConsole;
+
+    //Calls SUB twice, then OUTER, which calls a trap routine of its own.
+    const CALLS: &str = "
+            .ORIG x3000
+    MAIN    LD R0, GUESTP
+            STI R0, VEC40
+            JSR SUB
+            JSR SUB
+            JSR OUTER
+            HALT
+    SUB     ADD R1, R1, #1
+            RET
+    OUTER   ST R7, SAVE7
+            TRAP x40
+            ADD R3, R3, #1
+            LD R7, SAVE7
+            RET
+    GUEST   ADD R2, R2, #1
+            RET
+    GUESTP  .FILL GUEST
+    VEC40   .FILL x0040
+    SAVE7   .FILL 0
+            .END
+    ";
+
+    //Prints a character through the OS and carries on.
+    const OS_CALL: &str = "
+            .ORIG x3000
+    MAIN    LD R0, CH
+            OUT
+            ADD R1, R1, #1
+            HALT
+    CH      .FILL x0041
+            .END
+    ";
+
+    fn profile(source: &str, trap_mode: TrapMode) -> (Profile, Program, Vm) {
+        let program = assemble(source).unwrap();
+        let mut vm = Vm::with_console(Box::new(BufferConsole::new(b"")));
+        vm.memory_mut()[0x3000..0x3000 + program.words.len()].copy_from_slice(&program.words);
+        if trap_mode == TrapMode::Vectored {
+            vm.load_os();
+        }
+        vm.set_trap_mode(trap_mode);
+        vm.start_profile();
+        assert_eq!(vm.run().unwrap(), StopReason::Halted);
+        (vm.take_profile().unwrap(), program, vm)
+    }
+
+    //A native TRAP is no call, a guest routine reached through the trap
+    //vector table is one, and its RET goes back to the caller.
+    #[test]
+    fn folded_stacks_follow_calls_and_traps() {
+        let (profile, program, _) = profile(CALLS, TrapMode::Native);
+        assert_eq!(profile.folded(&program.symbols), "MAIN 9\nMAIN;SUB 4\nMAIN;OUTER 7\nMAIN;OUTER;GUEST 2\n");
+    }
+
+    #[test]
+    fn os_traps_return_with_rti() {
+        let (profile, program, _) = profile(OS_CALL, TrapMode::Vectored);
+        let folded = profile.folded(&program.symbols);
+        let lines: Vec<&str> = folded.lines().collect();
+        assert_eq!(lines[0], "MAIN 5");
+        assert!(lines[1..].iter().all(|line| line.starts_with("MAIN;x0204") || line.starts_with("MAIN;x0214")));
+    }
+
+    #[test]
+    fn report_lists_hot_spots_and_subroutines() {
+        let (profile, program, vm) = profile(CALLS, TrapMode::Native);
+        assert_eq!((profile.instructions(), profile.total_cycles()), (17, 22));
+        assert_eq!(profile.count(program.symbols.get("SUB").unwrap()), 2);
+
+        let report = profile.report(vm.memory(), &program.symbols, 1);
+        let lines: Vec<&str> = report.lines().collect();
+        assert_eq!(lines[0], "Profile: 17 instructions, 22 cycles");
+        assert_eq!(lines[4], "           1            3  13.64%  x3001 (MAIN+1)       STI R0, VEC40");
+        assert_eq!(&lines[8..], [
+            "         1            9  40.91%           22 100.00%  x3000 (MAIN)",
+            "         1            7  31.82%            9  40.91%  x3008 (OUTER)",
+            "         2            4  18.18%            4  18.18%  x3006 (SUB)",
+            "         1            2   9.09%            2   9.09%  x300D (GUEST)",
+        ]);
+    }
+}
@@ -0,0 +1,366 @@
+//Recording the input a program gets and feeding it back, so a session
+//can be reproduced exactly. The VM runs the same way every time except
+//for when keys arrive, and that is what the input log pins down: every
+//byte the program got, with the instruction it got it at.
+//
+//An input log is text, one event per line after the header:
+//
+//  # rust_vm input log v1
+//  1502 0 read 61
+//  98311 1 poll 77
+//
+//that is the instruction count, which console call during that
+//instruction it was (an instruction may poll the keyboard more than
+//once, e.g. reading KBSR while the keyboard checks for keys by itself),
+//whether it was a blocking read (GETC, IN) or a poll that found a key
+//(KBSR), and the byte in hex.
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+
+use crate::console::Console;
+
+
+const HEADER: &str = "# rust_vm input log v1";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InputKind {
+    Read,
+    Poll,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct InputEvent {
+    pub instructions: u64,
+    //How many console input calls came before this one during the same
+    //instruction.
+    pub call: u32,
+    pub kind: InputKind,
+    pub byte: u8,
+}
+
+impl fmt::Display for InputEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let kind = match self.kind {
+            InputKind::Read => "read",
+            InputKind::Poll => "poll",
+        };
+        write!(f, "{} {} {} {:02x}", self.instructions, self.call, kind, self.byte)
+    }
+}
+
+impl InputEvent {
+    fn parse(line: &str) -> Option<InputEvent> {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let (instructions, call, kind, byte) = match fields[..] {
+            [instructions, call, kind, byte] => (instructions, call, kind, byte),
+            _ => return None,
+        };
+        let kind = match kind {
+            "read" => InputKind::Read,
+            "poll" => InputKind::Poll,
+            _ => return None,
+        };
+
+        Some(InputEvent {
+            instructions: instructions.parse().ok()?,
+            call: call.parse().ok()?,
+            kind,
+            byte: u8::from_str_radix(byte, 16).ok()?,
+        })
+    }
+}
+
+//Reads an input log. Blank lines and `#` comments are skipped.
+pub fn parse_log(text: &str) -> Result<Vec<InputEvent>, String> {
+    let mut events = Vec::new();
+
+    for (number, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match InputEvent::parse(line) {
+            Some(event) => events.push(event),
+            None => return Err(format!("line {}: bad input event `{}`", number + 1, line)),
+        }
+    }
+
+    Ok(events)
+}
+
+
+//Keeps count of where the VM is, for the two consoles below.
+#[derive(Default)]
+struct Clock {
+    instructions: u64,
+    calls: u32,
+}
+
+impl Clock {
+    fn set(&mut self, instructions: u64) {
+        self.instructions = instructions;
+        self.calls = 0;
+    }
+
+    //Position of the console call being made, counting it.
+    fn next_call(&mut self) -> (u64, u32) {
+        let call = self.calls;
+        self.calls += 1;
+        (self.instructions, call)
+    }
+}
+
+
+//Passes everything through to `inner` and writes the input it hands out
+//to `log`.
+pub struct RecordingConsole<W: Write> {
+    inner: Box<dyn Console>,
+    log: W,
+    clock: Clock,
+}
+
+impl<W: Write> RecordingConsole<W> {
+    pub fn new(inner: Box<dyn Console>, mut log: W) -> io::Result<RecordingConsole<W>> {
+        writeln!(log, "{}", HEADER)?;
+        log.flush()?;
+        Ok(RecordingConsole { inner, log, clock: Clock::default() })
+    }
+
+    fn record(&mut self, kind: InputKind, byte: u8, (instructions, call): (u64, u32)) -> io::Result<()> {
+        let event = InputEvent { instructions, call, kind, byte };
+        //Keys are few and far between, and the log should survive the VM
+        //going down.
+        writeln!(self.log, "{}", event)?;
+        self.log.flush()
+    }
+}
+
+impl<W: Write> Console for RecordingConsole<W> {
+    fn read_byte(&mut self) -> io::Result<u8> {
+        let at = self.clock.next_call();
+        let byte = self.inner.read_byte()?;
+        self.record(InputKind::Read, byte, at)?;
+        Ok(byte)
+    }
+
+    fn poll_key(&mut self) -> io::Result<Option<u8>> {
+        let at = self.clock.next_call();
+        let key = self.inner.poll_key()?;
+        if let Some(byte) = key {
+            self.record(InputKind::Poll, byte, at)?;
+        }
+        Ok(key)
+    }
+
+    fn write_byte(&mut self, byte: u8) -> io::Result<()> {
+        self.inner.write_byte(byte)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+
+    fn clock(&mut self, instructions: u64) {
+        self.clock.set(instructions);
+        self.inner.clock(instructions);
+    }
+
+    fn clocked(&self) -> bool {
+        true
+    }
+}
+
+
+//Hands out the input of a log at exactly the points it was recorded at,
+//and nothing in between. Output goes to `inner`. Once the log is used up,
+//input comes from `inner` too, so the user can take over from there.
+//A program asking for input where the log has none (or the other way
+//around) is not the program that was recorded, that is an InvalidData
+//error.
+pub struct ReplayConsole {
+    inner: Box<dyn Console>,
+    events: VecDeque<InputEvent>,
+    clock: Clock,
+}
+
+impl ReplayConsole {
+    pub fn new(inner: Box<dyn Console>, events: Vec<InputEvent>) -> ReplayConsole {
+        ReplayConsole { inner, events: events.into(), clock: Clock::default() }
+    }
+
+    pub fn load(inner: Box<dyn Console>, path: &Path) -> io::Result<ReplayConsole> {
+        let events = parse_log(&fs::read_to_string(path)?)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        Ok(ReplayConsole::new(inner, events))
+    }
+
+    //True once every event of the log has been replayed.
+    pub fn is_finished(&self) -> bool {
+        self.events.is_empty()
+    }
+
+    //The event for the call at `at`, if the log has one there.
+    fn take(&mut self, kind: InputKind, at: (u64, u32)) -> io::Result<Option<u8>> {
+        let event = match self.events.front() {
+            Some(event) => *event,
+            None => return Ok(None),
+        };
+
+        if (event.instructions, event.call) > at {
+            return Ok(None);
+        }
+        if (event.instructions, event.call) < at || event.kind != kind {
+            return Err(diverged(&event));
+        }
+        self.events.pop_front();
+        Ok(Some(event.byte))
+    }
+}
+
+impl Console for ReplayConsole {
+    fn read_byte(&mut self) -> io::Result<u8> {
+        let at = self.clock.next_call();
+        if self.events.is_empty() {
+            return self.inner.read_byte();
+        }
+        match self.take(InputKind::Read, at)? {
+            Some(byte) => Ok(byte),
+            None => Err(diverged(&self.events[0])),
+        }
+    }
+
+    fn poll_key(&mut self) -> io::Result<Option<u8>> {
+        let at = self.clock.next_call();
+        if self.events.is_empty() {
+            return self.inner.poll_key();
+        }
+        self.take(InputKind::Poll, at)
+    }
+
+    fn write_byte(&mut self, byte: u8) -> io::Result<()> {
+        self.inner.write_byte(byte)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+
+    fn clock(&mut self, instructions: u64) {
+        self.clock.set(instructions);
+        self.inner.clock(instructions);
+    }
+
+    fn clocked(&self) -> bool {
+        true
+    }
+}
+
+fn diverged(expected: &InputEvent) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("replay diverged from the input log, next event was `{}`", expected),
+    )
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use super::*;
+    use crate::{Vm, StopReason, VmError};
+    use crate::asm::assemble;
+    use crate::console::{BufferConsole, ScriptedConsole};
+
+    //Polls for three keys, printing each with how many polls it took
+    //(modulo 8), then reads one more with GETC.
+    const POLLING: &str = "
+            .ORIG x3000
+            AND R3, R3, #0
+            ADD R3, R3, #3
+    AGAIN   AND R1, R1, #0
+    WAIT    ADD R1, R1, #1
+            LDI R2, KBSR
+            BRzp WAIT
+            LDI R0, KBDR
+            OUT
+            AND R1, R1, #7
+            LD R0, ZERO
+            ADD R0, R0, R1
+            OUT
+            ADD R3, R3, #-1
+            BRp AGAIN
+            GETC
+            OUT
+            HALT
+    KBSR    .FILL xFE00
+    KBDR    .FILL xFE02
+    ZERO    .FILL x0030
+            .END
+    ";
+
+    fn run(console: Box<dyn Console>) -> Result<StopReason, VmError> {
+        let program = assemble(POLLING).unwrap();
+        let mut vm = Vm::with_console(console);
+        vm.memory_mut()[0x3000..0x3000 + program.words.len()].copy_from_slice(&program.words);
+        vm.run_for(100_000)
+    }
+
+    //Runs POLLING on the input of `script`, returning its output and the
+    //input log.
+    fn record(script: ScriptedConsole) -> (Vec<u8>, String) {
+        let script = Rc::new(RefCell::new(script));
+        let recording = RecordingConsole::new(Box::new(script.clone()), Vec::new()).unwrap();
+        let recording = Rc::new(RefCell::new(recording));
+        assert_eq!(run(Box::new(recording.clone())).unwrap(), StopReason::Halted);
+
+        let output = script.borrow().output().to_vec();
+        let log = String::from_utf8(recording.borrow().log.clone()).unwrap();
+        (output, log)
+    }
+
+    #[test]
+    fn events_print_and_parse_back() {
+        let event = InputEvent { instructions: 98311, call: 1, kind: InputKind::Poll, byte: 0x77 };
+        assert_eq!(event.to_string(), "98311 1 poll 77");
+        assert_eq!(parse_log(&format!("{}\n\n{}\n", HEADER, event)), Ok(vec![event]));
+        assert!(parse_log("12 0 peek 61").is_err());
+    }
+
+    #[test]
+    fn replay_gives_the_same_output() {
+        let script = ScriptedConsole::new().idle(5).input(b"a").idle(13).input(b"b").idle(2).input(b"cd");
+        let (recorded, log) = record(script);
+        assert!(log.starts_with(HEADER));
+
+        let events = parse_log(&log).unwrap();
+        let kinds: Vec<InputKind> = events.iter().map(|event| event.kind).collect();
+        assert_eq!(kinds, [InputKind::Poll, InputKind::Poll, InputKind::Poll, InputKind::Read]);
+
+        //Nothing to read but the log.
+        let screen = Rc::new(RefCell::new(BufferConsole::new(b"")));
+        let replay = Rc::new(RefCell::new(ReplayConsole::new(Box::new(screen.clone()), events)));
+        assert_eq!(run(Box::new(replay.clone())).unwrap(), StopReason::Halted);
+        assert!(replay.borrow().is_finished());
+        assert_eq!(screen.borrow().output(), recorded);
+    }
+
+    #[test]
+    fn replay_notices_other_timing() {
+        let (_, log) = record(ScriptedConsole::new().idle(5).input(b"abcd"));
+        let mut events = parse_log(&log).unwrap();
+        events[0].instructions -= 1;
+
+        let replay = ReplayConsole::new(Box::new(BufferConsole::new(b"")), events);
+        match run(Box::new(replay)) {
+            Err(VmError::Io(err)) => assert_eq!(err.kind(), io::ErrorKind::InvalidData),
+            result => panic!("expected the replay to diverge, got {:?}", result),
+        }
+    }
+}
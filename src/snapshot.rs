@@ -0,0 +1,251 @@
+//Snapshots of the whole machine, to stop a program and carry on with it
+//later. A snapshot file is all big-endian, like .obj images:
+//
+//  MAGIC                      8 bytes
+//  VERSION                    u16
+//  registers                  u16 count, then the words (R0-R7, PC, PSR)
+//  saved USP, saved SSP       u16 each
+//  flags                      u16: bit 0 exceptions on, bit 1 vectored traps
+//  MCR                        u16
+//  instructions executed      u64
+//  cycles taken               u64 (since version 2)
+//  pending interrupts         u16 count, then vector and priority, u16 each
+//  devices                    u16 count, then per device a u16 count and
+//                             the words of its state, in attach order
+//  memory                     65536 words
+//
+//Breakpoints, watchpoints, symbols and the execution history are not
+//part of the machine and stay as they are.
+
+use std::fs;
+use std::path::Path;
+
+use crate::{Memory, TrapMode, VmError};
+use crate::interrupt::Interrupt;
+
+
+pub const MAGIC: &[u8; 8] = b"LC3SNAP\0";
+pub const VERSION: u16 = 2;
+
+const FLAG_EXCEPTIONS: u16 = 1 << 0;
+const FLAG_VECTORED_TRAPS: u16 = 1 << 1;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Snapshot {
+    pub registers: Vec<u16>,
+    pub saved_usp: u16,
+    pub saved_ssp: u16,
+    pub exceptions: bool,
+    pub trap_mode: TrapMode,
+    pub mcr: u16,
+    pub instructions: u64,
+    pub cycles: u64,
+    pub interrupts: Vec<Interrupt>,
+    //What Device::save_state() returned for each device on the bus.
+    pub devices: Vec<Vec<u16>>,
+    pub memory: Vec<u16>,
+}
+
+impl Snapshot {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Writer(Vec::with_capacity(MAGIC.len() + 2 * Memory::SIZE + 64));
+        out.0.extend_from_slice(MAGIC);
+        out.word(VERSION);
+
+        out.words(&self.registers);
+        out.word(self.saved_usp);
+        out.word(self.saved_ssp);
+        let mut flags = 0;
+        if self.exceptions {
+            flags |= FLAG_EXCEPTIONS;
+        }
+        if self.trap_mode == TrapMode::Vectored {
+            flags |= FLAG_VECTORED_TRAPS;
+        }
+        out.word(flags);
+        out.word(self.mcr);
+        out.long(self.instructions);
+        out.long(self.cycles);
+
+        out.word(self.interrupts.len() as u16);
+        for irq in &self.interrupts {
+            out.word(irq.vector as u16);
+            out.word(irq.priority as u16);
+        }
+
+        out.word(self.devices.len() as u16);
+        for state in &self.devices {
+            out.words(state);
+        }
+
+        for &word in &self.memory {
+            out.word(word);
+        }
+        out.0
+    }
+
+    pub fn parse(data: &[u8]) -> Result<Snapshot, VmError> {
+        let mut input = Reader(data);
+
+        if input.take(MAGIC.len())? != MAGIC {
+            return Err(VmError::BadSnapshot("not a snapshot file"));
+        }
+        let version = input.word()?;
+        if version == 0 || version > VERSION {
+            return Err(VmError::BadSnapshot("unsupported snapshot version"));
+        }
+
+        let registers = input.words()?;
+        let saved_usp = input.word()?;
+        let saved_ssp = input.word()?;
+        let flags = input.word()?;
+        let mcr = input.word()?;
+        let instructions = input.long()?;
+        //Version 1 did not count cycles.
+        let cycles = if version >= 2 { input.long()? } else { 0 };
+
+        let mut interrupts = Vec::new();
+        for _ in 0..input.word()? {
+            let vector = input.word()? as u8;
+            let priority = input.word()? as u8;
+            interrupts.push(Interrupt { vector, priority });
+        }
+
+        let mut devices = Vec::new();
+        for _ in 0..input.word()? {
+            devices.push(input.words()?);
+        }
+
+        let mut memory = Vec::with_capacity(Memory::SIZE);
+        for _ in 0..Memory::SIZE {
+            memory.push(input.word()?);
+        }
+        if !input.0.is_empty() {
+            return Err(VmError::BadSnapshot("trailing data after the snapshot"));
+        }
+
+        Ok(Snapshot {
+            registers,
+            saved_usp,
+            saved_ssp,
+            exceptions: flags & FLAG_EXCEPTIONS != 0,
+            trap_mode: if flags & FLAG_VECTORED_TRAPS != 0 { TrapMode::Vectored } else { TrapMode::Native },
+            mcr,
+            instructions,
+            cycles,
+            interrupts,
+            devices,
+            memory,
+        })
+    }
+
+    pub fn load(path: &Path) -> Result<Snapshot, VmError> {
+        Snapshot::parse(&fs::read(path)?)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), VmError> {
+        fs::write(path, self.to_bytes())?;
+        Ok(())
+    }
+}
+
+
+struct Writer(Vec<u8>);
+
+impl Writer {
+    fn word(&mut self, word: u16) {
+        self.0.extend_from_slice(&word.to_be_bytes());
+    }
+
+    fn long(&mut self, long: u64) {
+        self.0.extend_from_slice(&long.to_be_bytes());
+    }
+
+    //A count, then the words.
+    fn words(&mut self, words: &[u16]) {
+        self.word(words.len() as u16);
+        for &word in words {
+            self.word(word);
+        }
+    }
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], VmError> {
+        if self.0.len() < len {
+            return Err(VmError::BadSnapshot("snapshot is truncated"));
+        }
+        let (bytes, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(bytes)
+    }
+
+    fn word(&mut self) -> Result<u16, VmError> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn long(&mut self) -> Result<u64, VmError> {
+        let mut bytes = [0u8; 8];
+        bytes.copy_from_slice(self.take(8)?);
+        Ok(u64::from_be_bytes(bytes))
+    }
+
+    fn words(&mut self) -> Result<Vec<u16>, VmError> {
+        let len = self.word()?;
+        (0..len).map(|_| self.word()).collect()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interrupt::Interrupt;
+
+    fn snapshot() -> Snapshot {
+        let mut memory = vec![0; Memory::SIZE];
+        memory[0x3000] = 0xF025;
+        memory[0xFFFF] = 0x1234;
+
+        Snapshot {
+            registers: vec![1, 2, 3, 4, 5, 6, 0x2FFE, 0x3010, 0x3001, 0x8004],
+            saved_usp: 0xFE00,
+            saved_ssp: 0x3000,
+            exceptions: true,
+            trap_mode: TrapMode::Vectored,
+            mcr: 0x8000,
+            instructions: 1 << 40,
+            cycles: (1 << 41) + 7,
+            interrupts: vec![Interrupt { vector: 0x81, priority: 5 }],
+            devices: vec![vec![0x8000, 0x0061], vec![], vec![0x4000, 10, 3]],
+            memory,
+        }
+    }
+
+    #[test]
+    fn bytes_parse_back_to_the_same_snapshot() {
+        let snapshot = snapshot();
+        assert_eq!(Snapshot::parse(&snapshot.to_bytes()).unwrap(), snapshot);
+    }
+
+    #[test]
+    fn saves_and_loads() {
+        let path = std::env::temp_dir().join(format!("rust_vm_snapshot_test_{}.snap", std::process::id()));
+        let snapshot = snapshot();
+        snapshot.save(&path).unwrap();
+        let loaded = Snapshot::load(&path);
+        let _ = fs::remove_file(&path);
+        assert_eq!(loaded.unwrap(), snapshot);
+    }
+
+    #[test]
+    fn rejects_other_files() {
+        let mut bytes = snapshot().to_bytes();
+        assert!(Snapshot::parse(&bytes[..100]).is_err());
+        bytes[0] = b'X';
+        assert!(Snapshot::parse(&bytes).is_err());
+    }
+}
@@ -0,0 +1,230 @@
+//Symbol tables, mapping labels to addresses, and the .sym files LC3
+//toolchains write them to next to the .obj image. Ours look like the
+//ones lc3as writes:
+//
+//  // Symbol table
+//  // Scope level 0:
+//  //	Symbol Name       Page Address
+//  //	----------------  ------------
+//  //	START             3000
+//  //	LOOP              3004
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::asm::parse_number;
+
+
+//An address further than this past the closest label is not described
+//relative to it, it most likely belongs to something else entirely.
+const MAX_DESCRIBE_OFFSET: u16 = 0x400;
+
+#[derive(Clone, Default)]
+pub struct SymbolTable {
+    by_name: HashMap<String, u16>,
+    //Every address can only carry one name, the first one given to it.
+    by_addr: BTreeMap<u16, String>,
+}
+
+impl SymbolTable {
+    pub fn new() -> SymbolTable {
+        SymbolTable::default()
+    }
+
+    pub fn insert(&mut self, name: &str, addr: u16) {
+        if let Some(old) = self.by_name.insert(name.to_string(), addr) {
+            if self.by_addr.get(&old).map(String::as_str) == Some(name) {
+                self.by_addr.remove(&old);
+            }
+        }
+        self.by_addr.entry(addr).or_insert_with(|| name.to_string());
+    }
+
+    //Adds every symbol of `other`, replacing the ones with the same name.
+    pub fn extend(&mut self, other: &SymbolTable) {
+        for (name, addr) in other.iter() {
+            self.insert(name, addr);
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<u16> {
+        self.by_name.get(name).copied()
+    }
+
+    //The label at exactly `addr`.
+    pub fn name_at(&self, addr: u16) -> Option<&str> {
+        self.by_addr.get(&addr).map(String::as_str)
+    }
+
+    //`addr` as LABEL or LABEL+offset, using the closest label at or
+    //before it.
+    pub fn describe(&self, addr: u16) -> Option<String> {
+        let (&base, name) = self.by_addr.range(..=addr).next_back()?;
+        match addr - base {
+            0 => Some(name.clone()),
+            offset if offset <= MAX_DESCRIBE_OFFSET => Some(format!("{}+{}", name, offset)),
+            _ => None,
+        }
+    }
+
+    //`addr` the way messages should show it: x3005 (LOOP+1), or just
+    //x3005 if no label is close enough.
+    pub fn annotate(&self, addr: u16) -> String {
+        match self.describe(addr) {
+            Some(name) => format!("x{:04X} ({})", addr, name),
+            None => format!("x{:04X}", addr),
+        }
+    }
+
+    //Turns what a user typed for an address into one: a number in any of
+    //the assembler's notations (x3000, #12288, 0x3000), a label, or a
+    //label plus or minus an offset (LOOP+2).
+    pub fn resolve(&self, text: &str) -> Result<u16, String> {
+        if let Some(value) = parse_number(text) {
+            if !(-0x8000..=0xFFFF).contains(&value) {
+                return Err(format!("{} does not fit in 16 bits", text));
+            }
+            return Ok(value as u16);
+        }
+
+        let (name, offset) = match text.find(['+', '-']) {
+            Some(at) => {
+                let offset = parse_number(&text[at + 1..])
+                    .ok_or_else(|| format!("Bad offset in `{}`", text))?;
+                let offset = if text.as_bytes()[at] == b'-' { -offset } else { offset };
+                (&text[..at], offset)
+            },
+            None => (text, 0),
+        };
+
+        match self.get(name) {
+            Some(addr) => Ok((addr as i32 + offset) as u16),
+            None => Err(format!("No symbol `{}` (and it is not an address either)", name)),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.by_name.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_name.is_empty()
+    }
+
+    //All symbols, ordered by address.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, u16)> + '_ {
+        let mut symbols: Vec<_> = self.by_name.iter().map(|(name, &addr)| (name.as_str(), addr)).collect();
+        symbols.sort_by_key(|&(name, addr)| (addr, name));
+        symbols.into_iter()
+    }
+
+    //Reads a .sym file. Lines that are not a symbol (the headers, or
+    //anything else) are skipped.
+    pub fn parse(text: &str) -> SymbolTable {
+        let mut table = SymbolTable::new();
+
+        for line in text.lines() {
+            let line = line.trim_start_matches('/');
+            let mut fields = line.split_whitespace();
+            let (name, addr) = match (fields.next(), fields.next(), fields.next()) {
+                (Some(name), Some(addr), None) => (name, addr),
+                _ => continue,
+            };
+
+            let addr = addr.trim_start_matches(['x', 'X']);
+            if let Ok(addr) = u16::from_str_radix(addr, 16) {
+                table.insert(name, addr);
+            }
+        }
+
+        table
+    }
+
+    pub fn to_sym_string(&self) -> String {
+        let mut text = String::new();
+        text.push_str("// Symbol table\n");
+        text.push_str("// Scope level 0:\n");
+        text.push_str("//\tSymbol Name       Page Address\n");
+        text.push_str("//\t----------------  ------------\n");
+        for (name, addr) in self.iter() {
+            text.push_str(&format!("//\t{:<16}  {:04X}\n", name, addr));
+        }
+        text.push('\n');
+        text
+    }
+
+    pub fn load(path: &Path) -> io::Result<SymbolTable> {
+        Ok(SymbolTable::parse(&fs::read_to_string(path)?))
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        fs::write(path, self.to_sym_string())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn table() -> SymbolTable {
+        let mut table = SymbolTable::new();
+        table.insert("START", 0x3000);
+        table.insert("LOOP", 0x3004);
+        table.insert("DATA", 0x3010);
+        table
+    }
+
+    #[test]
+    fn sym_files_read_back_the_same() {
+        let text = table().to_sym_string();
+        assert_eq!(text, "\
+// Symbol table
+// Scope level 0:
+//\tSymbol Name       Page Address
+//\t----------------  ------------
+//\tSTART             3000
+//\tLOOP              3004
+//\tDATA              3010
+
+");
+        let parsed = SymbolTable::parse(&text);
+        assert_eq!(parsed.iter().collect::<Vec<_>>(), table().iter().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn the_first_name_of_an_address_sticks() {
+        let table = SymbolTable::parse("//\tLOOP  x3004\n//\tAGAIN 3004\n//\tnot a symbol line\n");
+        assert_eq!(table.len(), 2);
+        assert_eq!(table.get("AGAIN"), Some(0x3004));
+        assert_eq!(table.name_at(0x3004), Some("LOOP"));
+    }
+
+    #[test]
+    fn resolves_numbers_labels_and_offsets() {
+        let table = table();
+        assert_eq!(table.resolve("x3000"), Ok(0x3000));
+        assert_eq!(table.resolve("#12289"), Ok(0x3001));
+        assert_eq!(table.resolve("LOOP"), Ok(0x3004));
+        assert_eq!(table.resolve("LOOP+2"), Ok(0x3006));
+        assert_eq!(table.resolve("LOOP+x10"), Ok(0x3014));
+        assert_eq!(table.resolve("LOOP-1"), Ok(0x3003));
+        assert!(table.resolve("LOOP+").is_err());
+        assert!(table.resolve("NOWHERE").is_err());
+        assert!(table.resolve("x10000").is_err());
+    }
+
+    #[test]
+    fn describes_addresses_by_the_closest_label() {
+        let table = table();
+        assert_eq!(table.describe(0x3004).as_deref(), Some("LOOP"));
+        assert_eq!(table.describe(0x3007).as_deref(), Some("LOOP+3"));
+        assert_eq!(table.describe(0x2FFF), None);
+        assert_eq!(table.describe(0x3010 + MAX_DESCRIBE_OFFSET).as_deref(), Some("DATA+1024"));
+        assert_eq!(table.describe(0x3011 + MAX_DESCRIBE_OFFSET), None);
+        assert_eq!(table.annotate(0x3005), "x3005 (LOOP+1)");
+        assert_eq!(table.annotate(0x0200), "x0200");
+    }
+}
@@ -0,0 +1,88 @@
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use termios::*;
+
+
+//Platform Specifics (Unix here)
+//Setting terminal input/output behaviour such as accepting
+//character without the need for a newline character
+//Refer: https://stackoverflow.com/questions/26321592/how-can-i-read-one-character-from-stdin-without-having-to-hit-enter
+//The terminal goes back to how it was when this is dropped, so the
+//debugger can hand out line-buffered input at its prompt in between
+//running the program.
+pub struct RawMode {
+    original: Termios,
+}
+
+const STDIN: i32 = 0;
+
+impl RawMode {
+    //Fails if stdin is not a terminal.
+    pub fn enable() -> io::Result<RawMode> {
+        let original = Termios::from_fd(STDIN)?;
+
+        let mut raw = original; // make a mutable copy of termios
+        // that we will modify
+        raw.c_iflag &= IGNBRK | BRKINT | PARMRK | ISTRIP | INLCR | IGNCR | ICRNL | IXON;
+        raw.c_lflag &= !(ICANON | ECHO); // no echo and canonical mode
+        tcsetattr(STDIN, TCSANOW, &raw)?;
+
+        Ok(RawMode { original })
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        // reset the stdin to original termios data
+        let _ = tcsetattr(STDIN, TCSANOW, &self.original);
+    }
+}
+
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_sigint(_signal: libc::c_int) {
+    INTERRUPTED.store(true, Ordering::SeqCst);
+}
+
+//The flag Ctrl-C raises while a CatchInterrupt is alive, for the console
+//to give up waiting for a key.
+pub fn interrupt_flag() -> &'static AtomicBool {
+    &INTERRUPTED
+}
+
+//While this is alive, Ctrl-C does not kill the process but is noted for
+//interrupted() to report. Lets the debugger stop a running program. The
+//handler goes in without SA_RESTART, so that a program waiting for a key
+//is interrupted too.
+pub struct CatchInterrupt {
+    previous: libc::sigaction,
+}
+
+impl CatchInterrupt {
+    pub fn new() -> CatchInterrupt {
+        INTERRUPTED.store(false, Ordering::SeqCst);
+        unsafe {
+            let mut action: libc::sigaction = std::mem::zeroed();
+            action.sa_sigaction = on_sigint as extern "C" fn(libc::c_int) as libc::sighandler_t;
+            libc::sigemptyset(&mut action.sa_mask);
+            let mut previous: libc::sigaction = std::mem::zeroed();
+            libc::sigaction(libc::SIGINT, &action, &mut previous);
+            CatchInterrupt { previous }
+        }
+    }
+
+    //Whether Ctrl-C was pressed since the last call.
+    pub fn interrupted(&self) -> bool {
+        INTERRUPTED.swap(false, Ordering::SeqCst)
+    }
+}
+
+impl Drop for CatchInterrupt {
+    fn drop(&mut self) {
+        unsafe {
+            libc::sigaction(libc::SIGINT, &self.previous, std::ptr::null_mut());
+        }
+    }
+}
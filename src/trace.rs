@@ -0,0 +1,372 @@
+//Execution traces. With a tracer attached the VM describes every
+//instruction it executes in a TraceRecord, and TraceWriter writes those
+//out as JSON Lines for tools to pick apart, or in a compact binary form
+//for long runs.
+
+use std::convert::TryFrom;
+use std::fmt::Write as _;
+use std::io::{self, BufWriter, Write};
+use std::ops::RangeInclusive;
+
+use crate::PSR_COND;
+use crate::disasm::disassemble;
+use crate::opcodes::OpCodes;
+use crate::symbols::SymbolTable;
+
+
+//A memory access made by an instruction (the instruction fetch itself
+//is not included).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MemAccess {
+    pub write: bool,
+    pub addr: u16,
+    pub val: u16,
+}
+
+//One executed instruction.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TraceRecord {
+    //How many cycles the instructions before this one took.
+    pub cycle: u64,
+    pub pc: u16,
+    pub instr: u16,
+    //General purpose registers (0-7) whose value changed, with the new value.
+    pub reg_writes: Vec<(u8, u16)>,
+    pub accesses: Vec<MemAccess>,
+    //The PSR after the instruction, the condition flags are its low bits.
+    pub psr: u16,
+}
+
+impl TraceRecord {
+    //The condition flags as "N", "Z" or "P" (or "" when none is set, as
+    //at startup).
+    pub fn flags(&self) -> &'static str {
+        match self.psr & PSR_COND {
+            0x4 => "N",
+            0x2 => "Z",
+            0x1 => "P",
+            _ => "",
+        }
+    }
+}
+
+//Whoever gets the records of a traced VM.
+pub trait Tracer {
+    fn record(&mut self, record: &TraceRecord) -> io::Result<()>;
+
+    //Called once the program is done, to flush what is buffered.
+    fn finish(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+
+//Rough groups of opcodes, for filtering traces.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OpClass {
+    //ADD, AND, NOT
+    Alu,
+    //LD, LDI, LDR, LEA
+    Load,
+    //ST, STI, STR
+    Store,
+    //BR, JMP/RET, JSR/JSRR, RTI
+    Control,
+    Trap,
+    //RES
+    Illegal,
+}
+
+impl OpClass {
+    pub fn of(instr: u16) -> OpClass {
+        match instr >> 12 {
+            op if op == OpCodes::OP_ADD as u16 || op == OpCodes::OP_AND as u16 || op == OpCodes::OP_NOT as u16 => {
+                OpClass::Alu
+            },
+            op if op == OpCodes::OP_LD as u16 || op == OpCodes::OP_LDI as u16
+                || op == OpCodes::OP_LDR as u16 || op == OpCodes::OP_LEA as u16 => OpClass::Load,
+            op if op == OpCodes::OP_ST as u16 || op == OpCodes::OP_STI as u16 || op == OpCodes::OP_STR as u16 => {
+                OpClass::Store
+            },
+            op if op == OpCodes::OP_TRAP as u16 => OpClass::Trap,
+            op if op == OpCodes::OP_RES as u16 => OpClass::Illegal,
+            _ => OpClass::Control,
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<OpClass> {
+        let class = match name {
+            "alu" => OpClass::Alu,
+            "load" => OpClass::Load,
+            "store" => OpClass::Store,
+            "control" => OpClass::Control,
+            "trap" => OpClass::Trap,
+            "illegal" => OpClass::Illegal,
+            _ => return None,
+        };
+        Some(class)
+    }
+}
+
+
+//Which records make it into the trace. Empty means everything.
+#[derive(Clone, Debug, Default)]
+pub struct TraceFilter {
+    pub pc_ranges: Vec<RangeInclusive<u16>>,
+    pub classes: Vec<OpClass>,
+}
+
+impl TraceFilter {
+    pub fn matches(&self, record: &TraceRecord) -> bool {
+        (self.pc_ranges.is_empty() || self.pc_ranges.iter().any(|range| range.contains(&record.pc)))
+            && (self.classes.is_empty() || self.classes.contains(&OpClass::of(record.instr)))
+    }
+}
+
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TraceFormat {
+    //One JSON object per line:
+    //{"cycle":0,"pc":12288,"instr":57354,"asm":"LEA R0, MSG","regs":{"R0":12299},
+    // "mem":[{"op":"r","addr":12299,"val":72}],"psr":1,"flags":"P"}
+    JsonLines,
+    //BINARY_MAGIC, then per record, all big-endian like .obj images:
+    //cycle u64, pc u16, instr u16, psr u16,
+    //register write count u16, then per write: register u8, value u16,
+    //access count u16, then per access: 0 (read) or 1 (write) u8, addr u16, value u16.
+    Binary,
+}
+
+pub const BINARY_MAGIC: &[u8; 8] = b"LC3TRC01";
+
+pub struct TraceWriter<W: Write> {
+    out: BufWriter<W>,
+    format: TraceFormat,
+    filter: TraceFilter,
+    //For the "asm" field of JSON records.
+    symbols: SymbolTable,
+}
+
+impl<W: Write> TraceWriter<W> {
+    pub fn new(out: W, format: TraceFormat, filter: TraceFilter, symbols: SymbolTable) -> io::Result<TraceWriter<W>> {
+        let mut out = BufWriter::new(out);
+        if format == TraceFormat::Binary {
+            out.write_all(BINARY_MAGIC)?;
+        }
+        Ok(TraceWriter { out, format, filter, symbols })
+    }
+
+    fn write_json(&mut self, record: &TraceRecord) -> io::Result<()> {
+        let mut line = String::new();
+        let asm = disassemble(record.instr, record.pc, &self.symbols);
+        //Writing to a String cannot fail.
+        let _ = write!(
+            line,
+            "{{\"cycle\":{},\"pc\":{},\"instr\":{},\"asm\":\"{}\",\"regs\":{{",
+            record.cycle, record.pc, record.instr, json_escape(&asm)
+        );
+        for (i, (reg, val)) in record.reg_writes.iter().enumerate() {
+            let _ = write!(line, "{}\"R{}\":{}", if i > 0 { "," } else { "" }, reg, val);
+        }
+        line.push_str("},\"mem\":[");
+        for (i, access) in record.accesses.iter().enumerate() {
+            let _ = write!(
+                line,
+                "{}{{\"op\":\"{}\",\"addr\":{},\"val\":{}}}",
+                if i > 0 { "," } else { "" }, if access.write { "w" } else { "r" }, access.addr, access.val
+            );
+        }
+        let _ = writeln!(line, "],\"psr\":{},\"flags\":\"{}\"}}", record.psr, record.flags());
+
+        self.out.write_all(line.as_bytes())
+    }
+
+    fn write_binary(&mut self, record: &TraceRecord) -> io::Result<()> {
+        let mut bytes = Vec::with_capacity(32);
+        bytes.extend_from_slice(&record.cycle.to_be_bytes());
+        bytes.extend_from_slice(&record.pc.to_be_bytes());
+        bytes.extend_from_slice(&record.instr.to_be_bytes());
+        bytes.extend_from_slice(&record.psr.to_be_bytes());
+
+        //A native PUTS reads its whole string, so the accesses of one
+        //instruction can run into the thousands.
+        bytes.extend_from_slice(&count(record.reg_writes.len())?);
+        for &(reg, val) in &record.reg_writes {
+            bytes.push(reg);
+            bytes.extend_from_slice(&val.to_be_bytes());
+        }
+        bytes.extend_from_slice(&count(record.accesses.len())?);
+        for access in &record.accesses {
+            bytes.push(access.write as u8);
+            bytes.extend_from_slice(&access.addr.to_be_bytes());
+            bytes.extend_from_slice(&access.val.to_be_bytes());
+        }
+
+        self.out.write_all(&bytes)
+    }
+}
+
+impl<W: Write> Tracer for TraceWriter<W> {
+    fn record(&mut self, record: &TraceRecord) -> io::Result<()> {
+        if !self.filter.matches(record) {
+            return Ok(());
+        }
+        match self.format {
+            TraceFormat::JsonLines => self.write_json(record),
+            TraceFormat::Binary => self.write_binary(record),
+        }
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+//A count in a binary record. Only a string running through nearly all of
+//memory makes for more accesses than that can hold.
+fn count(len: usize) -> io::Result<[u8; 2]> {
+    match u16::try_from(len) {
+        Ok(len) => Ok(len.to_be_bytes()),
+        Err(_) => Err(io::Error::new(io::ErrorKind::InvalidData, "too many memory accesses to trace")),
+    }
+}
+
+fn json_escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(escaped, "\\u{:04x}", c as u32);
+            },
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::convert::TryInto;
+    use std::rc::Rc;
+
+    use super::*;
+    use crate::{Vm, StopReason};
+    use crate::asm::assemble;
+    use crate::console::BufferConsole;
+
+    //Prints a 300 character string, so the PUTS reads 301 words.
+    const LONG_PUTS: &str = "
+            .ORIG x3000
+            LEA R0, TEXT
+            PUTS
+            HALT
+    TEXT    .BLKW #300 x41
+            .FILL 0
+            .END
+    ";
+
+    //A trace file the test can look at while the VM still has the writer.
+    #[derive(Clone, Default)]
+    struct Shared(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    //Keeps the records themselves, next to the writer under test.
+    struct Tee {
+        records: Rc<RefCell<Vec<TraceRecord>>>,
+        writer: TraceWriter<Shared>,
+    }
+
+    impl Tracer for Tee {
+        fn record(&mut self, record: &TraceRecord) -> io::Result<()> {
+            self.records.borrow_mut().push(record.clone());
+            self.writer.record(record)
+        }
+
+        fn finish(&mut self) -> io::Result<()> {
+            self.writer.finish()
+        }
+    }
+
+    //Runs LONG_PUTS traced in `format`, returning the records and the file.
+    fn trace(format: TraceFormat) -> (Vec<TraceRecord>, Vec<u8>) {
+        let program = assemble(LONG_PUTS).unwrap();
+        let mut vm = Vm::with_console(Box::new(BufferConsole::new(b"")));
+        vm.memory_mut()[0x3000..0x3000 + program.words.len()].copy_from_slice(&program.words);
+        vm.set_pc(0x3000);
+
+        let file = Shared::default();
+        let records = Rc::new(RefCell::new(Vec::new()));
+        let writer = TraceWriter::new(file.clone(), format, TraceFilter::default(), SymbolTable::default()).unwrap();
+        vm.set_tracer(Box::new(Tee { records: records.clone(), writer }));
+        assert_eq!(vm.run().unwrap(), StopReason::Halted);
+        vm.take_tracer().unwrap().finish().unwrap();
+
+        let records = records.borrow().clone();
+        let bytes = file.0.borrow().clone();
+        (records, bytes)
+    }
+
+    //Reads back what write_binary wrote.
+    fn read_binary(mut bytes: &[u8]) -> Vec<TraceRecord> {
+        fn take<const N: usize>(bytes: &mut &[u8]) -> [u8; N] {
+            let (head, tail) = bytes.split_at(N);
+            *bytes = tail;
+            head.try_into().unwrap()
+        }
+        fn word(bytes: &mut &[u8]) -> u16 {
+            u16::from_be_bytes(take(bytes))
+        }
+
+        assert_eq!(take::<8>(&mut bytes), *BINARY_MAGIC);
+        let mut records = Vec::new();
+        while !bytes.is_empty() {
+            let cycle = u64::from_be_bytes(take(&mut bytes));
+            let (pc, instr, psr) = (word(&mut bytes), word(&mut bytes), word(&mut bytes));
+            let reg_writes = (0..word(&mut bytes)).map(|_| (take::<1>(&mut bytes)[0], word(&mut bytes))).collect();
+            let accesses = (0..word(&mut bytes))
+                .map(|_| MemAccess { write: take::<1>(&mut bytes)[0] == 1, addr: word(&mut bytes), val: word(&mut bytes) })
+                .collect();
+            records.push(TraceRecord { cycle, pc, instr, reg_writes, accesses, psr });
+        }
+        records
+    }
+
+    #[test]
+    fn binary_traces_read_back_the_same() {
+        let (records, bytes) = trace(TraceFormat::Binary);
+        assert_eq!(records.len(), 3);
+        assert_eq!(records[1].accesses.len(), 301);
+        assert_eq!(read_binary(&bytes), records);
+    }
+
+    #[test]
+    fn json_traces_have_a_line_per_instruction() {
+        let (records, bytes) = trace(TraceFormat::JsonLines);
+        let text = String::from_utf8(bytes).unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines.len(), records.len());
+
+        assert_eq!(
+            lines[0],
+            "{\"cycle\":0,\"pc\":12288,\"instr\":57346,\"asm\":\"LEA R0, x3003\",\"regs\":{\"R0\":12291},\
+             \"mem\":[],\"psr\":1,\"flags\":\"P\"}"
+        );
+        assert_eq!(lines[1].matches("{\"op\":\"r\",").count(), 301);
+        assert!(lines[1].contains("{\"op\":\"r\",\"addr\":12291,\"val\":65},"));
+        assert!(lines[1].ends_with("{\"op\":\"r\",\"addr\":12591,\"val\":0}],\"psr\":1,\"flags\":\"P\"}"));
+    }
+}
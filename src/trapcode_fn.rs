@@ -1,66 +1,81 @@
 // Programs generally start at address 0x3000 only because
 // the lower address are left empty for trap routine codes.
 
-use std::io::Read;
-use rust_vm::register::Reg;
+use std::io;
 
-// perhaps take a slice for memory?
-pub fn trap_puts(reg: &mut Vec<u16>, memory: &mut Vec<u16>) {
+use crate::{Memory, mem_read};
+use crate::console::Console;
+use crate::register::Reg;
+
+//The string routines read through mem_read(), like a guest routine would,
+//so that watchpoints and traces see what they read.
+pub fn trap_puts(reg: &mut [u16], memory: &mut Memory) -> io::Result<()> {
     let mut index: usize = reg[Reg::R0] as usize;
 
-    while index < memory.len() && memory[index] != 0 {
+    while index < Memory::SIZE {
+        let word = mem_read(index as u16, memory);
+        if word == 0 {
+            break;
+        }
         //the `as` cast truncates the upper 8 bits while going
         //from u16 -> u8
-        print!("{}", (memory[index] as u8) as char);
-        index = index + 1;
+        memory.console.write_byte(word as u8)?;
+        index += 1;
     }
+
+    memory.console.flush()
 }
 
 
-pub fn trap_getc(reg: &mut Vec<u16>) {
-    //Credits to erfur for an easy way to input only a single
-    //character from stdin: 
-    //https://github.com/erfur/lc3-vm-rust/blob/61679739c7d498dc932e34d6c74c8ba0564b18aa/src/main.rs#L257
-    let mut buffer = [0 as u8; 1];
-    std::io::stdin().read_exact(&mut buffer).unwrap();
-    reg[Reg::R0] = buffer[0].into();
+pub fn trap_getc(reg: &mut [u16], console: &mut dyn Console) -> io::Result<()> {
+    reg[Reg::R0] = console.read_byte()?.into();
+    Ok(())
 }
 
 
-pub fn trap_out(reg: &mut Vec<u16>) {
-    print!("{}", (reg[Reg::R0] as u8) as char);
+pub fn trap_out(reg: &mut [u16], console: &mut dyn Console) -> io::Result<()> {
+    console.write_byte(reg[Reg::R0] as u8)?;
+    console.flush()
 }
 
 
-pub fn trap_in(reg: &mut Vec<u16>) {
-    print!("Enter a character: ");
-    //Alternative way to get a character input
-    reg[Reg::R0] = std::io::stdin()
-        .bytes()
-        .next()
-        .and_then(|result| result.ok())
-        .map(|byte| byte as u16)
-        .unwrap();
+pub fn trap_in(reg: &mut [u16], console: &mut dyn Console) -> io::Result<()> {
+    for byte in b"Enter a character: " {
+        console.write_byte(*byte)?;
+    }
+    console.flush()?;
+
+    //Unlike GETC, IN echoes the character, as the OS routine does.
+    let byte = console.read_byte()?;
+    console.write_byte(byte)?;
+    console.flush()?;
+
+    reg[Reg::R0] = byte.into();
+    Ok(())
 }
 
 
-pub fn trap_putsp(reg: &mut Vec<u16>, memory: &mut Vec<u16>) {
+pub fn trap_putsp(reg: &mut [u16], memory: &mut Memory) -> io::Result<()> {
     let mut index: usize = reg[Reg::R0] as usize;
 
-    while index < memory.len() && memory[index] != 0 {
+    while index < Memory::SIZE {
         //A word in our VM is 16 bits
-        let word: u16 = memory[index];
+        let word: u16 = mem_read(index as u16, memory);
+        if word == 0 {
+            break;
+        }
 
         //We get the two bytes from our word. bytes here is an array of u8
         let bytes = word.to_be_bytes();
-        
-        print!("{}", bytes[1] as char);
+
+        memory.console.write_byte(bytes[1])?;
 
         if bytes[0] != 0  {
-            print!("{}", bytes[0] as char);
+            memory.console.write_byte(bytes[0])?;
         }
 
-        index = index + 1;
+        index += 1;
     }
 
+    memory.console.flush()
 }
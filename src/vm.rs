@@ -0,0 +1,1510 @@
+use std::collections::HashSet;
+use std::io;
+use std::path::Path;
+
+use crate::{Memory, TrapCode, TrapMode, Exception, VmError, StopReason, read_image, load_image_bytes, fetch, mem_read, mem_write};
+use crate::{PSR_USER, PSR_COND, PSR_PRIORITY, PSR_PRIORITY_SHIFT, INTERRUPT_VECTOR_TABLE};
+use crate::console::{Console, StdioConsole};
+use crate::cycles::CycleModel;
+use crate::decode::Instruction;
+use crate::device::Device;
+use crate::interrupt::{Interrupt, InterruptController};
+use crate::os::{load_os, TRAP_VECTOR_TABLE};
+use crate::register::Reg;
+use crate::snapshot::Snapshot;
+use crate::symbols::SymbolTable;
+use crate::trace::{Tracer, TraceRecord};
+use crate::history::{History, Undo};
+use crate::profile::Profile;
+use crate::coverage::Coverage;
+use crate::watch::{Watchpoint, WatchHit, WatchKind};
+use crate::opcode_fn::*;
+use crate::trapcode_fn::*;
+
+
+//The LC3 machine: its registers and its memory, with the devices and
+//the console behind it. Everything the fetch/decode/execute loop needs
+//lives here so that the VM can be driven from other Rust code, not just
+//from main.rs. Whether the machine is running is kept where the LC3
+//keeps it, in the clock enable bit of the MCR.
+pub struct Vm {
+    registers: Vec<u16>,
+    memory: Memory,
+    //R6 is the stack pointer. Whichever of the user and supervisor stack
+    //pointers is not in use is parked here while the other one is in R6.
+    saved_usp: u16,
+    saved_ssp: u16,
+    interrupts: InterruptController,
+    //Whether illegal opcodes, privilege mode violations and access
+    //control violations are handed to the guest's exception handlers.
+    exceptions: bool,
+    trap_mode: TrapMode,
+    breakpoints: HashSet<u16>,
+    //Labels of the loaded images, from the .sym files next to them.
+    symbols: SymbolTable,
+    //Instructions executed so far, and the cycles they took.
+    instructions: u64,
+    cycles: u64,
+    cycle_model: CycleModel,
+    tracer: Option<Box<dyn Tracer>>,
+    //What it takes to undo the last instructions, if we keep track.
+    history: Option<History>,
+    profile: Option<Profile>,
+    coverage: Option<Coverage>,
+    //Whether the last instruction ended up in an interrupt or exception
+    //handler, for the debugger and the profiler.
+    serviced: bool,
+    //Whether the last instruction, if it was a BR, branched.
+    branched: bool,
+}
+
+impl Vm {
+    pub const PC_START: u16 = 0x3000; //default starting address for PC
+
+    //The supervisor stack grows down from just below the user programs.
+    pub const SSP_START: u16 = 0x3000;
+
+    //A VM talking to the process' stdin and stdout.
+    pub fn new() -> Vm {
+        Vm::with_console(Box::new(StdioConsole::new()))
+    }
+
+    pub fn with_console(console: Box<dyn Console>) -> Vm {
+        //memory of the computer.
+        let memory = Memory::new(console);
+
+        //LC3 register
+        let mut registers: Vec<u16> = vec![0; Reg::COUNT as usize];
+        registers[Reg::PC] = Vm::PC_START; //Default starting address
+
+        //We start out in supervisor mode, which lets plain programs like
+        //rogue.obj poke at the device registers. An operating system can
+        //drop to user mode with RTI. Until then R6 is the supervisor stack
+        //pointer that traps and interrupts push onto.
+        registers[Reg::R6] = Vm::SSP_START;
+        Vm {
+            registers,
+            memory,
+            saved_usp: 0,
+            saved_ssp: Vm::SSP_START,
+            interrupts: InterruptController::new(),
+            exceptions: true,
+            trap_mode: TrapMode::Native,
+            breakpoints: HashSet::new(),
+            symbols: SymbolTable::new(),
+            instructions: 0,
+            cycles: 0,
+            cycle_model: CycleModel::new(),
+            tracer: None,
+            history: None,
+            profile: None,
+            coverage: None,
+            serviced: false,
+            branched: false,
+        }
+    }
+
+    //Loads an image file into memory at the origin stored in its first word.
+    //If there is a .sym file next to it (2048.sym for 2048.obj), its
+    //symbols are loaded as well.
+    pub fn load_image(&mut self, image: &str) -> Result<(), VmError> {
+        read_image(image, self.memory.words_mut())?;
+
+        let sym = Path::new(image).with_extension("sym");
+        if sym.is_file() {
+            self.load_symbols(&sym)?;
+        }
+        Ok(())
+    }
+
+    pub fn load_symbols(&mut self, path: &Path) -> Result<(), VmError> {
+        let symbols = SymbolTable::load(path)?;
+        self.symbols.extend(&symbols);
+        Ok(())
+    }
+
+    pub fn symbols(&self) -> &SymbolTable {
+        &self.symbols
+    }
+
+    pub fn symbols_mut(&mut self) -> &mut SymbolTable {
+        &mut self.symbols
+    }
+
+    //`addr` the way messages about it should show it: x3005 (LOOP+1),
+    //or just x3005 if no label is close enough.
+    pub fn describe_addr(&self, addr: u16) -> String {
+        self.symbols.annotate(addr)
+    }
+
+    //Loads an image that is already in memory, in the same format as a file.
+    pub fn load_image_bytes(&mut self, data: &[u8]) -> Result<(), VmError> {
+        load_image_bytes(data, self.memory.words_mut())
+    }
+
+    pub fn reg(&self, reg: Reg) -> u16 {
+        self.registers[reg]
+    }
+
+    pub fn set_reg(&mut self, reg: Reg, val: u16) {
+        self.registers[reg] = val;
+    }
+
+    pub fn registers(&self) -> &[u16] {
+        &self.registers
+    }
+
+    pub fn pc(&self) -> u16 {
+        self.registers[Reg::PC]
+    }
+
+    pub fn set_pc(&mut self, pc: u16) {
+        self.registers[Reg::PC] = pc;
+    }
+
+    //Just the N/Z/P bits of the PSR.
+    pub fn cond(&self) -> u16 {
+        self.registers[Reg::PSR] & PSR_COND
+    }
+
+    pub fn psr(&self) -> u16 {
+        self.registers[Reg::PSR]
+    }
+
+    pub fn set_psr(&mut self, psr: u16) {
+        self.registers[Reg::PSR] = psr;
+    }
+
+    pub fn is_user_mode(&self) -> bool {
+        self.registers[Reg::PSR] & PSR_USER != 0
+    }
+
+    pub fn saved_usp(&self) -> u16 {
+        self.saved_usp
+    }
+
+    pub fn set_saved_usp(&mut self, usp: u16) {
+        self.saved_usp = usp;
+    }
+
+    pub fn saved_ssp(&self) -> u16 {
+        self.saved_ssp
+    }
+
+    pub fn set_saved_ssp(&mut self, ssp: u16) {
+        self.saved_ssp = ssp;
+    }
+
+    pub fn memory(&self) -> &[u16] {
+        self.memory.words()
+    }
+
+    //Changing memory behind the program's back would leave the history
+    //undoing writes over values it does not know about, so it is forgotten.
+    pub fn memory_mut(&mut self) -> &mut [u16] {
+        self.clear_history();
+        self.memory.words_mut()
+    }
+
+    pub fn console_mut(&mut self) -> &mut dyn Console {
+        self.memory.console_mut()
+    }
+
+    //Puts a device on the bus next to the keyboard and display.
+    pub fn attach_device(&mut self, device: Box<dyn Device>) -> Result<(), VmError> {
+        self.memory.bus_mut().attach(device)
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.memory.bus.clock_enabled()
+    }
+
+    //Lets a halted machine execute instructions again.
+    pub fn resume(&mut self) {
+        self.memory.bus.set_clock_enabled(true);
+    }
+
+    //Current priority level, PSR[10:8].
+    pub fn priority(&self) -> u8 {
+        ((self.registers[Reg::PSR] & PSR_PRIORITY) >> PSR_PRIORITY_SHIFT) as u8
+    }
+
+    //Asks for the service routine at `vector` to run at PL `priority`. It
+    //will, after the current instruction, once the running program's
+    //priority is lower than `priority`.
+    pub fn raise_interrupt(&mut self, vector: u8, priority: u8) {
+        self.interrupts.raise(Interrupt { vector, priority: priority & 0x7 });
+    }
+
+    //Interrupts raised with raise_interrupt() that have not run yet.
+    pub fn pending_interrupts(&self) -> &[Interrupt] {
+        self.interrupts.pending()
+    }
+
+    //With exceptions turned on (the default), an illegal opcode, RTI in
+    //user mode or user code touching system space runs the handler the
+    //guest installed at 0x0100/0x0101/0x0102. When there is no handler,
+    //or exceptions are turned off, the VM stops with the matching
+    //VmError instead. Turning them on also lets unknown traps go through
+    //the trap vector table when the guest installed a routine there.
+    pub fn set_exceptions(&mut self, enabled: bool) {
+        self.exceptions = enabled;
+    }
+
+    pub fn exceptions_enabled(&self) -> bool {
+        self.exceptions
+    }
+
+    pub fn set_trap_mode(&mut self, mode: TrapMode) {
+        self.trap_mode = mode;
+    }
+
+    pub fn trap_mode(&self) -> TrapMode {
+        self.trap_mode
+    }
+
+    //Loads the built-in OS and its trap vector table, for use with
+    //TrapMode::Vectored. Images loaded afterwards may replace entries in
+    //the table with their own trap routines.
+    pub fn load_os(&mut self) {
+        load_os(self.memory.words_mut());
+    }
+
+    //Returns false if there already was a breakpoint at `addr`.
+    pub fn add_breakpoint(&mut self, addr: u16) -> bool {
+        self.breakpoints.insert(addr)
+    }
+
+    //Returns false if there was no breakpoint at `addr`.
+    pub fn remove_breakpoint(&mut self, addr: u16) -> bool {
+        self.breakpoints.remove(&addr)
+    }
+
+    pub fn has_breakpoint(&self, addr: u16) -> bool {
+        self.breakpoints.contains(&addr)
+    }
+
+    pub fn breakpoints(&self) -> impl Iterator<Item = u16> + '_ {
+        self.breakpoints.iter().copied()
+    }
+
+    pub fn add_watchpoint(&mut self, watchpoint: Watchpoint) {
+        self.memory.watchpoints.push(watchpoint);
+    }
+
+    //Removes the watchpoint at `index` in watchpoints().
+    pub fn remove_watchpoint(&mut self, index: usize) -> Option<Watchpoint> {
+        if index < self.memory.watchpoints.len() {
+            return Some(self.memory.watchpoints.remove(index));
+        }
+        None
+    }
+
+    pub fn watchpoints(&self) -> &[Watchpoint] {
+        &self.memory.watchpoints
+    }
+
+    //The watched accesses made since the last call.
+    pub fn take_watch_hits(&mut self) -> Vec<WatchHit> {
+        std::mem::take(&mut self.memory.watch_hits)
+    }
+
+    //The complete state of the machine.
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            registers: self.registers.clone(),
+            saved_usp: self.saved_usp,
+            saved_ssp: self.saved_ssp,
+            exceptions: self.exceptions,
+            trap_mode: self.trap_mode,
+            mcr: self.memory.bus.mcr(),
+            instructions: self.instructions,
+            cycles: self.cycles,
+            interrupts: self.interrupts.pending().to_vec(),
+            devices: self.memory.bus.save_state(),
+            memory: self.memory.words.clone(),
+        }
+    }
+
+    //Puts the machine back in the state of `snapshot`. The devices must
+    //be the same ones that were attached when it was taken.
+    pub fn restore(&mut self, snapshot: &Snapshot) -> Result<(), VmError> {
+        if snapshot.registers.len() != self.registers.len() || snapshot.memory.len() != Memory::SIZE {
+            return Err(VmError::BadSnapshot("the snapshot is of a different machine"));
+        }
+        self.memory.bus.load_state(&snapshot.devices)?;
+
+        self.registers.copy_from_slice(&snapshot.registers);
+        self.saved_usp = snapshot.saved_usp;
+        self.saved_ssp = snapshot.saved_ssp;
+        self.exceptions = snapshot.exceptions;
+        self.trap_mode = snapshot.trap_mode;
+        self.memory.bus.set_mcr(snapshot.mcr);
+        self.instructions = snapshot.instructions;
+        self.cycles = snapshot.cycles;
+        self.interrupts.clear();
+        for &irq in &snapshot.interrupts {
+            self.interrupts.raise(irq);
+        }
+        self.memory.words_mut().copy_from_slice(&snapshot.memory);
+        self.clear_history();
+        Ok(())
+    }
+
+    pub fn save_snapshot(&self, path: &Path) -> Result<(), VmError> {
+        self.snapshot().save(path)
+    }
+
+    pub fn load_snapshot(&mut self, path: &Path) -> Result<(), VmError> {
+        self.restore(&Snapshot::load(path)?)
+    }
+
+    //How many instructions have been executed.
+    pub fn instruction_count(&self) -> u64 {
+        self.instructions
+    }
+
+    //How many cycles the instructions executed so far took, according
+    //to the cycle model. Trace records carry this as their cycle number.
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    //Whether the last step went into an interrupt or exception handler,
+    //after the instruction it executed.
+    pub fn serviced(&self) -> bool {
+        self.serviced
+    }
+
+    pub fn set_cycle_model(&mut self, model: CycleModel) {
+        self.cycle_model = model;
+    }
+
+    pub fn cycle_model(&self) -> &CycleModel {
+        &self.cycle_model
+    }
+
+    //Hands every instruction executed from now on to `tracer`.
+    pub fn set_tracer(&mut self, tracer: Box<dyn Tracer>) {
+        self.tracer = Some(tracer);
+    }
+
+    //Starts counting where the program spends its time, from scratch.
+    pub fn start_profile(&mut self) {
+        self.profile = Some(Profile::new());
+    }
+
+    //The profile gathered since start_profile(), if it was called.
+    pub fn profile(&self) -> Option<&Profile> {
+        self.profile.as_ref()
+    }
+
+    pub fn take_profile(&mut self) -> Option<Profile> {
+        self.profile.take()
+    }
+
+    //Starts noting which instructions run and which way branches go,
+    //from scratch.
+    pub fn start_coverage(&mut self) {
+        self.coverage = Some(Coverage::new());
+    }
+
+    //The coverage gathered since start_coverage(), if it was called.
+    pub fn coverage(&self) -> Option<&Coverage> {
+        self.coverage.as_ref()
+    }
+
+    pub fn take_coverage(&mut self) -> Option<Coverage> {
+        self.coverage.take()
+    }
+
+    //Detaches the tracer, so that it can be finished.
+    pub fn take_tracer(&mut self) -> Option<Box<dyn Tracer>> {
+        self.tracer.take()
+    }
+
+    //Runs instructions until the program halts, hits a breakpoint or
+    //makes an access a watchpoint is set on.
+    pub fn run(&mut self) -> Result<StopReason, VmError> {
+        self.run_until(None, None)
+    }
+
+    //Like run(), but gives up after executing `max_steps` instructions.
+    pub fn run_for(&mut self, max_steps: u64) -> Result<StopReason, VmError> {
+        self.run_until(Some(max_steps), None)
+    }
+
+    //Like run(), but gives up once the instructions executed have taken
+    //`max_cycles` cycles or more by the cycle model.
+    pub fn run_for_cycles(&mut self, max_cycles: u64) -> Result<StopReason, VmError> {
+        self.run_until(None, Some(self.cycles.saturating_add(max_cycles)))
+    }
+
+    //`end_cycles` is the cycle count to stop at.
+    fn run_until(&mut self, max_steps: Option<u64>, end_cycles: Option<u64>) -> Result<StopReason, VmError> {
+        let mut steps: u64 = 0;
+
+        while self.is_running() {
+            if max_steps == Some(steps) || end_cycles.is_some_and(|end| self.cycles >= end) {
+                return Ok(StopReason::StepLimit);
+            }
+
+            //The breakpoint at the PC we start from is skipped so that
+            //calling run() again continues past it instead of stopping
+            //on the same instruction forever.
+            if steps > 0 && self.breakpoints.contains(&self.pc()) {
+                return Ok(StopReason::Breakpoint);
+            }
+
+            let hits = self.memory.watch_hits.len();
+            match self.step() {
+                Err(err) if is_interrupt(&err) => return Ok(StopReason::Interrupted),
+                result => result?,
+            }
+            steps += 1;
+
+            if self.memory.watch_hits.len() > hits {
+                return Ok(StopReason::Watchpoint);
+            }
+        }
+
+        Ok(StopReason::Halted)
+    }
+
+    //Fetches, decodes and executes a single instruction. If the console
+    //interrupts a read the instruction waits in, the PC is put back for
+    //it to run again and the io::ErrorKind::Interrupted error returned.
+    pub fn step(&mut self) -> Result<(), VmError> {
+        let pc = self.registers[Reg::PC];
+        let result = if self.tracer.is_some() || self.history.is_some() {
+            self.recorded_step()
+        }
+        else {
+            self.execute()
+        };
+        let instr = match result {
+            Err(err) if is_interrupt(&err) => {
+                self.registers[Reg::PC] = pc;
+                return Err(err);
+            },
+            result => result?,
+        };
+
+        let cost = self.cycle_model.cost(instr);
+        self.instructions += 1;
+        self.cycles += cost;
+
+        if let Some(profile) = &mut self.profile {
+            let next_pc = self.registers[Reg::PC];
+            profile.record(pc, instr, cost, next_pc, self.serviced);
+        }
+        if let Some(coverage) = &mut self.coverage {
+            coverage.record(pc, instr, self.branched);
+        }
+        Ok(())
+    }
+
+    //step(), noting what the instruction did: for the tracer the
+    //registers it changed and the memory it accessed, for the history
+    //how to undo it. An instruction that fails goes into the history, so
+    //that one can step back from the failure, but is not traced.
+    fn recorded_step(&mut self) -> Result<u16, VmError> {
+        let pc = self.registers[Reg::PC];
+        let before = self.registers.clone();
+
+        let undo = self.history.as_ref().map(|_| Undo {
+            pc,
+            instructions: self.instructions,
+            cycles: self.cycles,
+            registers: before.clone(),
+            saved_usp: self.saved_usp,
+            saved_ssp: self.saved_ssp,
+            interrupts: self.interrupts.clone(),
+            running: self.is_running(),
+            writes: Vec::new(),
+            accesses: Vec::new(),
+        });
+
+        self.memory.accesses = Some(Vec::new());
+        if undo.is_some() {
+            self.memory.undo = Some(Vec::new());
+        }
+        let result = self.execute();
+        let accesses = self.memory.accesses.take().unwrap_or_default();
+        let writes = self.memory.undo.take().unwrap_or_default();
+
+        //An interrupted instruction did not happen, it runs again.
+        let interrupted = matches!(&result, Err(err) if is_interrupt(err));
+        if let (Some(history), Some(mut undo), false) = (&mut self.history, undo, interrupted) {
+            undo.writes = writes;
+            undo.accesses = accesses.clone();
+            history.push(undo);
+        }
+        let instr = result?;
+
+        if self.tracer.is_none() {
+            return Ok(instr);
+        }
+        let reg_writes = (Reg::R0 as usize..=Reg::R7 as usize)
+            .filter(|&reg| self.registers[reg] != before[reg])
+            .map(|reg| (reg as u8, self.registers[reg]))
+            .collect();
+        let record = TraceRecord {
+            cycle: self.cycles,
+            pc,
+            instr,
+            reg_writes,
+            accesses,
+            psr: self.registers[Reg::PSR],
+        };
+        if let Some(tracer) = &mut self.tracer {
+            tracer.record(&record)?;
+        }
+        Ok(instr)
+    }
+
+    //Keeps the last `limit` instructions executed from now on, so that
+    //step_back() can undo them. 0 turns the history off.
+    pub fn set_history_limit(&mut self, limit: usize) {
+        match (&mut self.history, limit) {
+            (_, 0) => self.history = None,
+            (Some(history), _) => history.set_limit(limit),
+            (None, _) => self.history = Some(History::new(limit)),
+        }
+    }
+
+    pub fn history_limit(&self) -> usize {
+        self.history.as_ref().map_or(0, History::limit)
+    }
+
+    //How many instructions step_back() can undo.
+    pub fn history_len(&self) -> usize {
+        self.history.as_ref().map_or(0, History::len)
+    }
+
+    pub fn clear_history(&mut self) {
+        if let Some(history) = &mut self.history {
+            history.clear();
+        }
+    }
+
+    //Undoes the last instruction in the history. Accesses it made that a
+    //watchpoint is set on are noted as watch hits, just like when it ran.
+    //Returns false if the history is empty.
+    pub fn step_back(&mut self) -> bool {
+        let undo = match self.history.as_mut().and_then(History::pop) {
+            Some(undo) => undo,
+            None => return false,
+        };
+
+        for &(addr, old) in undo.writes.iter().rev() {
+            self.memory.set_word(addr, old);
+        }
+        self.registers = undo.registers;
+        self.saved_usp = undo.saved_usp;
+        self.saved_ssp = undo.saved_ssp;
+        self.interrupts = undo.interrupts;
+        self.memory.bus.set_clock_enabled(undo.running);
+        self.instructions = undo.instructions;
+        self.cycles = undo.cycles;
+
+        if !self.memory.watchpoints.is_empty() {
+            let first_hit = self.memory.watch_hits.len();
+            let mut writes = undo.writes.iter();
+            for access in &undo.accesses {
+                let (kind, old) = if access.write {
+                    let old = writes.next().map_or(access.val, |&(_, old)| old);
+                    (WatchKind::Write, old)
+                }
+                else {
+                    (WatchKind::Read, access.val)
+                };
+                self.memory.watch(access.addr, kind, old, access.val);
+            }
+            self.stamp_watch_hits(first_hit, undo.pc);
+        }
+        true
+    }
+
+    //Runs the program backwards until it gets back to a breakpoint,
+    //undoes an access a watchpoint is set on, or runs out of history.
+    pub fn run_back(&mut self) -> StopReason {
+        let hits = self.memory.watch_hits.len();
+        loop {
+            if !self.step_back() {
+                return StopReason::HistoryStart;
+            }
+            if self.memory.watch_hits.len() > hits {
+                return StopReason::Watchpoint;
+            }
+            if self.breakpoints.contains(&self.pc()) {
+                return StopReason::Breakpoint;
+            }
+        }
+    }
+
+    //Returns the instruction executed.
+    fn execute(&mut self) -> Result<u16, VmError> {
+        let pc = self.registers[Reg::PC];
+        if self.memory.clocked {
+            self.memory.console.clock(self.instructions);
+        }
+        self.serviced = false;
+
+        //User code may only access user space. If it strays outside, the
+        //access is blocked and the instruction undone, so keep a copy of
+        //the registers it started with.
+        let protect = self.exceptions && self.is_user_mode();
+        self.memory.protect = protect;
+        let before = if protect { Some(self.registers.clone()) } else { None };
+
+        let registers = &mut self.registers;
+        let memory = &mut self.memory;
+
+        //Fetching the instruction is not a read the program asked for, so
+        //it does not count for watchpoints.
+        let first_hit = memory.watch_hits.len();
+        let (instr, instruction) = fetch(registers[Reg::PC], memory);
+
+        registers[Reg::PC] = registers[Reg::PC].wrapping_add(1); //increment PC
+
+        match instruction {
+            Instruction::Br { cond, offset } => {
+                self.branched = op_branch(registers, cond, offset);
+            },
+
+            Instruction::Add { dr, sr1, src2 } => op_add(registers, dr.into(), sr1.into(), src2),
+
+            Instruction::Ld { dr, offset } => op_load(registers, dr.into(), offset, memory),
+
+            Instruction::St { sr, offset } => op_st(registers, sr.into(), offset, memory),
+
+            Instruction::Jsr { offset } => op_jsr(registers, offset),
+
+            Instruction::Jsrr { base } => op_jsrr(registers, base.into()),
+
+            Instruction::And { dr, sr1, src2 } => op_and(registers, dr.into(), sr1.into(), src2),
+
+            Instruction::Ldr { dr, base, offset } => op_ldr(registers, dr.into(), base.into(), offset, memory),
+
+            Instruction::Str { sr, base, offset } => op_str(registers, sr.into(), base.into(), offset, memory),
+
+            Instruction::Rti => self.op_rti(pc)?,
+
+            Instruction::Not { dr, sr } => op_not(registers, dr.into(), sr.into()),
+
+            Instruction::Ldi { dr, offset } => op_ldi(registers, dr.into(), offset, memory),
+
+            Instruction::Sti { sr, offset } => op_sti(registers, sr.into(), offset, memory),
+
+            Instruction::Jmp { base } => op_jump(registers, base.into()),
+
+            Instruction::Reserved => {
+                self.exception(Exception::EX_ILLEGAL_OPCODE, VmError::IllegalOpcode { pc, instr })?;
+            },
+
+            Instruction::Lea { dr, offset } => op_lea(registers, dr.into(), offset),
+
+            //"If the program is executing in User mode, the User Stack
+            //Pointer must be saved and the System Stack Pointer loaded.
+            //Then the PSR and PC are pushed on the system stack. Then the
+            //PC is loaded with the starting address of the system call
+            //specified by trapvector8." The service routine runs in
+            //supervisor mode at the caller's priority and returns with RTI.
+            Instruction::Trap { vector } if self.trap_mode == TrapMode::Vectored => {
+                let psr = registers[Reg::PSR];
+                self.enter_service_routine(TRAP_VECTOR_TABLE + u16::from(vector), psr & !PSR_USER);
+            },
+
+            //first 4 bits = 1111, is for trap code
+            Instruction::Trap { vector } => {
+                //Like the OS routines, the native ones may read anywhere.
+                memory.protect = false;
+
+                //0xFF = 255, trapcode is identified by the last 8
+                //bits of the instruction
+                let trap: u16 = vector.into();
+                match trap {
+                    trap if trap == TrapCode::GETC as u16 => {
+                        trap_getc(registers, memory.console_mut())?;
+                    },
+
+                    trap if trap == TrapCode::OUT as u16 => {
+                        trap_out(registers, memory.console_mut())?;
+                    },
+
+                    trap if trap == TrapCode::PUTS as u16 => {
+                        trap_puts(registers, memory)?;
+                    },
+
+                    trap if trap == TrapCode::IN as u16 => {
+                        trap_in(registers, memory.console_mut())?;
+                    },
+
+                    trap if trap == TrapCode::PUTSP as u16 => {
+                        trap_putsp(registers, memory)?;
+                    },
+
+                    trap if trap == TrapCode::HALT as u16 => {
+                        memory.bus.set_clock_enabled(false);
+                    },
+
+                    //Not one of ours, but the guest may have installed its
+                    //own routine for it in the trap vector table.
+                    _ => {
+                        let routine = memory.words[trap as usize];
+                        if !self.exceptions || routine == 0 {
+                            return Err(VmError::UnknownTrap { vector: trap as u8 });
+                        }
+                        registers[Reg::R7] = registers[Reg::PC];
+                        registers[Reg::PC] = routine;
+                    }
+                }
+            },
+        }
+
+        self.memory.protect = false;
+
+        self.stamp_watch_hits(first_hit, pc);
+
+        //Accessing a device may have hit a console error.
+        if let Some(err) = self.memory.take_fault() {
+            return Err(err.into());
+        }
+
+        if let Some(addr) = self.memory.violation.take() {
+            if let Some(before) = before {
+                self.registers = before;
+            }
+            self.registers[Reg::PC] = pc.wrapping_add(1);
+            self.exception(Exception::EX_ACCESS_VIOLATION, VmError::AccessViolation { pc, addr })?;
+        }
+
+        //Devices get to notice that an instruction's worth of time has
+        //passed, then any interrupt they raised is taken before the next
+        //instruction is fetched. With every device idle there is nothing
+        //to tick.
+        if self.memory.bus.armed() {
+            self.memory.bus.tick(self.memory.console.as_mut())?;
+        }
+        self.service_interrupt();
+
+        //Exceptions and interrupts push onto the supervisor stack, which
+        //is put down to the instruction they happened at.
+        self.stamp_watch_hits(first_hit, pc);
+
+        Ok(instr)
+    }
+
+    fn stamp_watch_hits(&mut self, first_hit: usize, pc: u16) {
+        for hit in &mut self.memory.watch_hits[first_hit..] {
+            hit.pc = pc;
+        }
+    }
+
+    //Takes the highest priority interrupt that beats the running program,
+    //if there is one. The service routine runs in supervisor mode at the
+    //interrupt's priority with the condition codes cleared. An interrupt
+    //whose vector is 0 has no service routine and is not taken.
+    fn service_interrupt(&mut self) {
+        //Idle devices request nothing, and nothing beats priority 7.
+        if !self.memory.bus.armed() && self.interrupts.pending().is_empty() {
+            return;
+        }
+        let priority = self.priority();
+        if priority == 7 {
+            return;
+        }
+
+        let requests = self.memory.bus.interrupt_requests();
+        let words = &self.memory.words;
+        let handled = |irq: &Interrupt| words[(INTERRUPT_VECTOR_TABLE + irq.vector as u16) as usize] != 0;
+
+        if let Some(irq) = self.interrupts.select(priority, requests, handled) {
+            let psr = (irq.priority as u16) << PSR_PRIORITY_SHIFT;
+            self.enter_service_routine(INTERRUPT_VECTOR_TABLE + irq.vector as u16, psr);
+            self.serviced = true;
+        }
+    }
+
+    //"Return from Interrupt - If the processor is running in Supervisor
+    //mode, the top two elements on the Supervisor Stack are popped and
+    //loaded into PC, PSR. If the processor is running in User mode, a
+    //privilege mode violation exception occurs."
+    fn op_rti(&mut self, pc: u16) -> Result<(), VmError> {
+        if self.is_user_mode() {
+            return self.exception(Exception::EX_PRIVILEGE, VmError::PrivilegeViolation { pc });
+        }
+
+        let new_pc = self.pop();
+        let psr = self.pop();
+        self.registers[Reg::PC] = new_pc;
+        self.registers[Reg::PSR] = psr;
+
+        //Going back to user code means going back to the user stack.
+        if psr & PSR_USER != 0 {
+            self.saved_ssp = self.registers[Reg::R6];
+            self.registers[Reg::R6] = self.saved_usp;
+        }
+
+        Ok(())
+    }
+
+    //Raises an exception. The handler is found in the vector table; if
+    //the guest never installed one there is nothing sensible to jump to,
+    //so the VM stops with `fallback` instead. Same when exceptions are
+    //turned off.
+    fn exception(&mut self, exception: Exception, fallback: VmError) -> Result<(), VmError> {
+        let vector = exception as u16;
+        let handler = self.memory.words[(INTERRUPT_VECTOR_TABLE + vector) as usize];
+
+        if !self.exceptions || handler == 0 {
+            return Err(fallback);
+        }
+
+        let psr = self.registers[Reg::PSR];
+        self.enter_service_routine(INTERRUPT_VECTOR_TABLE + vector, psr & !PSR_USER);
+        self.serviced = true;
+        Ok(())
+    }
+
+    //Pushes PSR and PC onto the supervisor stack (switching to it first
+    //when coming from user mode) and jumps to the routine whose address
+    //is in the vector table entry at `entry`, with the new `psr`.
+    fn enter_service_routine(&mut self, entry: u16, psr: u16) {
+        let old_psr = self.registers[Reg::PSR];
+
+        //The supervisor stack is outside of user space.
+        self.memory.protect = false;
+
+        if old_psr & PSR_USER != 0 {
+            self.saved_usp = self.registers[Reg::R6];
+            self.registers[Reg::R6] = self.saved_ssp;
+        }
+
+        let old_pc = self.registers[Reg::PC];
+        self.push(old_psr);
+        self.push(old_pc);
+
+        self.registers[Reg::PSR] = psr;
+        self.registers[Reg::PC] = mem_read(entry, &mut self.memory);
+    }
+
+    fn push(&mut self, val: u16) {
+        self.registers[Reg::R6] = self.registers[Reg::R6].wrapping_sub(1);
+        mem_write(self.registers[Reg::R6], val, &mut self.memory);
+    }
+
+    fn pop(&mut self) -> u16 {
+        let val = mem_read(self.registers[Reg::R6], &mut self.memory);
+        self.registers[Reg::R6] = self.registers[Reg::R6].wrapping_add(1);
+        val
+    }
+}
+
+
+fn is_interrupt(err: &VmError) -> bool {
+    matches!(err, VmError::Io(err) if err.kind() == io::ErrorKind::Interrupted)
+}
+
+
+impl Default for Vm {
+    fn default() -> Vm {
+        Vm::new()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use super::*;
+    use crate::{asm, CondFlags};
+    use crate::console::{BufferConsole, ScriptedConsole};
+    use crate::device::MCR_CLOCK_ENABLE;
+
+    //Assembles `source` into a VM reading `input`, and returns the VM with
+    //a handle on its console.
+    fn vm_with(source: &str, input: &[u8]) -> (Vm, Rc<RefCell<BufferConsole>>) {
+        let program = asm::assemble(source).expect("test program assembles");
+        let console = Rc::new(RefCell::new(BufferConsole::new(input)));
+        let mut vm = Vm::with_console(Box::new(console.clone()));
+
+        let origin = program.origin as usize;
+        vm.memory_mut()[origin..origin + program.words.len()].copy_from_slice(&program.words);
+        vm.set_pc(program.origin);
+        (vm, console)
+    }
+
+    //A VM reading `input` with `programs` loaded, each at its origin, and
+    //the PC at the first one. Returns the VM with a handle on its console.
+    fn vm_with_words(programs: &[(u16, &[u16])], input: &[u8]) -> (Vm, Rc<RefCell<BufferConsole>>) {
+        let console = Rc::new(RefCell::new(BufferConsole::new(input)));
+        let mut vm = Vm::with_console(Box::new(console.clone()));
+        for &(origin, words) in programs {
+            vm.memory_mut()[origin as usize..][..words.len()].copy_from_slice(words);
+        }
+        vm.set_pc(programs[0].0);
+        (vm, console)
+    }
+
+    fn output(console: &Rc<RefCell<BufferConsole>>) -> String {
+        String::from_utf8(console.borrow().output().to_vec()).unwrap()
+    }
+
+    //Adds up the three words at DATA into SUM.
+    const SUM: [u16; 15] = [
+        0x5020, // x3000          AND R0, R0, #0
+        0xE209, // x3001          LEA R1, DATA
+        0x2407, // x3002          LD R2, COUNT
+        0x6640, // x3003  LOOP    LDR R3, R1, #0
+        0x1003, // x3004          ADD R0, R0, R3
+        0x1261, // x3005          ADD R1, R1, #1
+        0x14BF, // x3006          ADD R2, R2, #-1
+        0x03FB, // x3007          BRp LOOP
+        0x3005, // x3008          ST R0, TOTAL
+        0xF025, // x3009          HALT
+        0x0003, // x300A  COUNT   .FILL #3
+        0x000A, // x300B  DATA    .FILL #10
+        0x0014, // x300C          .FILL #20
+        0x000C, // x300D          .FILL #12
+        0x0000, // x300E  TOTAL   .FILL #0
+    ];
+
+    #[test]
+    fn steps_execute_one_instruction_each() {
+        //ADD R0, R0, #5 and ADD R1, R0, #-7
+        let (mut vm, _) = vm_with_words(&[(0x3000, &[0x1025, 0x1239])], b"");
+        vm.step().unwrap();
+        assert_eq!((vm.reg(Reg::R0), vm.pc(), vm.cond()), (5, 0x3001, CondFlags::FL_POS as u16));
+        vm.step().unwrap();
+        assert_eq!((vm.reg(Reg::R1), vm.pc(), vm.cond()), (0xFFFE, 0x3002, CondFlags::FL_NEG as u16));
+    }
+
+    #[test]
+    fn programs_run_until_they_halt() {
+        let (mut vm, _) = vm_with_words(&[(0x3000, &SUM)], b"");
+        assert_eq!(vm.run().unwrap(), StopReason::Halted);
+        assert!(!vm.is_running());
+        assert_eq!(vm.memory()[0x300E], 42);
+        assert_eq!(vm.pc(), 0x300A);
+    }
+
+    #[test]
+    fn bad_instructions_stop_the_vm() {
+        let (mut vm, _) = vm_with_words(&[(0x3000, &[0x1025, 0xD000])], b"");
+        match vm.run() {
+            Err(VmError::IllegalOpcode { pc: 0x3001, instr: 0xD000 }) => {},
+            other => panic!("expected an illegal opcode, got {:?}", other),
+        }
+
+        let (mut vm, _) = vm_with_words(&[(0x3000, &[0xF040])], b"");
+        match vm.run() {
+            Err(VmError::UnknownTrap { vector: 0x40 }) => {},
+            other => panic!("expected an unknown trap, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn images_must_be_whole_and_fit() {
+        let mut vm = Vm::with_console(Box::new(BufferConsole::new(b"")));
+        vm.load_image_bytes(&[0x40, 0x00, 0x12, 0x34]).unwrap();
+        assert_eq!(vm.memory()[0x4000], 0x1234);
+
+        assert!(matches!(vm.load_image_bytes(&[0x40]), Err(VmError::TruncatedImage)));
+        assert!(matches!(vm.load_image_bytes(&[0x40, 0x00, 0x12]), Err(VmError::TruncatedImage)));
+        assert!(matches!(
+            vm.load_image_bytes(&[0xFF, 0xFF, 0, 1, 0, 2]),
+            Err(VmError::ImageTooLarge { origin: 0xFFFF, words: 2 })
+        ));
+    }
+
+    #[test]
+    fn runs_stop_at_breakpoints_and_step_limits() {
+        let (mut vm, _) = vm_with_words(&[(0x3000, &SUM)], b"");
+        assert!(vm.add_breakpoint(0x3007));
+        assert_eq!(vm.run().unwrap(), StopReason::Breakpoint);
+        assert_eq!((vm.pc(), vm.reg(Reg::R0)), (0x3007, 10));
+
+        //Carries on past the breakpoint it stopped at.
+        assert_eq!(vm.run().unwrap(), StopReason::Breakpoint);
+        assert_eq!((vm.pc(), vm.reg(Reg::R0)), (0x3007, 30));
+
+        assert!(vm.remove_breakpoint(0x3007));
+        assert_eq!(vm.run_for(2).unwrap(), StopReason::StepLimit);
+        assert_eq!(vm.pc(), 0x3004);
+        assert_eq!(vm.run().unwrap(), StopReason::Halted);
+    }
+
+    //Echoes a key, then prints a string and a packed one.
+    const PRINTS: [u16; 13] = [
+        0xF020, // x3000          GETC
+        0xF021, // x3001          OUT
+        0xE004, // x3002          LEA R0, MSG
+        0xF022, // x3003          PUTS
+        0xE005, // x3004          LEA R0, PACKED
+        0xF024, // x3005          PUTSP
+        0xF025, // x3006          HALT
+        0x006F, // x3007  MSG     .STRINGZ "ok"
+        0x006B,
+        0x0000,
+        0x6968, // x300A  PACKED  .FILL x6968
+        0x0021, // x300B          .FILL x0021
+        0x0000, // x300C          .FILL x0000
+    ];
+
+    #[test]
+    fn traps_talk_to_the_console() {
+        let (mut vm, console) = vm_with_words(&[(0x3000, &PRINTS)], b"k");
+        assert_eq!(vm.run().unwrap(), StopReason::Halted);
+        assert_eq!(output(&console), "kokhi!");
+        assert_eq!(vm.reg(Reg::R0), 0x300A);
+    }
+
+    #[test]
+    fn reading_past_the_input_fails() {
+        let (mut vm, _) = vm_with_words(&[(0x3000, &PRINTS)], b"");
+        match vm.run() {
+            Err(VmError::Io(err)) => assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof),
+            other => panic!("expected the input to run out, got {:?}", other),
+        }
+    }
+
+    //Polls KBSR, counting the polls in R1, then reads the key from KBDR.
+    const POLL: [u16; 8] = [
+        0x5260, // x3000          AND R1, R1, #0
+        0x1261, // x3001  POLL    ADD R1, R1, #1
+        0xA403, // x3002          LDI R2, KBSR
+        0x07FD, // x3003          BRzp POLL
+        0xA002, // x3004          LDI R0, KBDR
+        0xF025, // x3005          HALT
+        0xFE00, // x3006  KBSR    .FILL xFE00
+        0xFE02, // x3007  KBDR    .FILL xFE02
+    ];
+
+    #[test]
+    fn polling_the_keyboard_does_not_wait_for_a_key() {
+        let console = ScriptedConsole::new().idle(3).input(b"k");
+        let mut vm = Vm::with_console(Box::new(console));
+        vm.memory_mut()[0x3000..0x3008].copy_from_slice(&POLL);
+        assert_eq!(vm.run().unwrap(), StopReason::Halted);
+        assert_eq!((vm.reg(Reg::R0), vm.reg(Reg::R1)), (u16::from(b'k'), 4));
+    }
+
+    //Waits for the display to be ready and writes to it, then reads MCR
+    //and stops the clock by clearing it.
+    const DISPLAY: [u16; 12] = [
+        0xA207, // x3000  WAIT    LDI R1, DSR
+        0x07FE, // x3001          BRzp WAIT
+        0x2006, // x3002          LD R0, CH
+        0xB006, // x3003          STI R0, DDR
+        0xA406, // x3004          LDI R2, MCR
+        0x5260, // x3005          AND R1, R1, #0
+        0xB204, // x3006          STI R1, MCR
+        0x1021, // x3007          ADD R0, R0, #1
+        0xFE04, // x3008  DSR     .FILL xFE04
+        0x0021, // x3009  CH      .FILL x0021
+        0xFE06, // x300A  DDR     .FILL xFE06
+        0xFFFE, // x300B  MCR     .FILL xFFFE
+    ];
+
+    #[test]
+    fn the_display_prints_what_ddr_gets() {
+        let (mut vm, console) = vm_with_words(&[(0x3000, &DISPLAY)], b"");
+        vm.run_for(4).unwrap();
+        assert_eq!(output(&console), "!");
+    }
+
+    #[test]
+    fn clearing_mcr_stops_the_clock() {
+        let (mut vm, _) = vm_with_words(&[(0x3000, &DISPLAY)], b"");
+        assert_eq!(vm.run().unwrap(), StopReason::Halted);
+        assert_eq!(vm.reg(Reg::R2), MCR_CLOCK_ENABLE);
+        assert_eq!((vm.pc(), vm.reg(Reg::R0)), (0x3007, 0x21));
+        assert!(!vm.is_running());
+
+        vm.resume();
+        assert_eq!(vm.run_for(1).unwrap(), StopReason::StepLimit);
+        assert_eq!(vm.reg(Reg::R0), 0x22);
+    }
+
+    //Drops to user mode, to the program at x3010.
+    const TO_USER: [u16; 11] = [
+        0x2C07, // x3000          LD R6, SSP
+        0x2007, // x3001          LD R0, UPSR
+        0x1DBF, // x3002          ADD R6, R6, #-1
+        0x7180, // x3003          STR R0, R6, #0
+        0x2005, // x3004          LD R0, UPC
+        0x1DBF, // x3005          ADD R6, R6, #-1
+        0x7180, // x3006          STR R0, R6, #0
+        0x8000, // x3007          RTI
+        0x3000, // x3008  SSP     .FILL x3000
+        0x8002, // x3009  UPSR    .FILL x8002
+        0x3010, // x300A  UPC     .FILL x3010
+    ];
+
+    //Adds to R0 in user mode, then tries to RTI there.
+    const USER_RTI: [u16; 2] = [
+        0x1027, // x3010          ADD R0, R0, #7
+        0x8000, // x3011          RTI
+    ];
+
+    //Reads from below user space in user mode.
+    const USER_PEEK: [u16; 3] = [
+        0x2201, // x3010          LD R1, OS
+        0x6040, // x3011          LDR R0, R1, #0
+        0x0200, // x3012  OS      .FILL x0200
+    ];
+
+    #[test]
+    fn rti_drops_to_user_mode_on_the_user_stack() {
+        let (mut vm, _) = vm_with_words(&[(0x3000, &TO_USER), (0x3010, &USER_RTI)], b"");
+        vm.set_saved_usp(0x4000);
+        assert_eq!(vm.run_for(8).unwrap(), StopReason::StepLimit);
+        assert!(vm.is_user_mode());
+        assert_eq!((vm.pc(), vm.psr()), (0x3010, 0x8002));
+        assert_eq!((vm.reg(Reg::R6), vm.saved_ssp()), (0x4000, 0x3000));
+
+        vm.step().unwrap();
+        assert_eq!(vm.psr(), 0x8001);
+        match vm.step() {
+            Err(VmError::PrivilegeViolation { pc: 0x3011 }) => {},
+            other => panic!("expected a privilege violation, got {:?}", other),
+        }
+    }
+
+    //Starts the timer with interrupts enabled, then spins.
+    const TICKING: [u16; 11] = [
+        0x2C05, // x3000          LD R6, STACK
+        0x2005, // x3001          LD R0, TICKS
+        0xB005, // x3002          STI R0, TIR
+        0x2005, // x3003          LD R0, IE
+        0xB005, // x3004          STI R0, TSR
+        0x0FFF, // x3005  SPIN    BRnzp SPIN
+        0x3000, // x3006  STACK   .FILL x3000
+        0x0005, // x3007  TICKS   .FILL #5
+        0xFE0A, // x3008  TIR     .FILL xFE0A
+        0x4000, // x3009  IE      .FILL x4000
+        0xFE08, // x300A  TSR     .FILL xFE08
+    ];
+
+    //Counts the timer's interrupts in R4.
+    const ON_TICK: [u16; 4] = [
+        0x1921, // x4000          ADD R4, R4, #1
+        0xAA01, // x4001          LDI R5, TSR
+        0x8000, // x4002          RTI
+        0xFE08, // x4003  TSR     .FILL xFE08
+    ];
+
+    #[test]
+    fn timer_interrupts_run_their_service_routine() {
+        let (mut vm, _) = vm_with_words(&[(0x3000, &TICKING), (0x4000, &ON_TICK), (0x0181, &[0x4000])], b"");
+        vm.add_breakpoint(0x4000);
+        assert_eq!(vm.run_for(100).unwrap(), StopReason::Breakpoint);
+        //At the timer's priority with the condition codes cleared, the
+        //spinning program's PC and PSR on the stack.
+        assert_eq!(vm.psr(), 0x0500);
+        assert_eq!(vm.reg(Reg::R6), 0x2FFE);
+        assert_eq!(&vm.memory()[0x2FFE..0x3000], &[0x3005, 0x0001]);
+
+        assert_eq!(vm.run_for(3).unwrap(), StopReason::StepLimit);
+        assert_eq!((vm.pc(), vm.psr(), vm.reg(Reg::R6)), (0x3005, 0x0001, 0x3000));
+        assert_eq!(vm.run_for(100).unwrap(), StopReason::Breakpoint);
+        assert_eq!(vm.reg(Reg::R4), 1);
+    }
+
+    #[test]
+    fn interrupts_wait_for_a_lower_priority() {
+        let (mut vm, _) = vm_with_words(&[(0x3000, &TICKING), (0x4000, &ON_TICK), (0x0181, &[0x4000])], b"");
+        vm.set_psr(0x0500);
+        assert_eq!(vm.run_for(100).unwrap(), StopReason::StepLimit);
+        assert_eq!(vm.reg(Reg::R4), 0);
+
+        let (mut vm, _) = vm_with_words(&[(0x3000, &[0x0FFF]), (0x4000, &[0xF025]), (0x0190, &[0x4000])], b"");
+        vm.set_psr(0x0300);
+        vm.raise_interrupt(0x90, 3);
+        assert_eq!(vm.run_for(10).unwrap(), StopReason::StepLimit);
+        assert_eq!(vm.pending_interrupts(), &[Interrupt { vector: 0x90, priority: 3 }]);
+
+        vm.set_psr(0x0200);
+        assert_eq!(vm.run().unwrap(), StopReason::Halted);
+        assert_eq!((vm.pc(), vm.priority()), (0x4001, 3));
+        assert!(vm.pending_interrupts().is_empty());
+    }
+
+    //Enables keyboard interrupts, then spins.
+    const KEYED: [u16; 7] = [
+        0x2C03, // x3000          LD R6, STACK
+        0x2003, // x3001          LD R0, IE
+        0xB003, // x3002          STI R0, KBSR
+        0x0FFF, // x3003  SPIN    BRnzp SPIN
+        0x3000, // x3004  STACK   .FILL x3000
+        0x4000, // x3005  IE      .FILL x4000
+        0xFE00, // x3006  KBSR    .FILL xFE00
+    ];
+
+    #[test]
+    fn keys_interrupt_the_program() {
+        let mut vm = Vm::with_console(Box::new(ScriptedConsole::new().idle(2).input(b"k")));
+        vm.memory_mut()[0x3000..0x3007].copy_from_slice(&KEYED);
+        //LDI R0, KBDR; HALT; KBDR .FILL xFE02
+        vm.memory_mut()[0x4000..0x4003].copy_from_slice(&[0xA001, 0xF025, 0xFE02]);
+        vm.memory_mut()[0x0180] = 0x4000;
+
+        assert_eq!(vm.run().unwrap(), StopReason::Halted);
+        assert_eq!((vm.reg(Reg::R0), vm.priority()), (u16::from(b'k'), 4));
+        assert_eq!(vm.memory()[0x2FFE], 0x3003);
+    }
+
+    #[test]
+    fn illegal_opcodes_run_their_exception_handler() {
+        let (mut vm, _) = vm_with_words(&[(0x3000, &[0xD000]), (0x4000, &[0xF025]), (0x0101, &[0x4000])], b"");
+        assert_eq!(vm.run().unwrap(), StopReason::Halted);
+        assert_eq!((vm.pc(), vm.reg(Reg::R6)), (0x4001, 0x2FFE));
+        assert_eq!(&vm.memory()[0x2FFE..0x3000], &[0x3001, 0x0000]);
+
+        let (mut vm, _) = vm_with_words(&[(0x3000, &[0xD000]), (0x4000, &[0xF025]), (0x0101, &[0x4000])], b"");
+        vm.set_exceptions(false);
+        match vm.run() {
+            Err(VmError::IllegalOpcode { pc: 0x3000, instr: 0xD000 }) => {},
+            other => panic!("expected an illegal opcode, got {:?}", other),
+        }
+    }
+
+    //Runs `user` from x3010 in user mode, with the handlers halting.
+    fn user_fault(user: &[u16], vector: u16) -> Vm {
+        let (mut vm, _) = vm_with_words(&[(0x3000, &TO_USER), (0x3010, user), (0x4000, &[0xF025]), (vector, &[0x4000])], b"");
+        vm.set_saved_usp(0x4000);
+        assert_eq!(vm.run().unwrap(), StopReason::Halted);
+        assert!(!vm.is_user_mode());
+        assert_eq!((vm.pc(), vm.reg(Reg::R6), vm.saved_usp()), (0x4001, 0x2FFE, 0x4000));
+        vm
+    }
+
+    #[test]
+    fn user_mode_faults_run_their_exception_handlers() {
+        let vm = user_fault(&USER_RTI, 0x0100);
+        assert_eq!(&vm.memory()[0x2FFE..0x3000], &[0x3012, 0x8001]);
+
+        //The load is undone, R0 still has what TO_USER left in it.
+        let vm = user_fault(&USER_PEEK, 0x0102);
+        assert_eq!(&vm.memory()[0x2FFE..0x3000], &[0x3012, 0x8001]);
+        assert_eq!(vm.reg(Reg::R0), 0x3010);
+    }
+
+    #[test]
+    fn faults_without_a_handler_stop_the_vm() {
+        let (mut vm, _) = vm_with_words(&[(0x3000, &TO_USER), (0x3010, &USER_PEEK)], b"");
+        match vm.run() {
+            Err(VmError::AccessViolation { pc: 0x3011, addr: 0x0200 }) => {},
+            other => panic!("expected an access violation, got {:?}", other),
+        }
+    }
+
+    //Makes system calls from user mode, then spins with a mark in R7.
+    const USER_CALLS: [u16; 9] = [
+        0x2C05, // x3010          LD R6, USP
+        0x2E05, // x3011          LD R7, MARK
+        0x2005, // x3012          LD R0, CH
+        0xF021, // x3013          OUT
+        0xF023, // x3014          IN
+        0x0FFF, // x3015  HERE    BRnzp HERE
+        0x4000, // x3016  USP     .FILL x4000
+        0x1234, // x3017  MARK    .FILL x1234
+        0x0041, // x3018  CH      .FILL x0041
+    ];
+
+    //Jumps from user mode into the middle of the OS's GETC.
+    const INTO_OS: [u16; 3] = [
+        0x2001, // x3010          LD R0, OSGETC
+        0xC000, // x3011          JMP R0
+        0x0220, // x3012  OSGETC  .FILL x0220
+    ];
+
+    fn user_calls(trap_mode: TrapMode) -> Vm {
+        let (mut vm, console) = vm_with_words(&[(0x3000, &TO_USER), (0x3010, &USER_CALLS)], b"z");
+        if trap_mode == TrapMode::Vectored {
+            vm.load_os();
+        }
+        vm.set_trap_mode(trap_mode);
+        assert_eq!(vm.run_for(10_000).unwrap(), StopReason::StepLimit);
+        assert_eq!(output(&console), "AEnter a character: z");
+        vm
+    }
+
+    #[test]
+    fn user_mode_traps_under_native_routines() {
+        let vm = user_calls(TrapMode::Native);
+        assert!(vm.is_user_mode());
+        assert_eq!((vm.pc(), vm.reg(Reg::R6), vm.reg(Reg::R7)), (0x3015, 0x4000, 0x1234));
+    }
+
+    //The OS runs in supervisor mode on the supervisor stack and comes back
+    //with RTI, leaving R7 alone.
+    #[test]
+    fn user_mode_traps_under_os() {
+        let vm = user_calls(TrapMode::Vectored);
+        assert!(vm.is_user_mode());
+        assert_eq!((vm.pc(), vm.reg(Reg::R6), vm.reg(Reg::R7)), (0x3015, 0x4000, 0x1234));
+        assert_eq!(vm.saved_ssp(), 0x3000);
+    }
+
+    #[test]
+    fn user_mode_cannot_jump_into_the_os() {
+        let (mut vm, _) = vm_with_words(&[(0x3000, &TO_USER), (0x3010, &INTO_OS)], b"z");
+        vm.load_os();
+        vm.set_trap_mode(TrapMode::Vectored);
+        match vm.run() {
+            Err(VmError::AccessViolation { pc: 0x0220, addr: 0x0220 }) => {},
+            other => panic!("expected an access violation, got {:?}", other),
+        }
+    }
+
+    //What a program that echoes one key prints when it runs from
+    //`snapshot` with `key` to read.
+    fn resume(snapshot: &Snapshot, key: &[u8]) -> Vec<u8> {
+        let console = Rc::new(RefCell::new(BufferConsole::new(key)));
+        let mut vm = Vm::with_console(Box::new(console.clone()));
+        vm.restore(snapshot).unwrap();
+        assert_eq!(vm.run().unwrap(), StopReason::Halted);
+        let output = console.borrow().output().to_vec();
+        output
+    }
+
+    #[test]
+    fn interrupted_read_stops_before_the_read() {
+        let program = asm::assemble("
+                .ORIG x3000
+                GETC
+                OUT
+                HALT
+                .END
+        ").unwrap();
+        let console = Rc::new(RefCell::new(ScriptedConsole::new().interrupt().input(b"k")));
+        let mut vm = Vm::with_console(Box::new(console.clone()));
+        vm.set_history_limit(100);
+        vm.memory_mut()[0x3000..0x3003].copy_from_slice(&program.words);
+
+        assert_eq!(vm.run().unwrap(), StopReason::Interrupted);
+        assert_eq!(vm.pc(), 0x3000);
+        assert_eq!(vm.instruction_count(), 0);
+        assert!(!vm.step_back());
+
+        //The snapshot waits for a key of its own, the one the console
+        //still has is not in it.
+        let snapshot = vm.snapshot();
+        assert_eq!(resume(&snapshot, b"z"), b"z");
+
+        assert_eq!(vm.run().unwrap(), StopReason::Halted);
+        assert_eq!(console.borrow().output(), b"k");
+    }
+
+    #[test]
+    fn restored_snapshot_carries_on_the_same() {
+        let mut calls = USER_CALLS;
+        calls[5] = 0xF025; //HALT
+        let (mut vm, console) = vm_with_words(&[(0x3000, &TO_USER), (0x3010, &calls)], b"z");
+        vm.load_os();
+        vm.set_trap_mode(TrapMode::Vectored);
+        //Somewhere in the middle of the OS's IN routine.
+        while console.borrow().output().len() < 5 {
+            vm.step().unwrap();
+        }
+
+        let snapshot = vm.snapshot();
+        let mut copy = Vm::with_console(Box::new(BufferConsole::new(b"")));
+        copy.restore(&snapshot).unwrap();
+        assert_eq!(copy.snapshot(), snapshot);
+
+        console.borrow_mut().take_output();
+        assert_eq!(vm.run().unwrap(), StopReason::Halted);
+        let rest = console.borrow().output().to_vec();
+        assert_eq!(rest, b"r a character: z");
+        assert_eq!(resume(&snapshot, b"z"), rest);
+    }
+
+    //Runs LOOP twice, patching it in between.
+    const PATCHED: &str = "
+            .ORIG x3000
+            AND R1, R1, #0
+            AND R2, R2, #0
+    LOOP    ADD R1, R1, #1
+            ADD R2, R2, #1
+            ADD R3, R2, #-2
+            BRz DONE
+            LD R0, NEW
+            ST R0, LOOP
+            BR LOOP
+    DONE    HALT
+    NEW     ADD R1, R1, #2
+            .END
+    ";
+
+    #[test]
+    fn stores_replace_decoded_instructions() {
+        let (mut vm, _) = vm_with(PATCHED, b"");
+        assert_eq!(vm.run().unwrap(), StopReason::Halted);
+        assert_eq!(vm.reg(Reg::R1), 3);
+    }
+
+    #[test]
+    fn undoing_a_store_restores_the_old_instruction() {
+        let (mut vm, _) = vm_with(PATCHED, b"");
+        vm.set_history_limit(100);
+        vm.add_breakpoint(0x3009);
+        assert_eq!(vm.run().unwrap(), StopReason::Breakpoint);
+        assert_eq!(vm.reg(Reg::R1), 3);
+
+        //Back to before the ST, which puts the first ADD back.
+        while vm.memory()[0x3002] != 0x1261 {
+            assert!(vm.step_back());
+        }
+        vm.set_pc(0x3002);
+        vm.step().unwrap();
+        assert_eq!(vm.reg(Reg::R1), 2);
+    }
+
+    #[test]
+    fn changed_memory_is_decoded_again() {
+        let (mut vm, _) = vm_with(PATCHED, b"");
+        vm.run_for(3).unwrap();
+        assert_eq!(vm.reg(Reg::R1), 1);
+
+        //ADD R1, R1, #4
+        vm.memory_mut()[0x3002] = 0x1264;
+        vm.set_pc(0x3002);
+        vm.step().unwrap();
+        assert_eq!(vm.reg(Reg::R1), 5);
+    }
+
+    #[test]
+    fn native_puts_trips_read_watchpoints() {
+        let (mut vm, console) = vm_with("
+                .ORIG x3000
+                LEA R0, MSG
+                PUTS
+                LEA R0, PACKED
+                PUTSP
+                HALT
+        MSG     .STRINGZ \"hi\"
+        PACKED  .FILL x6F79
+                .FILL x0000
+                .END
+        ", b"");
+        vm.add_watchpoint(Watchpoint::new(WatchKind::Read, 0x3006..=0x3006));
+        vm.add_watchpoint(Watchpoint::new(WatchKind::Read, 0x3008..=0x3008));
+
+        assert_eq!(vm.run().unwrap(), StopReason::Watchpoint);
+        let hits = vm.take_watch_hits();
+        assert_eq!((hits[0].pc, hits[0].addr), (0x3001, 0x3006));
+
+        assert_eq!(vm.run().unwrap(), StopReason::Watchpoint);
+        let hits = vm.take_watch_hits();
+        assert_eq!((hits[0].pc, hits[0].addr), (0x3003, 0x3008));
+
+        assert_eq!(vm.run().unwrap(), StopReason::Halted);
+        assert_eq!(console.borrow().output(), b"hiyo");
+    }
+}
@@ -0,0 +1,104 @@
+//Watchpoints: memory accesses the VM should tell us about. mem_read()
+//and mem_write() check them and note every access that matches, the VM
+//then stops so whoever drives it can have a look.
+
+use std::fmt;
+use std::ops::RangeInclusive;
+
+use crate::symbols::SymbolTable;
+
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WatchKind {
+    Read,
+    Write,
+    //Either of the two.
+    Access,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Watchpoint {
+    pub kind: WatchKind,
+    pub range: RangeInclusive<u16>,
+}
+
+impl Watchpoint {
+    pub fn new(kind: WatchKind, range: RangeInclusive<u16>) -> Watchpoint {
+        Watchpoint { kind, range }
+    }
+
+    //`access` is Read or Write, the kind of access that happened.
+    pub fn matches(&self, addr: u16, access: WatchKind) -> bool {
+        (self.kind == WatchKind::Access || self.kind == access) && self.range.contains(&addr)
+    }
+
+    //Parses the --watch syntax, `[r|w|a:]START[..END]`. Without a kind
+    //it watches writes. START and END are anything SymbolTable::resolve()
+    //takes, e.g. `w:TABLE..TABLE+15` or `x4000`.
+    pub fn parse(spec: &str, symbols: &SymbolTable) -> Result<Watchpoint, String> {
+        let (kind, range) = match spec.split_once(':') {
+            Some(("r", range)) => (WatchKind::Read, range),
+            Some(("w", range)) => (WatchKind::Write, range),
+            Some(("a", range)) => (WatchKind::Access, range),
+            Some((kind, _)) => return Err(format!("Unknown watchpoint kind `{}`, use r, w or a", kind)),
+            None => (WatchKind::Write, spec),
+        };
+
+        let (start, end) = match range.split_once("..") {
+            Some((start, end)) => (symbols.resolve(start)?, symbols.resolve(end)?),
+            None => {
+                let addr = symbols.resolve(range)?;
+                (addr, addr)
+            },
+        };
+        if end < start {
+            return Err(format!("Watchpoint range `{}` ends before it starts", range));
+        }
+
+        Ok(Watchpoint::new(kind, start..=end))
+    }
+}
+
+impl fmt::Display for Watchpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let kind = match self.kind {
+            WatchKind::Read => "read",
+            WatchKind::Write => "write",
+            WatchKind::Access => "access",
+        };
+        if self.range.start() == self.range.end() {
+            write!(f, "{} x{:04X}", kind, self.range.start())
+        }
+        else {
+            write!(f, "{} x{:04X}..x{:04X}", kind, self.range.start(), self.range.end())
+        }
+    }
+}
+
+
+//An access that hit a watchpoint. `kind` is Read or Write. A read has the
+//value read in both `old` and `new`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct WatchHit {
+    //The instruction that made the access.
+    pub pc: u16,
+    pub addr: u16,
+    pub kind: WatchKind,
+    pub old: u16,
+    pub new: u16,
+}
+
+impl WatchHit {
+    //The hit as a line for the user, with labels for the addresses:
+    //"write x4005 (TABLE+5) at x3010 (LOOP+2): x0000 -> x0041".
+    pub fn describe(&self, symbols: &SymbolTable) -> String {
+        let at = |addr: u16| symbols.annotate(addr);
+
+        match self.kind {
+            WatchKind::Write => format!(
+                "write {} at {}: x{:04X} -> x{:04X}", at(self.addr), at(self.pc), self.old, self.new
+            ),
+            _ => format!("read {} at {}: x{:04X}", at(self.addr), at(self.pc), self.new),
+        }
+    }
+}